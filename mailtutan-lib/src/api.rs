@@ -1,48 +1,142 @@
-use axum::{routing::delete, routing::get, Router};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{routing::delete, routing::get, routing::post, Router};
 
 use crate::auth;
-use crate::APP;
+use crate::{AppState, Error, Result};
 
 mod assets;
+mod case;
+mod config;
+mod diff;
+mod failures;
+mod feed;
+mod health;
 mod messages;
+mod pdf;
+mod read_only;
+mod smtp;
+mod timing;
 mod version;
 mod websocket;
 
-pub async fn serve() {
+pub async fn serve(state: AppState) -> Result<()> {
+    let uri = state.lock().unwrap().get_api_uri();
+
+    println!("listening on http://{}", uri);
+
+    let addr = uri
+        .parse()
+        .map_err(|_| Error::Config(format!("invalid http address {}", uri)))?;
+
+    axum::Server::try_bind(&addr)
+        .map_err(|err| Error::Io(std::io::Error::other(err)))?
+        .serve(router(state).into_make_service())
+        .await
+        .map_err(|err| Error::Io(std::io::Error::other(err)))
+}
+
+/// All HTTP routes, with authentication applied where configured.
+pub fn router(state: AppState) -> Router {
     let app = Router::new()
         .route("/", get(assets::index_html))
         .route("/ws", get(websocket::websocket_handler))
         .route("/mailtutan-web_bg.wasm", get(assets::wasm))
         .route("/styles.css", get(assets::css))
         .route("/mailtutan-web.js", get(assets::js))
-        .route("/api/messages", get(messages::index))
+        .route("/api/messages", get(messages::list))
+        .route("/api/messages/export.mbox", get(messages::export_mbox))
+        .route("/api/messages/diff", get(diff::show))
+        .route("/api/messages/feed.atom", get(feed::show))
+        .route(
+            "/api/messages/sequence/:day/:number",
+            get(messages::show_by_sequence),
+        )
         .route("/api/messages/:id/source", get(messages::show_source))
         .route("/api/messages/:id/plain", get(messages::show_plain))
+        .route(
+            "/api/messages/:id/plain.json",
+            get(messages::show_pretty_json),
+        )
         .route("/api/messages/:id/html", get(messages::show_html))
         .route("/api/messages/:id/json", get(messages::show_json))
         .route("/api/messages/:id/eml", get(messages::show_eml))
+        .route("/api/messages/:id/structure", get(messages::show_structure))
+        .route("/api/messages/:id/links", get(messages::show_links))
+        .route("/api/messages/:id/lint", get(messages::show_lint))
+        .route(
+            "/api/messages/:id/transcript",
+            get(messages::show_transcript),
+        )
+        .route("/api/messages/:id/pdf", get(pdf::show))
         .route("/api/messages/:id", delete(messages::delete))
+        .route("/api/messages/:id/labels", post(messages::update_labels))
+        .route("/api/messages/:id/quarantine", post(messages::quarantine))
+        .route(
+            "/api/messages/:id/unquarantine",
+            post(messages::unquarantine),
+        )
+        .route("/api/messages/:id/replay", post(messages::replay))
         .route(
             "/api/messages/:id/parts/:cid",
             get(messages::download_attachment),
         )
         .route("/api/messages", delete(messages::delete_all))
+        .route("/api/failures", get(failures::index))
+        .route("/api/config/reload", post(config::reload))
+        .route("/api/smtp/capabilities", get(smtp::capabilities))
         .route("/api/version", get(version::show));
 
     let app = {
-        if APP.get().unwrap().lock().unwrap().http_auth {
-            app.route_layer(axum::middleware::from_fn(auth::basic))
+        if state.lock().unwrap().read_only {
+            app.route_layer(axum::middleware::from_fn(read_only::reject_mutations))
+        } else {
+            app
+        }
+    };
+
+    let app = {
+        if state.lock().unwrap().http_auth {
+            app.route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth::basic,
+            ))
         } else {
             app
         }
     };
 
-    let uri = APP.get().unwrap().lock().unwrap().get_api_uri();
+    let app = {
+        if state.lock().unwrap().camel_case_json {
+            app.layer(axum::middleware::from_fn(case::camel_case_json))
+        } else {
+            app
+        }
+    };
 
-    println!("listening on http://{}", uri);
+    let app = {
+        if state.lock().unwrap().server_timing {
+            app.layer(axum::middleware::from_fn(timing::server_timing))
+        } else {
+            app
+        }
+    };
 
-    axum::Server::bind(&uri.parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    app.route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .with_state(state)
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::Storage(_) => StatusCode::NOT_FOUND,
+            Error::Parse(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Gone(_) => StatusCode::GONE,
+            Error::TooLarge(_) | Error::TooManyAttachments(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Io(_) | Error::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, self.to_string()).into_response()
+    }
 }
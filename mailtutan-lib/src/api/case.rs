@@ -0,0 +1,144 @@
+use axum::{
+    body::{self, Bytes},
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::Body;
+use serde_json::{Map, Value};
+
+/// Response extension marking JSON that was captured rather than produced
+/// by the API, e.g. a message body, which [`camel_case_json`] leaves alone.
+#[derive(Clone, Copy, Debug)]
+pub struct Verbatim;
+
+/// Rewrites JSON responses to camelCase keys when `camel_case_json` is set.
+/// Explicit renames such as an attachment's `type` have no underscore and
+/// come out unchanged.
+pub async fn camel_case_json<B>(request: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(request).await;
+
+    if response.extensions().get::<Verbatim>().is_some() {
+        return response;
+    }
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    let mut bytes = vec![];
+
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => bytes.extend_from_slice(&chunk),
+            Err(err) => return (parts.status, err.to_string()).into_response(),
+        }
+    }
+
+    let bytes = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(value) => Bytes::from(camel_case(value).to_string()),
+        Err(_) => Bytes::from(bytes),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    Response::from_parts(parts, body::boxed(body::Full::from(bytes)))
+}
+
+/// Renames the keys of every object in `value` from snake_case to camelCase.
+pub fn camel_case(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| (camel_case_key(&key), camel_case(value)))
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(camel_case).collect()),
+        value => value,
+    }
+}
+
+fn camel_case_key(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper = false;
+
+    for c in key.chars() {
+        if c == '_' {
+            upper = !out.is_empty();
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::{Message, ParseOptions};
+    use crate::test_util::{request, spawn_router, MessageBuilder};
+    use crate::Mailtutan;
+
+    #[tokio::test]
+    async fn test_camel_case_json() {
+        let state = Mailtutan {
+            camel_case_json: true,
+            ..Default::default()
+        }
+        .init();
+        let data = MessageBuilder::new()
+            .subject("camels")
+            .plain("body")
+            .attachment("a.txt", "text/plain", b"a")
+            .build();
+        let message = Message::try_from(&data).unwrap();
+        state.lock().unwrap().capture(message).unwrap();
+
+        let addr = spawn_router(state);
+
+        let response = request(addr, "GET", "/api/messages").await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\"createdAt\":"));
+        assert!(response.contains("\"mimeType\":\"text/plain\""));
+        assert!(response.contains("\"type\":\"text\""));
+        assert!(!response.contains("created_at"));
+    }
+
+    #[tokio::test]
+    async fn test_captured_json_is_left_alone() {
+        let state = Mailtutan {
+            camel_case_json: true,
+            ..Default::default()
+        }
+        .init();
+        let data = MessageBuilder::new()
+            .subject("json")
+            .plain("{\"user_id\": 1}")
+            .build();
+        let options = ParseOptions {
+            pretty_json: true,
+            ..Default::default()
+        };
+        let message = Message::parse(&data, &options).unwrap();
+        let id = state.lock().unwrap().capture(message).unwrap().id.unwrap();
+
+        let addr = spawn_router(state);
+
+        let path = format!("/api/messages/{}/plain.json", id);
+        let response = request(addr, "GET", &path).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\n  \"user_id\": 1\n}"), "{}", response);
+    }
+}
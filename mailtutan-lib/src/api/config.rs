@@ -0,0 +1,59 @@
+use crate::{AppState, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+
+pub async fn reload(State(state): State<AppState>) -> Result<StatusCode> {
+    state.lock().unwrap().reload()?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Message;
+    use crate::{Error, Mailtutan, Settings};
+
+    #[tokio::test]
+    async fn test_reload_changes_messages_limit() {
+        let state = Mailtutan {
+            reloader: Some(Box::new(|| {
+                Ok(Settings {
+                    messages_limit: 2,
+                    ..Default::default()
+                })
+            })),
+            ..Default::default()
+        }
+        .init();
+
+        for subject in ["one", "two", "three"] {
+            let message = Message {
+                subject: subject.to_owned(),
+                ..Default::default()
+            };
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        assert_eq!(
+            reload(State(state.clone())).await.unwrap(),
+            StatusCode::NO_CONTENT
+        );
+
+        let mut app = state.lock().unwrap();
+        assert_eq!(app.messages_limit, 2);
+        assert_eq!(app.storage.size(), 2);
+        assert!(app.storage.get(1).is_err());
+
+        app.capture(Message::default()).unwrap();
+        assert_eq!(app.storage.size(), 2);
+        assert!(app.storage.get(2).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_without_reloader() {
+        let state = Mailtutan::default().init();
+
+        assert!(matches!(reload(State(state)).await, Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::models::Message;
+use crate::{AppState, Result};
+use axum::extract::{Query, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeSet;
+
+#[derive(Deserialize, Debug)]
+pub struct DiffQuery {
+    pub a: usize,
+    pub b: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Change<T> {
+    pub a: T,
+    pub b: T,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct HeaderChange {
+    pub name: String,
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct LineChange {
+    /// `-` for a line only in `a`, `+` for a line only in `b`.
+    pub op: &'static str,
+    pub line: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct AttachmentChange {
+    pub filename: String,
+    /// SHA-256 of the attachment body, `None` when it is missing on that side.
+    pub a: Option<String>,
+    pub b: Option<String>,
+}
+
+/// Only the parts that differ; everything empty means identical messages.
+#[derive(Serialize, Debug, Default)]
+pub struct Diff {
+    pub subject: Option<Change<String>>,
+    pub headers: Vec<HeaderChange>,
+    pub plain: Vec<LineChange>,
+    pub html: Vec<LineChange>,
+    pub attachments: Vec<AttachmentChange>,
+}
+
+pub async fn show(
+    State(state): State<AppState>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<Diff>> {
+    let (a, b) = {
+        let app = state.lock().unwrap();
+        (app.storage.get(query.a)?, app.storage.get(query.b)?)
+    };
+
+    Ok(Json(diff(&a, &b)))
+}
+
+fn diff(a: &Message, b: &Message) -> Diff {
+    Diff {
+        subject: (a.subject != b.subject).then(|| Change {
+            a: a.subject.clone(),
+            b: b.subject.clone(),
+        }),
+        headers: diff_headers(&headers(&a.source), &headers(&b.source)),
+        plain: diff_lines(a.plain.as_deref(), b.plain.as_deref()),
+        html: diff_lines(a.html.as_deref(), b.html.as_deref()),
+        attachments: diff_attachments(a, b),
+    }
+}
+
+/// Unfolded `(lowercased name, value)` pairs of the top-level header block.
+fn headers(source: &[u8]) -> Vec<(String, String)> {
+    let source = String::from_utf8_lossy(source);
+    let mut headers: Vec<(String, String)> = vec![];
+
+    for line in source.lines() {
+        if line.is_empty() {
+            break;
+        }
+
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+        }
+    }
+
+    headers
+}
+
+fn diff_headers(a: &[(String, String)], b: &[(String, String)]) -> Vec<HeaderChange> {
+    let values = |headers: &[(String, String)], name: &str| -> Vec<String> {
+        headers
+            .iter()
+            .filter(|(header, _)| header == name)
+            .map(|(_, value)| value.clone())
+            .collect()
+    };
+
+    let names: BTreeSet<&String> = a.iter().chain(b).map(|(name, _)| name).collect();
+
+    names
+        .into_iter()
+        .map(|name| HeaderChange {
+            name: name.clone(),
+            a: values(a, name),
+            b: values(b, name),
+        })
+        .filter(|change| change.a != change.b)
+        .collect()
+}
+
+fn diff_lines(a: Option<&str>, b: Option<&str>) -> Vec<LineChange> {
+    let (a, b) = (a.unwrap_or_default(), b.unwrap_or_default());
+
+    TextDiff::from_lines(a, b)
+        .iter_all_changes()
+        .filter_map(|change| {
+            let op = match change.tag() {
+                ChangeTag::Delete => "-",
+                ChangeTag::Insert => "+",
+                ChangeTag::Equal => return None,
+            };
+
+            Some(LineChange {
+                op,
+                line: change.value().trim_end_matches(['\r', '\n']).to_owned(),
+            })
+        })
+        .collect()
+}
+
+fn diff_attachments(a: &Message, b: &Message) -> Vec<AttachmentChange> {
+    let hash = |message: &Message, filename: &str| {
+        message
+            .attachments
+            .iter()
+            .find(|attachment| attachment.filename == filename)
+            .map(|attachment| {
+                Sha256::digest(&attachment.body)
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+            })
+    };
+
+    let filenames: BTreeSet<&String> = a
+        .attachments
+        .iter()
+        .chain(&b.attachments)
+        .map(|attachment| &attachment.filename)
+        .collect();
+
+    filenames
+        .into_iter()
+        .map(|filename| AttachmentChange {
+            filename: filename.clone(),
+            a: hash(a, filename),
+            b: hash(b, filename),
+        })
+        .filter(|change| change.a != change.b)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MessageBuilder;
+    use crate::Mailtutan;
+
+    #[tokio::test]
+    async fn test_diff_reports_changed_line() {
+        let state = Mailtutan::default().init();
+        let template = |greeting: &str, tracking: &str| {
+            let data = MessageBuilder::new()
+                .subject("Welcome")
+                .header("X-Template", tracking)
+                .plain(&format!("{}\r\nThanks for joining.\r\nThe team", greeting))
+                .attachment("logo.png", "image/png", b"same bytes")
+                .build();
+            Message::try_from(&data).unwrap()
+        };
+
+        let a = state
+            .lock()
+            .unwrap()
+            .capture(template("Hello Alice,", "v1"))
+            .unwrap();
+        let b = state
+            .lock()
+            .unwrap()
+            .capture(template("Hi Alice,", "v2"))
+            .unwrap();
+
+        let query = DiffQuery {
+            a: a.id.unwrap(),
+            b: b.id.unwrap(),
+        };
+        let Json(diff) = show(State(state), Query(query)).await.unwrap();
+
+        assert_eq!(diff.subject, None);
+        assert_eq!(
+            diff.plain,
+            vec![
+                LineChange {
+                    op: "-",
+                    line: "Hello Alice,".to_owned()
+                },
+                LineChange {
+                    op: "+",
+                    line: "Hi Alice,".to_owned()
+                },
+            ]
+        );
+        assert_eq!(
+            diff.headers,
+            vec![HeaderChange {
+                name: "x-template".to_owned(),
+                a: vec!["v1".to_owned()],
+                b: vec!["v2".to_owned()],
+            }]
+        );
+        assert!(diff.attachments.is_empty());
+    }
+}
@@ -0,0 +1,22 @@
+use crate::models::ParseFailure;
+use crate::AppState;
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct Failures {
+    /// Every unparseable delivery since startup, not only the kept ones.
+    pub count: usize,
+    /// The newest ones, oldest first.
+    pub failures: Vec<ParseFailure>,
+}
+
+pub async fn index(State(state): State<AppState>) -> Json<Failures> {
+    let app = state.lock().unwrap();
+
+    Json(Failures {
+        count: app.parse_failure_count,
+        failures: app.parse_failures.iter().cloned().collect(),
+    })
+}
@@ -0,0 +1,269 @@
+use crate::models::{html_to_text, Message};
+use crate::{AppState, Result};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use serde::Deserialize;
+
+const DEFAULT_LIMIT: usize = 20;
+/// Characters of body text shown per entry.
+const SNIPPET_LENGTH: usize = 200;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FeedQuery {
+    /// Entries in the feed, newest first; 20 when absent.
+    pub limit: Option<usize>,
+}
+
+/// The most recent messages as an Atom feed for feed readers.
+///
+/// Links start with `public_url` when set, else with the host the request
+/// was made to, and are relative when it didn't name one.
+pub async fn show(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<FeedQuery>,
+) -> Result<impl IntoResponse> {
+    let (mut messages, public_url) = {
+        let app = state.lock().unwrap();
+        (app.storage.list()?, app.public_url.clone())
+    };
+
+    let base = match public_url {
+        Some(public_url) => public_url.trim_end_matches('/').to_owned(),
+        None => headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(|host| format!("http://{}", host))
+            .unwrap_or_default(),
+    };
+
+    messages.sort_by_key(|message| std::cmp::Reverse(message.id));
+    messages.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+
+    Ok((
+        StatusCode::OK,
+        [("Content-Type", "application/atom+xml; charset=utf-8")],
+        render(&messages, &base),
+    ))
+}
+
+fn render(messages: &[Message], base: &str) -> String {
+    let updated = messages
+        .first()
+        .and_then(created_at)
+        .unwrap_or_else(Local::now);
+
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str("  <title>Mailtutan</title>\n");
+    feed.push_str("  <id>urn:mailtutan:messages</id>\n");
+    feed.push_str(&format!("  <updated>{}</updated>\n", updated.to_rfc3339()));
+    feed.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}/api/messages/feed.atom\"/>\n",
+        escape(base)
+    ));
+
+    for message in messages {
+        let id = message.id.unwrap_or_default();
+        let updated = created_at(message).unwrap_or_else(Local::now);
+        let author = match message.sender.as_str() {
+            "" => "unknown",
+            sender => sender,
+        };
+
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!(
+            "    <title>{}</title>\n",
+            escape(&message.subject)
+        ));
+        feed.push_str(&format!("    <id>urn:mailtutan:message:{}</id>\n", id));
+        feed.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            updated.to_rfc3339()
+        ));
+        feed.push_str(&format!(
+            "    <author><name>{}</name></author>\n",
+            escape(author)
+        ));
+        feed.push_str(&format!(
+            "    <link href=\"{}/api/messages/{}/json\"/>\n",
+            escape(base),
+            id
+        ));
+        feed.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(&snippet(message))
+        ));
+        feed.push_str("  </entry>\n");
+    }
+
+    feed.push_str("</feed>\n");
+    feed
+}
+
+/// `created_at` is stored in local time without an offset.
+fn created_at(message: &Message) -> Option<DateTime<Local>> {
+    let created_at =
+        NaiveDateTime::parse_from_str(message.created_at.as_deref()?, "%Y-%m-%d %H:%M:%S").ok()?;
+
+    Local.from_local_datetime(&created_at).earliest()
+}
+
+/// The start of the plain body, or of the html body's text, on one line.
+fn snippet(message: &Message) -> String {
+    let text = match (&message.plain, &message.html) {
+        (Some(plain), _) => plain.clone(),
+        (None, Some(html)) => html_to_text(html),
+        (None, None) => String::new(),
+    };
+
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(SNIPPET_LENGTH)
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Not allowed anywhere in XML 1.0.
+            '\u{0}'..='\u{8}' | '\u{b}' | '\u{c}' | '\u{e}'..='\u{1f}' => {}
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{body_bytes, MessageBuilder};
+    use crate::Mailtutan;
+
+    /// Checks that every tag is closed in order and that text holds no
+    /// bare markup characters.
+    fn assert_well_formed(xml: &str) {
+        let body = xml
+            .strip_prefix("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n")
+            .expect("xml declaration");
+        let mut open: Vec<String> = vec![];
+        let mut rest = body;
+
+        while let Some(start) = rest.find('<') {
+            assert!(!rest[..start].contains('>'), "stray '>' in {:?}", rest);
+            let end = start + rest[start..].find('>').expect("unterminated tag");
+            let tag = &rest[start + 1..end];
+
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop().as_deref(), Some(name));
+            } else if !tag.ends_with('/') {
+                let name = tag.split(' ').next().unwrap();
+                open.push(name.to_owned());
+            }
+            assert_eq!(tag.matches('"').count() % 2, 0, "unbalanced quotes");
+
+            rest = &rest[end + 1..];
+        }
+
+        assert!(rest.trim().is_empty());
+        assert!(open.is_empty(), "unclosed {:?}", open);
+    }
+
+    #[tokio::test]
+    async fn test_feed_lists_messages() {
+        let state = Mailtutan::default().init();
+
+        for subject in ["Welcome <new> user", "Invoice & receipt", "Reset password"] {
+            let data = MessageBuilder::new()
+                .subject(subject)
+                .plain("Hello there,\r\nthanks for signing up.")
+                .build();
+            state
+                .lock()
+                .unwrap()
+                .capture(Message::try_from(&data).unwrap())
+                .unwrap();
+        }
+
+        let query = FeedQuery { limit: Some(2) };
+        let response = show(State(state), HeaderMap::new(), Query(query))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/atom+xml; charset=utf-8"
+        );
+
+        let feed = body_bytes(response).await;
+        let feed = String::from_utf8(feed).unwrap();
+
+        assert_well_formed(&feed);
+        assert_eq!(feed.matches("<entry>").count(), 2);
+        assert!(feed.contains("<title>Reset password</title>"));
+        assert!(feed.contains("<title>Invoice &amp; receipt</title>"));
+        assert!(!feed.contains("Welcome"));
+        assert!(
+            feed.contains("<content type=\"text\">Hello there, thanks for signing up.</content>")
+        );
+        assert!(feed.contains("<link rel=\"self\" href=\"/api/messages/feed.atom\"/>"));
+        assert!(feed.contains("<link href=\"/api/messages/3/json\"/>"));
+    }
+
+    #[tokio::test]
+    async fn test_feed_links() {
+        let feed = |public_url: Option<&str>, host: Option<&str>| {
+            let state = Mailtutan {
+                public_url: public_url.map(str::to_owned),
+                ..Default::default()
+            }
+            .init();
+            let data = MessageBuilder::new().subject("linked").build();
+            state
+                .lock()
+                .unwrap()
+                .capture(Message::try_from(&data).unwrap())
+                .unwrap();
+
+            let mut headers = HeaderMap::new();
+            if let Some(host) = host {
+                headers.insert(header::HOST, host.parse().unwrap());
+            }
+
+            async move {
+                let response = show(State(state), headers, Query(FeedQuery::default()))
+                    .await
+                    .unwrap()
+                    .into_response();
+                String::from_utf8(body_bytes(response).await).unwrap()
+            }
+        };
+
+        let proxied = feed(
+            Some("https://mail.example.com/tools/"),
+            Some("10.0.0.1:1080"),
+        )
+        .await;
+        assert!(
+            proxied.contains("<link href=\"https://mail.example.com/tools/api/messages/1/json\"/>")
+        );
+
+        let direct = feed(None, Some("localhost:1080")).await;
+        assert!(direct.contains("<link href=\"http://localhost:1080/api/messages/1/json\"/>"));
+        assert!(direct.contains(
+            "<link rel=\"self\" href=\"http://localhost:1080/api/messages/feed.atom\"/>"
+        ));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+
+/// Liveness: the process is up and serving HTTP.
+pub async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness: the SMTP listener is bound and the storage is usable.
+pub async fn readyz(State(state): State<AppState>) -> StatusCode {
+    let app = state.lock().unwrap();
+
+    if app.smtp_ready && app.storage.healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mailtutan;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_readyz_waits_for_smtp() {
+        let state = Mailtutan {
+            ip: "127.0.0.1".parse().unwrap(),
+            smtp_ports: vec![0],
+            ..Default::default()
+        }
+        .init();
+
+        assert_eq!(healthz().await, StatusCode::OK);
+        assert_eq!(
+            readyz(State(state.clone())).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        tokio::spawn(crate::smtp::serve(state.clone()));
+
+        for _ in 0..100 {
+            if readyz(State(state.clone())).await == StatusCode::OK {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("readyz never became ready");
+    }
+}
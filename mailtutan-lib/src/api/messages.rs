@@ -1,119 +1,1965 @@
-use crate::models::Message;
-use crate::APP;
-use axum::extract::Path;
-use axum::http::StatusCode;
-use axum::response::Html;
-use axum::response::IntoResponse;
-use axum::Json;
+use super::case::Verbatim;
+use super::timing::Timings;
+use crate::models::{lint, neutralize_tracking_pixels, Lint, Message, MimePart};
+use crate::{AppState, Error, Result};
+use axum::body::StreamBody;
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::NaiveDateTime;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::time::Instant;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
-pub async fn index() -> Json<Vec<Message>> {
-    Json(APP.get().unwrap().lock().unwrap().storage.list().to_vec())
+/// Query parameters narrowing down the message list.
+#[derive(Deserialize, Default, Debug)]
+pub struct Filter {
+    pub label: Option<String>,
+    /// Case- and accent-insensitive substring searched in subject, sender and
+    /// recipients.
+    pub q: Option<String>,
+    /// Also search `q` in the plain and tag-stripped html bodies.
+    #[serde(default)]
+    pub body: bool,
+    /// Only messages with a greater id, newest first.
+    pub since_id: Option<usize>,
+    /// Substring of an attachment filename.
+    pub attachment_name: Option<String>,
+    /// Exact attachment mimetype, e.g. `application/pdf`.
+    pub attachment_type: Option<String>,
+    /// Address in the `To` header, case-insensitive.
+    pub to: Option<String>,
+    /// Address given in RCPT TO, which also covers BCC recipients.
+    pub envelope_to: Option<String>,
+    /// Keep only messages flagged (`true`) or not flagged (`false`) as spam.
+    pub spam: Option<bool>,
+    /// Address in the `From` header or given in MAIL FROM, case-insensitive.
+    pub sender: Option<String>,
+    /// Name of the mailbox the message was routed into.
+    pub mailbox: Option<String>,
+    /// Keep only messages with (`true`) or without (`false`) attachments.
+    pub has_attachments: Option<bool>,
+    /// Smallest `size` in bytes, inclusive.
+    pub min_size: Option<usize>,
+    /// Largest `size` in bytes, inclusive.
+    pub max_size: Option<usize>,
+    /// Also list quarantined messages, which are hidden otherwise.
+    #[serde(default)]
+    pub include_quarantined: bool,
+    /// Collapse runs of identical messages (same sender, subject and
+    /// bodies) into one entry with `count` and `ids`.
+    #[serde(default)]
+    pub group: bool,
 }
 
-pub async fn show_source(Path(id): Path<usize>) -> impl IntoResponse {
-    (
-        StatusCode::OK,
-        [("Content-Type", "text/plain;charset=utf-8")],
-        APP.get()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .storage
-            .get(id)
-            .source
-            .clone(),
+impl Filter {
+    pub fn matches(&self, message: &Message) -> bool {
+        if message.quarantined && !self.include_quarantined {
+            return false;
+        }
+
+        if let Some(label) = &self.label {
+            if !message.labels.contains(label) {
+                return false;
+            }
+        }
+
+        if let Some(q) = &self.q {
+            if !self.search(message, &fold(q)) {
+                return false;
+            }
+        }
+
+        if let Some(name) = &self.attachment_name {
+            if !message
+                .attachments
+                .iter()
+                .any(|attachment| attachment.filename.contains(name.as_str()))
+            {
+                return false;
+            }
+        }
+
+        if let Some(mime_type) = &self.attachment_type {
+            if !message
+                .attachments
+                .iter()
+                .any(|attachment| attachment.mime_type.eq_ignore_ascii_case(mime_type))
+            {
+                return false;
+            }
+        }
+
+        let has_address = |list: &[String], address: &str| {
+            list.iter().any(|item| item.eq_ignore_ascii_case(address))
+        };
+
+        if let Some(to) = &self.to {
+            if !has_address(&message.recipients, to)
+                && !has_address(&message.normalized_recipients, to)
+            {
+                return false;
+            }
+        }
+
+        if let Some(envelope_to) = &self.envelope_to {
+            if !has_address(&message.envelope_recipients, envelope_to) {
+                return false;
+            }
+        }
+
+        if self.spam.is_some_and(|spam| spam != message.spam) {
+            return false;
+        }
+
+        if self.min_size.is_some_and(|min| message.size < min)
+            || self.max_size.is_some_and(|max| message.size > max)
+        {
+            return false;
+        }
+
+        if self
+            .has_attachments
+            .is_some_and(|has_attachments| has_attachments == message.attachments.is_empty())
+        {
+            return false;
+        }
+
+        if self
+            .mailbox
+            .as_ref()
+            .is_some_and(|mailbox| *mailbox != message.mailbox)
+        {
+            return false;
+        }
+
+        if let Some(sender) = &self.sender {
+            // `sender` is `Name address` when the header carries a name.
+            let from = message.sender.rsplit(' ').next().unwrap_or_default();
+
+            let mut senders = vec![from.to_owned(), message.envelope_sender.clone()];
+            senders.extend(message.normalized_sender.clone());
+
+            if !has_address(&senders, sender) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn search(&self, message: &Message, needle: &str) -> bool {
+        let found = |text: &str| fold(text).contains(needle);
+
+        if found(&message.subject)
+            || found(&message.sender)
+            || message.recipients.iter().any(|recipient| found(recipient))
+        {
+            return true;
+        }
+
+        // Bodies are only scanned when asked for, and the html one only
+        // after the cheaper plain body missed.
+        self.body
+            && (message.plain.as_deref().is_some_and(found)
+                || message
+                    .html
+                    .as_deref()
+                    .is_some_and(|html| found(&strip_tags(html))))
+    }
+}
+
+/// Lowercases and drops diacritics, so "Résumé" compares equal to "resume".
+fn fold(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    text
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct LabelsUpdate {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// The message list, answering 304 while the client's `If-None-Match`
+/// still names the current list.
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+    query: Query<Filter>,
+) -> Result<Response> {
+    let mut timings = Timings::default();
+    let started = Instant::now();
+    let etag = list_etag(&state, raw_query.as_deref().unwrap_or_default());
+    let etag = HeaderValue::from_str(&etag).map_err(|err| Error::Parse(err.to_string()))?;
+
+    if headers.get(header::IF_NONE_MATCH) == Some(&etag) {
+        timings.record("storage", started);
+        let mut response = (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+        response.extensions_mut().insert(timings);
+        return Ok(response);
+    }
+
+    let group = query.group;
+    let (limit, inline_body_size) = {
+        let app = state.lock().unwrap();
+        let limit = match query.since_id {
+            Some(_) => None,
+            None => app.max_list_size,
+        };
+        (limit, app.inline_body_size)
+    };
+    let Json(mut messages) = index(State(state), query).await?;
+    let total = messages.len();
+    timings.record("storage", started);
+
+    let started = Instant::now();
+    if let Some(limit) = limit.filter(|limit| total > *limit) {
+        messages.sort_by_key(|message| std::cmp::Reverse(message.id));
+        messages.truncate(limit);
+    }
+    let body = match (inline_body_size, group) {
+        (_, true) => Json(group_duplicates(messages, inline_body_size)?).into_response(),
+        (Some(max), false) => Json(inline_bodies(messages, max)?).into_response(),
+        (None, false) => Json(messages).into_response(),
+    };
+
+    let mut response = if limit.is_some_and(|limit| total > limit) {
+        (
+            [
+                (header::ETAG, etag),
+                (
+                    HeaderName::from_static(TOTAL_COUNT),
+                    HeaderValue::from(total),
+                ),
+                (
+                    HeaderName::from_static(TRUNCATED),
+                    HeaderValue::from_static("true"),
+                ),
+            ],
+            body,
+        )
+            .into_response()
+    } else {
+        ([(header::ETAG, etag)], body).into_response()
+    };
+    timings.record("serialize", started);
+
+    response.extensions_mut().insert(timings);
+    Ok(response)
+}
+
+/// The list entries, with `plain` and `html` added to those of messages
+/// smaller than `max` bytes.
+fn inline_bodies(messages: Vec<Message>, max: usize) -> Result<Vec<serde_json::Value>> {
+    messages
+        .into_iter()
+        .map(|message| {
+            let mut value =
+                serde_json::to_value(&message).map_err(|err| Error::Parse(err.to_string()))?;
+
+            if message.size < max {
+                value["plain"] = message.plain.into();
+                value["html"] = message.html.into();
+            }
+
+            Ok(value)
+        })
+        .collect()
+}
+
+/// The list entries, newest first, with consecutive identical messages
+/// merged into the newest one, which gets the `count` of messages and
+/// their `ids`.
+fn group_duplicates(
+    mut messages: Vec<Message>,
+    inline_body_size: Option<usize>,
+) -> Result<Vec<serde_json::Value>> {
+    messages.sort_by_key(|message| std::cmp::Reverse(message.id));
+
+    let keys: Vec<_> = messages.iter().map(duplicate_key).collect();
+    let ids: Vec<_> = messages.iter().map(|message| message.id).collect();
+    let values = match inline_body_size {
+        Some(max) => inline_bodies(messages, max)?,
+        None => messages
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|err| Error::Parse(err.to_string()))?,
+    };
+
+    let mut groups: Vec<(Vec<u8>, serde_json::Value, Vec<Option<usize>>)> = vec![];
+    for ((key, value), id) in keys.into_iter().zip(values).zip(ids) {
+        match groups.last_mut() {
+            Some((last, _, ids)) if *last == key => ids.push(id),
+            _ => groups.push((key, value, vec![id])),
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(_, mut value, ids)| {
+            value["count"] = ids.len().into();
+            value["ids"] = ids.into();
+            value
+        })
+        .collect())
+}
+
+/// Hash of what makes repeated notifications look the same.
+fn duplicate_key(message: &Message) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in [
+        Some(&message.sender),
+        Some(&message.subject),
+        message.plain.as_ref(),
+        message.html.as_ref(),
+    ] {
+        // Length-prefixed, so moving text between fields changes the hash.
+        let part = part.map_or(&b""[..], |part| part.as_bytes());
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+
+    hasher.finalize().to_vec()
+}
+
+/// Number of matching messages, sent along when the list was truncated.
+const TOTAL_COUNT: &str = "x-total-count";
+/// Set when only the newest `max_list_size` messages were returned.
+const TRUNCATED: &str = "x-truncated";
+
+/// Changes whenever a stored message is added, edited or removed, and
+/// differs between queries. The query parameters are sorted, so their
+/// order doesn't matter.
+fn list_etag(state: &AppState, query: &str) -> String {
+    let mut parameters: Vec<&str> = query.split('&').filter(|pair| !pair.is_empty()).collect();
+    parameters.sort_unstable();
+    let query = Sha256::digest(parameters.join("&"));
+
+    let app = state.lock().unwrap();
+
+    format!(
+        "\"{}-{}-{}-{:x}\"",
+        app.storage.last_id().unwrap_or_default(),
+        app.storage.size(),
+        app.storage.revision(),
+        u64::from_be_bytes(query[..8].try_into().unwrap())
     )
 }
 
-pub async fn delete(Path(id): Path<usize>) -> impl IntoResponse {
-    APP.get().unwrap().lock().unwrap().storage.remove(id);
+pub async fn index(
+    State(state): State<AppState>,
+    Query(filter): Query<Filter>,
+) -> Result<Json<Vec<Message>>> {
+    let messages = {
+        let app = state.lock().unwrap();
+
+        match filter.since_id {
+            Some(id) => app.storage.list_since(id)?,
+            None => app.storage.list()?,
+        }
+    };
+
+    Ok(Json(
+        messages
+            .into_iter()
+            .filter(|message| filter.matches(message))
+            .collect(),
+    ))
+}
+
+pub async fn update_labels(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Json(update): Json<LabelsUpdate>,
+) -> Result<Json<Message>> {
+    let mut app = state.lock().unwrap();
+    let mut message = app.storage.get(id)?;
+
+    message
+        .labels
+        .retain(|label| !update.remove.contains(label));
+
+    for label in update.add {
+        if !message.labels.contains(&label) {
+            message.labels.push(label);
+        }
+    }
+
+    Ok(Json(app.storage.update(message)?))
+}
+
+pub async fn quarantine(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Message>> {
+    set_quarantined(&state, id, true)
+}
+
+pub async fn unquarantine(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Message>> {
+    set_quarantined(&state, id, false)
+}
+
+fn set_quarantined(state: &AppState, id: usize, quarantined: bool) -> Result<Json<Message>> {
+    let mut app = state.lock().unwrap();
+    let mut message = app.storage.get(id)?;
+    message.quarantined = quarantined;
 
-    StatusCode::OK
+    Ok(Json(app.storage.update(message)?))
 }
 
-pub async fn show_plain(Path(id): Path<usize>) -> impl IntoResponse {
-    (
+/// Looks up a message whose body is about to be served.
+pub(super) fn with_bodies(state: &AppState, id: usize) -> Result<Message> {
+    let message = state.lock().unwrap().storage.get(id)?;
+
+    if message.bodies_discarded {
+        return Err(Error::Gone(format!(
+            "bodies of message {} were discarded in headers-only mode",
+            id
+        )));
+    }
+
+    Ok(message)
+}
+
+/// Feeds a stored message through the capture path again as a new delivery.
+pub async fn replay(State(state): State<AppState>, Path(id): Path<usize>) -> Result<Json<Message>> {
+    let original = with_bodies(&state, id)?;
+    let mut app = state.lock().unwrap();
+
+    let mut message = Message::parse(&original.source, &app.parse_options)?;
+    message.envelope_sender = original.envelope_sender;
+    message.envelope_recipients = original.envelope_recipients;
+
+    Ok(Json(app.capture(message)?))
+}
+
+/// Streams every stored message as one mboxrd file, oldest first. Only the
+/// ids are taken up front; each message is loaded when the stream reaches
+/// it, so a large mailbox isn't held in memory, and messages deleted in the
+/// meantime are left out.
+pub async fn export_mbox(State(state): State<AppState>) -> Result<impl IntoResponse> {
+    let mut ids = state.lock().unwrap().storage.ids()?;
+    ids.sort_unstable();
+
+    let entries = futures::stream::iter(ids).filter_map(move |id| {
+        let message = state.lock().unwrap().storage.get(id);
+
+        async move {
+            match message {
+                Ok(message) => Some(Ok(mbox_entry(&message))),
+                Err(Error::Storage(_)) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }
+    });
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", "application/mbox"),
+            (
+                "Content-Disposition",
+                "attachment; filename=\"messages.mbox\"",
+            ),
+        ],
+        StreamBody::new(entries),
+    ))
+}
+
+fn mbox_entry(message: &Message) -> Vec<u8> {
+    let sender = match message.envelope_sender.as_str() {
+        "" => "MAILER-DAEMON",
+        sender => sender,
+    };
+    let date = message
+        .created_at
+        .as_deref()
+        .and_then(|created_at| NaiveDateTime::parse_from_str(created_at, "%Y-%m-%d %H:%M:%S").ok())
+        .unwrap_or_default()
+        .format("%a %b %e %H:%M:%S %Y");
+
+    let mut entry = format!("From {} {}\n", sender, date).into_bytes();
+
+    for line in message.source.split_inclusive(|byte| *byte == b'\n') {
+        if line
+            .iter()
+            .skip_while(|byte| **byte == b'>')
+            .take(5)
+            .eq(b"From ")
+        {
+            entry.push(b'>');
+        }
+
+        entry.extend_from_slice(line);
+    }
+
+    if !entry.ends_with(b"\n") {
+        entry.push(b'\n');
+    }
+
+    entry.push(b'\n');
+    entry
+}
+
+pub async fn show_source(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse> {
+    Ok((
         StatusCode::OK,
         [("Content-Type", "text/plain;charset=utf-8")],
-        APP.get()
+        with_bodies(&state, id)?.source,
+    ))
+}
+
+pub async fn delete(State(state): State<AppState>, Path(id): Path<usize>) -> Result<StatusCode> {
+    state.lock().unwrap().storage.remove(id)?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn show_plain(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse> {
+    let plain = with_bodies(&state, id)?.plain;
+
+    Ok(match plain {
+        Some(plain) => (
+            StatusCode::OK,
+            [("Content-Type", "text/plain;charset=utf-8")],
+            plain,
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            [("Content-Type", "text/plain;charset=utf-8")],
+            "".to_owned(),
+        ),
+    })
+}
+
+/// The plain body pretty-printed, for messages whose plain body is JSON.
+pub async fn show_pretty_json(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse> {
+    let pretty_json = with_bodies(&state, id)?.pretty_json;
+
+    let (status, body) = match pretty_json {
+        Some(pretty_json) => (StatusCode::OK, pretty_json),
+        None => (StatusCode::NOT_FOUND, String::new()),
+    };
+
+    // The keys are the sender's, not ours to rename.
+    Ok((
+        status,
+        [("Content-Type", "application/json")],
+        Extension(Verbatim),
+        body,
+    ))
+}
+
+pub async fn show_html(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse> {
+    let message = with_bodies(&state, id)?;
+    let (base, block_tracking_pixels) = {
+        let app = state.lock().unwrap();
+        (
+            app.public_url.clone().unwrap_or_default(),
+            app.block_tracking_pixels,
+        )
+    };
+    let html = message.html.as_deref().map(|html| {
+        let html = rewrite_cids(html, &message, &base);
+        match block_tracking_pixels {
+            true => neutralize_tracking_pixels(&html),
+            false => html,
+        }
+    });
+
+    Ok(match html {
+        Some(html) => (
+            StatusCode::OK,
+            [("Content-Type", "text/html;charset=utf-8")],
+            html,
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            [("Content-Type", "text/html;charset=utf-8")],
+            "".to_owned(),
+        ),
+    })
+}
+
+/// Points `cid:` references at the attachment download endpoint, prefixed
+/// with `base` (relative when empty).
+fn rewrite_cids(html: &str, message: &Message, base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let mut html = html.to_owned();
+
+    for attachment in &message.attachments {
+        if let (Some(content_id), Some(id)) = (&attachment.content_id, message.id) {
+            html = html.replace(
+                &format!("cid:{}", content_id),
+                &format!("{}/api/messages/{}/parts/{}", base, id, attachment.cid),
+            );
+        }
+    }
+
+    html
+}
+
+pub async fn show_eml(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse> {
+    Ok((
+        StatusCode::OK,
+        [("Content-Type", "message/rfc822")],
+        with_bodies(&state, id)?.source,
+    ))
+}
+
+/// Size of the chunks attachment downloads are streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub async fn download_attachment(
+    State(state): State<AppState>,
+    Path((id, cid)): Path<(usize, String)>,
+) -> Result<Response> {
+    let attachment = state.lock().unwrap().storage.attachment(id, &cid)?;
+
+    if let Some((filename, body)) = attachment {
+        // The body is handed out in slices of the one buffer instead of
+        // being copied into the response at once.
+        let chunks = (0..body.len()).step_by(CHUNK_SIZE).map(move |start| {
+            Ok::<_, Infallible>(body.slice(start..body.len().min(start + CHUNK_SIZE)))
+        });
+
+        return Ok((
+            StatusCode::OK,
+            [(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", filename),
+            )],
+            StreamBody::new(futures::stream::iter(chunks)),
+        )
+            .into_response());
+    }
+
+    Ok((
+        StatusCode::OK,
+        [("Content-Type", "message/rfc822".to_string())],
+        vec![],
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct JsonOptions {
+    /// Inline the attachment bodies, base64 encoded.
+    #[serde(default)]
+    pub include_bodies: bool,
+}
+
+pub async fn show_json(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Query(options): Query<JsonOptions>,
+) -> Result<(Extension<Timings>, Json<serde_json::Value>)> {
+    let mut timings = Timings::default();
+    let started = Instant::now();
+    let message = state.lock().unwrap().storage.get(id)?;
+    timings.record("storage", started);
+
+    let started = Instant::now();
+    let mut value = serde_json::to_value(&message).map_err(|err| Error::Parse(err.to_string()))?;
+
+    if options.include_bodies {
+        if let Some(attachments) = value["attachments"].as_array_mut() {
+            for (json, attachment) in attachments.iter_mut().zip(&message.attachments) {
+                json["body"] = STANDARD.encode(&attachment.body).into();
+            }
+        }
+    }
+
+    timings.record("serialize", started);
+
+    Ok((Extension(timings), Json(value)))
+}
+
+pub async fn show_links(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<String>>> {
+    let message = state.lock().unwrap().storage.get(id)?;
+
+    Ok(Json(message.links))
+}
+
+/// SMTP commands and replies of the message's delivery.
+pub async fn show_transcript(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<String>>> {
+    let message = state.lock().unwrap().storage.get(id)?;
+
+    Ok(Json(message.transcript))
+}
+
+/// Looks a message up by its `daily_sequence` number, e.g.
+/// `/api/messages/sequence/2024-06-01/7`.
+pub async fn show_by_sequence(
+    State(state): State<AppState>,
+    Path((day, number)): Path<(String, usize)>,
+) -> Result<Json<Message>> {
+    let sequence = format!("{}/{:03}", day, number);
+    let message = state
+        .lock()
+        .unwrap()
+        .storage
+        .list()?
+        .into_iter()
+        .find(|message| message.sequence.as_deref() == Some(sequence.as_str()))
+        .ok_or_else(|| Error::Storage(format!("message {} not found", sequence)))?;
+
+    Ok(Json(message))
+}
+
+pub async fn show_structure(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<MimePart>> {
+    let message = with_bodies(&state, id)?;
+
+    Ok(Json(MimePart::parse(&message.source)?))
+}
+
+pub async fn show_lint(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<Lint>>> {
+    let message = with_bodies(&state, id)?;
+
+    Ok(Json(lint(&message)))
+}
+
+#[derive(Serialize, Debug)]
+pub struct Deleted {
+    pub deleted: usize,
+}
+
+/// Deletes every message matching the filter parameters. Without any the
+/// whole inbox is cleared, quarantined messages included, with a single
+/// `clear` event.
+pub async fn delete_all(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+    Query(filter): Query<Filter>,
+) -> Result<Json<Deleted>> {
+    let mut app = state.lock().unwrap();
+
+    if query.as_deref().is_none_or(str::is_empty) {
+        let deleted = app.storage.size();
+        app.storage.delete_all()?;
+        app.notify("clear", &Message::default());
+
+        return Ok(Json(Deleted { deleted }));
+    }
+
+    let matching: Vec<Message> = app
+        .storage
+        .list()?
+        .into_iter()
+        .filter(|message| filter.matches(message))
+        .filter(|message| filter.since_id.is_none_or(|id| message.id > Some(id)))
+        .collect();
+
+    for message in &matching {
+        app.storage.remove(message.id.unwrap_or_default())?;
+        app.notify("delete", message);
+    }
+
+    Ok(Json(Deleted {
+        deleted: matching.len(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ParseOptions, DEFAULT_MAILBOX, PIXEL_PLACEHOLDER};
+    use crate::smtp::Session;
+    use crate::test_util::{body_bytes, MessageBuilder};
+    use crate::Mailtutan;
+    use http_body::Body;
+
+    fn message(subject: &str) -> Message {
+        let data = format!("Subject: {}\r\n\r\nbody\r\n", subject).into_bytes();
+        Message::try_from(&data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_show_by_sequence() {
+        let state = Mailtutan {
+            daily_sequence: true,
+            ..Default::default()
+        }
+        .init();
+        state.lock().unwrap().capture(message("first")).unwrap();
+        let second = state.lock().unwrap().capture(message("second")).unwrap();
+        let sequence = second.sequence.unwrap();
+        let (day, number) = sequence.split_once('/').unwrap();
+        assert_eq!(number, "002");
+
+        let path = Path((day.to_owned(), 2));
+        let Json(found) = show_by_sequence(State(state.clone()), path).await.unwrap();
+        assert_eq!(found.subject, "second");
+
+        let path = Path((day.to_owned(), 3));
+        assert!(show_by_sequence(State(state), path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quarantine() {
+        let state = Mailtutan::default().init();
+        let suspicious = state
+            .lock()
             .unwrap()
+            .capture(message("suspicious"))
+            .unwrap();
+        state.lock().unwrap().capture(message("regular")).unwrap();
+        let id = suspicious.id.unwrap();
+
+        let Json(updated) = quarantine(State(state.clone()), Path(id)).await.unwrap();
+        assert!(updated.quarantined);
+        assert!(state.lock().unwrap().storage.get(id).unwrap().quarantined);
+
+        let subjects = |list: Vec<Message>| -> Vec<String> {
+            let mut subjects: Vec<String> = list.into_iter().map(|m| m.subject).collect();
+            subjects.sort();
+            subjects
+        };
+
+        let Json(list) = index(State(state.clone()), Query(Filter::default()))
+            .await
+            .unwrap();
+        assert_eq!(subjects(list), vec!["regular"]);
+
+        let filter = Filter {
+            include_quarantined: true,
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert_eq!(subjects(list), vec!["regular", "suspicious"]);
+
+        let Json(updated) = unquarantine(State(state.clone()), Path(id)).await.unwrap();
+        assert!(!updated.quarantined);
+        let Json(list) = index(State(state.clone()), Query(Filter::default()))
+            .await
+            .unwrap();
+        assert_eq!(subjects(list), vec!["regular", "suspicious"]);
+    }
+
+    #[tokio::test]
+    async fn test_labels() {
+        let state = Mailtutan::default().init();
+        let first = state.lock().unwrap().capture(message("first")).unwrap();
+        state.lock().unwrap().capture(message("second")).unwrap();
+
+        let id = first.id.unwrap();
+        let update = LabelsUpdate {
+            add: vec!["checkout-flow".to_owned()],
+            ..Default::default()
+        };
+        let Json(updated) = update_labels(State(state.clone()), Path(id), Json(update))
+            .await
+            .unwrap();
+        assert_eq!(updated.labels, vec!["checkout-flow"]);
+
+        let filter = Filter {
+            label: Some("checkout-flow".to_owned()),
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].subject, "first");
+
+        let update = LabelsUpdate {
+            remove: vec!["checkout-flow".to_owned()],
+            ..Default::default()
+        };
+        let Json(updated) = update_labels(State(state.clone()), Path(id), Json(update))
+            .await
+            .unwrap();
+        assert!(updated.labels.is_empty());
+
+        let filter = Filter {
+            label: Some("checkout-flow".to_owned()),
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert!(list.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let state = Mailtutan::default().init();
+        let original = state.lock().unwrap().capture(message("flaky")).unwrap();
+
+        let Json(replayed) = replay(State(state.clone()), Path(original.id.unwrap()))
+            .await
+            .unwrap();
+        assert_ne!(replayed.id, original.id);
+        assert_eq!(replayed.source, original.source);
+        assert_eq!(state.lock().unwrap().storage.size(), 2);
+
+        let stored = state
             .lock()
             .unwrap()
             .storage
-            .get(id)
-            .plain
-            .as_ref()
+            .get(replayed.id.unwrap())
+            .unwrap();
+        assert_eq!(stored.source, original.source);
+    }
+
+    #[tokio::test]
+    async fn test_body_search() {
+        let state = Mailtutan::default().init();
+        let data = concat!(
+            "Subject: Your login code\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<p>Your code is <b>ABC-9137</b></p>\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+        state
+            .lock()
             .unwrap()
-            .clone(),
-    )
-}
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap();
+        state.lock().unwrap().capture(message("other")).unwrap();
 
-pub async fn show_html(Path(id): Path<usize>) -> impl IntoResponse {
-    (
-        StatusCode::OK,
-        [("Content-Type", "text/html;charset=utf-8")],
-        APP.get()
+        let filter = Filter {
+            q: Some("abc-9137".to_owned()),
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert!(list.is_empty());
+
+        let filter = Filter {
+            q: Some("abc-9137".to_owned()),
+            body: true,
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].subject, "Your login code");
+
+        let filter = Filter {
+            q: Some("LOGIN".to_owned()),
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert_eq!(list.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_accent_insensitive_search() {
+        let state = Mailtutan::default().init();
+        state.lock().unwrap().capture(message("Résumé")).unwrap();
+        state
+            .lock()
             .unwrap()
+            .capture(message("Cover letter"))
+            .unwrap();
+
+        let filter = Filter {
+            q: Some("resume".to_owned()),
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].subject, "Résumé");
+    }
+
+    #[tokio::test]
+    async fn test_large_attachment_is_streamed() {
+        let state = Mailtutan::default().init();
+        let content: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let data = MessageBuilder::new()
+            .plain("see attached")
+            .attachment("large.bin", "application/octet-stream", &content)
+            .build();
+        let message = Message::try_from(&data).unwrap();
+        let message = state.lock().unwrap().capture(message).unwrap();
+        let cid = message.attachments[0].cid.clone();
+
+        let response = download_attachment(State(state), Path((message.id.unwrap(), cid)))
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let mut body = response.into_body();
+        let mut downloaded = vec![];
+        let mut chunks = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= CHUNK_SIZE);
+            downloaded.extend_from_slice(&chunk);
+            chunks += 1;
+        }
+
+        assert_eq!(chunks, content.len() / CHUNK_SIZE);
+        assert!(downloaded == content);
+    }
+
+    #[tokio::test]
+    async fn test_binary_attachment_round_trips() {
+        let state = Mailtutan::default().init();
+        let content =
+            b"\x00\x01\xff\xfe\x80\r\n.\r\n..two dots\r\n.\x00after\r\n\x89PNG\x1a\n\xc3(".to_vec();
+
+        let mut data = concat!(
+            "Subject: binary\r\n",
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: multipart/mixed; boundary=\"mixed\"\r\n",
+            "\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "see attached\r\n",
+            "--mixed\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "Content-Disposition: attachment; filename=\"blob.bin\"\r\n",
+            "Content-Transfer-Encoding: binary\r\n",
+            "\r\n",
+        )
+        .as_bytes()
+        .to_vec();
+        data.extend_from_slice(&content);
+        data.extend_from_slice(b"\r\n--mixed--\r\n");
+
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+        for line in [
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com> BODY=BINARYMIME",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+        ] {
+            session.process(format!("{}\r\n", line).as_bytes()).await;
+        }
+        // Split into lines the way the connection reads them, dot-stuffed
+        // as a client would send them.
+        for line in data.split_inclusive(|byte| *byte == b'\n') {
+            let mut stuffed = vec![];
+            if line.starts_with(b".") {
+                stuffed.push(b'.');
+            }
+            stuffed.extend_from_slice(line);
+            assert_eq!(session.process(&stuffed).await, None);
+        }
+        assert_eq!(session.process(b".\r\n").await.unwrap().code, 250);
+
+        let message = state.lock().unwrap().storage.list().unwrap().remove(0);
+        assert_eq!(message.source, data);
+        let attachment = &message.attachments[0];
+        assert_eq!(attachment.filename, "blob.bin");
+
+        let response = download_attachment(
+            State(state),
+            Path((message.id.unwrap(), attachment.cid.clone())),
+        )
+        .await
+        .unwrap();
+        let downloaded = body_bytes(response).await;
+        assert_eq!(downloaded, content);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_content_ids() {
+        let data = concat!(
+            "Subject: images\r\n",
+            "Content-Type: multipart/related; boundary=b\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<img src=\"cid:logo@example.com\">\r\n",
+            "--b\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-Disposition: inline; filename=\"first.png\"\r\n",
+            "Content-ID: <logo@example.com>\r\n",
+            "\r\n",
+            "first\r\n",
+            "--b\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-Disposition: inline; filename=\"second.png\"\r\n",
+            "Content-ID: <logo@example.com>\r\n",
+            "\r\n",
+            "second\r\n",
+            "--b--\r\n"
+        );
+        let state = Mailtutan::default().init();
+        let message = state
             .lock()
             .unwrap()
-            .storage
-            .get(id)
-            .html
-            .as_ref()
+            .capture(Message::try_from(&data.as_bytes().to_vec()).unwrap())
+            .unwrap();
+        let id = message.id.unwrap();
+
+        assert!(message
+            .warnings
+            .contains(&"duplicate Content-ID: logo@example.com".to_owned()));
+        let content_ids: Vec<_> = message
+            .attachments
+            .iter()
+            .map(|attachment| attachment.content_id.as_deref().unwrap())
+            .collect();
+        assert_eq!(content_ids, vec!["logo@example.com", "logo@example.com-2"]);
+
+        for (content_id, expected) in [
+            ("logo@example.com", "first.png"),
+            ("logo@example.com-2", "second.png"),
+        ] {
+            let response = download_attachment(State(state.clone()), Path((id, content_id.into())))
+                .await
+                .unwrap();
+            let disposition = response.headers()["Content-Disposition"].to_str().unwrap();
+            assert!(disposition.contains(expected), "{}", disposition);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_mbox() {
+        let state = Mailtutan::default().init();
+        let sources = [
+            "Subject: first\r\n\r\nFrom the start\r\n>From quoted\r\n".to_owned(),
+            "Subject: second\n\nsecond body\n".to_owned(),
+        ];
+
+        for source in &sources {
+            let message = Message::try_from(&source.as_bytes().to_vec()).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let response = export_mbox(State(state)).await.unwrap().into_response();
+        let mbox = String::from_utf8(body_bytes(response).await).unwrap();
+
+        let mut split: Vec<String> = vec![];
+        for line in mbox.split_inclusive('\n') {
+            if line.starts_with("From ") {
+                split.push(String::new());
+            } else if let Some(entry) = split.last_mut() {
+                match line.strip_prefix('>') {
+                    Some(rest) if rest.trim_start_matches('>').starts_with("From ") => {
+                        entry.push_str(rest)
+                    }
+                    _ => entry.push_str(line),
+                }
+            }
+        }
+
+        let split: Vec<String> = split
+            .into_iter()
+            .map(|entry| entry.strip_suffix('\n').unwrap().to_owned())
+            .collect();
+        assert_eq!(split, sources);
+    }
+
+    #[tokio::test]
+    async fn test_export_mbox_loads_lazily() {
+        let state = Mailtutan::default().init();
+        for subject in ["kept", "deleted", "late"] {
+            state.lock().unwrap().capture(message(subject)).unwrap();
+        }
+
+        let response = export_mbox(State(state.clone()))
+            .await
             .unwrap()
-            .clone(),
-    )
-}
+            .into_response();
+        state.lock().unwrap().storage.remove(2).unwrap();
 
-pub async fn show_eml(Path(id): Path<usize>) -> impl IntoResponse {
-    (
-        StatusCode::OK,
-        [("Content-Type", "message/rfc822")],
-        APP.get()
+        let mbox = String::from_utf8(body_bytes(response).await).unwrap();
+        assert!(mbox.contains("Subject: kept"));
+        assert!(!mbox.contains("Subject: deleted"));
+        assert!(mbox.contains("Subject: late"));
+    }
+
+    #[tokio::test]
+    async fn test_since_id() {
+        let state = Mailtutan::default().init();
+        let first = state.lock().unwrap().capture(message("first")).unwrap();
+        state.lock().unwrap().capture(message("second")).unwrap();
+        state.lock().unwrap().capture(message("third")).unwrap();
+
+        let filter = Filter {
+            since_id: first.id,
+            ..Default::default()
+        };
+        let Json(list) = index(State(state.clone()), Query(filter)).await.unwrap();
+        let subjects: Vec<&str> = list
+            .iter()
+            .map(|message| message.subject.as_str())
+            .collect();
+        assert_eq!(subjects, vec!["third", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_include_bodies() {
+        let state = Mailtutan::default().init();
+        let data = concat!(
+            "Subject: with attachment\r\n",
+            "Content-Type: multipart/mixed; boundary=\"mixed\"\r\n",
+            "\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "see attached\r\n",
+            "--mixed\r\n",
+            "Content-Type: application/octet-stream; name=\"data.bin\"\r\n",
+            "Content-Disposition: attachment; filename=\"data.bin\"\r\n",
+            "\r\n",
+            "payload\r\n",
+            "--mixed--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+        let message = state
+            .lock()
             .unwrap()
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap();
+        let id = message.id.unwrap();
+
+        let (_, Json(json)) = show_json(
+            State(state.clone()),
+            Path(id),
+            Query(JsonOptions::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(json["attachments"][0]["filename"], "data.bin");
+        assert!(json["attachments"][0].get("body").is_none());
+
+        let options = JsonOptions {
+            include_bodies: true,
+        };
+        let (_, Json(json)) = show_json(State(state), Path(id), Query(options))
+            .await
+            .unwrap();
+        assert_eq!(json["attachments"][0]["body"], STANDARD.encode("payload"));
+    }
+
+    #[tokio::test]
+    async fn test_headers_only() {
+        let state = Mailtutan {
+            headers_only: true,
+            ..Default::default()
+        }
+        .init();
+        let data = MessageBuilder::new()
+            .from("sender@example.com")
+            .to("rcpt@example.com")
+            .subject("metadata only")
+            .plain("a very large body")
+            .build();
+        let message = state
             .lock()
             .unwrap()
-            .storage
-            .get(id)
-            .source
-            .clone(),
-    )
-}
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap();
+        let id = message.id.unwrap();
 
-pub async fn download_attachment(Path((id, cid)): Path<(usize, String)>) -> impl IntoResponse {
-    for attachment in &APP
-        .get()
-        .unwrap()
-        .lock()
-        .unwrap()
-        .storage
-        .get(id)
-        .attachments
-    {
-        if attachment.cid == cid {
-            return (
-                StatusCode::OK,
-                [(
-                    "Content-Disposition",
-                    format!("attachment; filename=\"{}\"", attachment.filename),
-                )],
-                attachment.body.clone(),
+        let (_, Json(json)) = show_json(
+            State(state.clone()),
+            Path(id),
+            Query(JsonOptions::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(json["subject"], "metadata only");
+        assert_eq!(json["sender"], "sender@example.com");
+        assert_eq!(json["bodies_discarded"], true);
+
+        let response = show_source(State(state.clone()), Path(id))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::GONE);
+
+        let response = show_plain(State(state), Path(id)).await.into_response();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn test_cid_links_use_public_url() {
+        let data = concat!(
+            "Subject: inline image\r\n",
+            "Content-Type: multipart/related; boundary=\"related\"\r\n",
+            "\r\n",
+            "--related\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<img src=\"cid:logo@example.com\">\r\n",
+            "--related\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-ID: <logo@example.com>\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "aGVsbG8=\r\n",
+            "--related--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        for (public_url, prefix) in [
+            (None, "/api/messages/"),
+            (
+                Some("https://mail.example.com/tools/"),
+                "https://mail.example.com/tools/api/messages/",
+            ),
+        ] {
+            let state = Mailtutan {
+                public_url: public_url.map(str::to_owned),
+                ..Default::default()
+            }
+            .init();
+            let message = state
+                .lock()
+                .unwrap()
+                .capture(Message::try_from(&data).unwrap())
+                .unwrap();
+            let id = message.id.unwrap();
+
+            let response = show_html(State(state), Path(id))
+                .await
+                .unwrap()
+                .into_response();
+            let html = String::from_utf8(body_bytes(response).await).unwrap();
+
+            let expected = format!(
+                "<img src=\"{}{}/parts/{}\">",
+                prefix, id, message.attachments[0].cid
             );
+            assert_eq!(html, expected);
         }
     }
 
-    (
-        StatusCode::OK,
-        [("Content-Type", "message/rfc822".to_string())],
-        vec![],
-    )
-}
+    #[tokio::test]
+    async fn test_tracking_pixels_are_blocked() {
+        let data = MessageBuilder::new()
+            .subject("newsletter")
+            .html("<p>News</p><img src=\"https://news.example.com/open.gif\" width=\"1\" height=\"1\">")
+            .build();
 
-pub async fn show_json(Path(id): Path<usize>) -> Json<Message> {
-    Json(APP.get().unwrap().lock().unwrap().storage.get(id).clone())
-}
+        for (block_tracking_pixels, src) in [
+            (false, "https://news.example.com/open.gif"),
+            (true, PIXEL_PLACEHOLDER),
+        ] {
+            let state = Mailtutan {
+                block_tracking_pixels,
+                ..Default::default()
+            }
+            .init();
+            let id = state
+                .lock()
+                .unwrap()
+                .capture(Message::try_from(&data).unwrap())
+                .unwrap()
+                .id
+                .unwrap();
+
+            let response = show_html(State(state.clone()), Path(id))
+                .await
+                .unwrap()
+                .into_response();
+            let html = String::from_utf8(body_bytes(response).await).unwrap();
+            assert_eq!(
+                html,
+                format!("<p>News</p><img src=\"{}\" width=\"1\" height=\"1\">", src)
+            );
+
+            let source = state.lock().unwrap().storage.get(id).unwrap().source;
+            assert!(String::from_utf8(source)
+                .unwrap()
+                .contains("https://news.example.com/open.gif"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attachment_filters() {
+        let state = Mailtutan::default().init();
+        let invoice = MessageBuilder::new()
+            .subject("invoice")
+            .plain("attached")
+            .attachment("invoice-2023.pdf", "application/pdf", b"%PDF")
+            .build();
+        let photo = MessageBuilder::new()
+            .subject("photo")
+            .plain("attached")
+            .attachment("cat.png", "image/png", b"png")
+            .build();
+
+        for data in [invoice, photo] {
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let search = |filter: Filter| {
+            let state = state.clone();
+            async move {
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                list.into_iter()
+                    .map(|message| message.subject)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let by_name = Filter {
+            attachment_name: Some("invoice".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(search(by_name).await, vec!["invoice"]);
+
+        let by_type = Filter {
+            attachment_type: Some("image/png".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(search(by_type).await, vec!["photo"]);
+
+        let both = Filter {
+            attachment_name: Some("invoice".to_owned()),
+            attachment_type: Some("image/png".to_owned()),
+            ..Default::default()
+        };
+        assert!(search(both).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_envelope_to_filter() {
+        let state = Mailtutan::default().init();
+        let data = MessageBuilder::new()
+            .to("visible@example.com")
+            .subject("bcc")
+            .plain("hidden copy")
+            .build();
+        let mut message = Message::try_from(&data).unwrap();
+        message.envelope_recipients = vec![
+            "visible@example.com".to_owned(),
+            "hidden@example.com".to_owned(),
+        ];
+        state.lock().unwrap().capture(message).unwrap();
+
+        let count = |filter: Filter| {
+            let state = state.clone();
+            async move {
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                list.len()
+            }
+        };
+
+        let by_envelope = Filter {
+            envelope_to: Some("Hidden@example.com".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(count(by_envelope).await, 1);
+
+        let by_header = Filter {
+            to: Some("hidden@example.com".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(count(by_header).await, 0);
+
+        let visible = Filter {
+            to: Some("visible@example.com".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(count(visible).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_normalized_addresses() {
+        let state = Mailtutan::default().init();
+        let options = ParseOptions {
+            normalize_addresses: true,
+            ..Default::default()
+        };
+        let data = MessageBuilder::new()
+            .from("Sender@Example.ORG")
+            .to("User@Example.COM")
+            .build();
+        let message = Message::parse(&data, &options).unwrap();
+        state.lock().unwrap().capture(message).unwrap();
+
+        let found = |filter: Filter| {
+            let state = state.clone();
+            async move {
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                list
+            }
+        };
+
+        let list = found(Filter {
+            to: Some("user@EXAMPLE.com".to_owned()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].recipients, vec!["User@Example.COM"]);
+        assert_eq!(list[0].normalized_recipients, vec!["User@example.com"]);
+        assert_eq!(
+            list[0].normalized_sender.as_deref(),
+            Some("Sender@example.org")
+        );
+
+        let list = found(Filter {
+            sender: Some("sender@example.org".to_owned()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(list.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_routing() {
+        let state = Mailtutan {
+            mailbox_rules: vec!["billing=domain:billing.example.com".parse().unwrap()],
+            ..Default::default()
+        }
+        .init();
+
+        for (subject, to) in [
+            ("invoice", "accounts@billing.example.com"),
+            ("welcome", "user@example.com"),
+        ] {
+            let data = MessageBuilder::new().subject(subject).to(to).build();
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let subjects = |mailbox: &str| {
+            let state = state.clone();
+            let filter = Filter {
+                mailbox: Some(mailbox.to_owned()),
+                ..Default::default()
+            };
+            async move {
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                list.into_iter()
+                    .map(|message| message.subject)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(subjects("billing").await, vec!["invoice"]);
+        assert_eq!(subjects(DEFAULT_MAILBOX).await, vec!["welcome"]);
+    }
+
+    #[tokio::test]
+    async fn test_has_attachments_filter() {
+        let state = Mailtutan::default().init();
+
+        let data = MessageBuilder::new()
+            .subject("with")
+            .plain("see attached")
+            .attachment("report.pdf", "application/pdf", b"%PDF")
+            .build();
+        let message = Message::try_from(&data).unwrap();
+        state.lock().unwrap().capture(message).unwrap();
+
+        let data = MessageBuilder::new().subject("without").plain("hi").build();
+        let message = Message::try_from(&data).unwrap();
+        state.lock().unwrap().capture(message).unwrap();
+
+        let subjects = |has_attachments: bool| {
+            let state = state.clone();
+            let filter = Filter {
+                has_attachments: Some(has_attachments),
+                ..Default::default()
+            };
+            async move {
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                list.into_iter()
+                    .map(|message| message.subject)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(subjects(true).await, vec!["with"]);
+        assert_eq!(subjects(false).await, vec!["without"]);
+    }
+
+    #[tokio::test]
+    async fn test_size_range_filter() {
+        let state = Mailtutan::default().init();
+        let mut sizes = vec![];
+
+        for length in [10, 100, 1000] {
+            let data = MessageBuilder::new().plain(&"x".repeat(length)).build();
+            let message = Message::try_from(&data).unwrap();
+            sizes.push(state.lock().unwrap().capture(message).unwrap().size);
+        }
+
+        let ids = |min_size: Option<usize>, max_size: Option<usize>| {
+            let state = state.clone();
+            let filter = Filter {
+                min_size,
+                max_size,
+                ..Default::default()
+            };
+            async move {
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                let mut ids: Vec<usize> =
+                    list.into_iter().filter_map(|message| message.id).collect();
+                ids.sort_unstable();
+                ids
+            }
+        };
 
-pub async fn delete_all() -> Html<&'static str> {
-    APP.get().unwrap().lock().unwrap().storage.delete_all();
-    Html("Ok")
+        assert_eq!(ids(Some(sizes[1]), None).await, vec![2, 3]);
+        assert_eq!(ids(None, Some(sizes[1])).await, vec![1, 2]);
+        assert_eq!(ids(Some(sizes[1]), Some(sizes[1])).await, vec![2]);
+        assert_eq!(ids(Some(sizes[0] + 1), Some(sizes[2] - 1)).await, vec![2]);
+        assert!(ids(Some(sizes[2] + 1), None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spam_keywords() {
+        let state = Mailtutan {
+            spam_keywords: vec!["Viagra".to_owned()],
+            ..Default::default()
+        }
+        .init();
+
+        for (subject, body) in [("offer", "cheap viagra here"), ("invoice", "see attached")] {
+            let data = MessageBuilder::new().subject(subject).plain(body).build();
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let subjects = |spam: bool| {
+            let state = state.clone();
+            async move {
+                let filter = Filter {
+                    spam: Some(spam),
+                    ..Default::default()
+                };
+                let Json(list) = index(State(state), Query(filter)).await.unwrap();
+                list.into_iter()
+                    .map(|message| (message.subject, message.spam))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(subjects(true).await, vec![("offer".to_owned(), true)]);
+        assert_eq!(subjects(false).await, vec![("invoice".to_owned(), false)]);
+    }
+
+    #[tokio::test]
+    async fn test_list_etag() {
+        let state = Mailtutan::default().init();
+        let capture = || {
+            let data = MessageBuilder::new().subject("poll").plain("body").build();
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        };
+        let request_query = |etag: Option<HeaderValue>, query: Option<&str>| {
+            let state = state.clone();
+            let query = query.map(str::to_owned);
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(etag) = etag {
+                    headers.insert(header::IF_NONE_MATCH, etag);
+                }
+                list(
+                    State(state),
+                    headers,
+                    RawQuery(query),
+                    Query(Filter::default()),
+                )
+                .await
+                .unwrap()
+            }
+        };
+        let request = |etag: Option<HeaderValue>| request_query(etag, None);
+
+        capture();
+        let first = request(None).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[header::ETAG].clone();
+
+        let second = request(Some(etag.clone())).await;
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(second.headers()[header::ETAG], etag);
+
+        capture();
+        let third = request(Some(etag.clone())).await;
+        assert_eq!(third.status(), StatusCode::OK);
+        assert_ne!(third.headers()[header::ETAG], etag);
+
+        // Editing a message in place keeps its id and the count.
+        let etag = third.headers()[header::ETAG].clone();
+        let update = LabelsUpdate {
+            add: vec!["seen".to_owned()],
+            ..Default::default()
+        };
+        let Json(updated) = update_labels(State(state.clone()), Path(1), Json(update))
+            .await
+            .unwrap();
+        assert_eq!(updated.labels, vec!["seen"]);
+        let labelled = request(Some(etag.clone())).await;
+        assert_eq!(labelled.status(), StatusCode::OK);
+        assert_ne!(labelled.headers()[header::ETAG], etag);
+
+        let etag = labelled.headers()[header::ETAG].clone();
+        let filtered = request_query(Some(etag.clone()), Some("q=poll&body=true")).await;
+        assert_eq!(filtered.status(), StatusCode::OK);
+        let filtered = filtered.headers()[header::ETAG].clone();
+        assert_ne!(filtered, etag);
+
+        let reordered = request_query(Some(filtered), Some("body=true&q=poll")).await;
+        assert_eq!(reordered.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_group_duplicates() {
+        let state = Mailtutan::default().init();
+
+        let alert = MessageBuilder::new()
+            .from("monitor@example.com")
+            .subject("Disk almost full")
+            .plain("90% used")
+            .build();
+        let resolved = MessageBuilder::new()
+            .from("monitor@example.com")
+            .subject("Disk almost full")
+            .plain("resolved")
+            .build();
+        let mut ids = vec![];
+        for data in [&alert, &alert, &alert, &resolved, &alert] {
+            let message = Message::try_from(data).unwrap();
+            let message = state.lock().unwrap().capture(message).unwrap();
+            ids.push(message.id.unwrap());
+        }
+
+        let filter = Filter {
+            group: true,
+            ..Default::default()
+        };
+        let response = list(
+            State(state.clone()),
+            HeaderMap::new(),
+            RawQuery(None),
+            Query(filter),
+        )
+        .await
+        .unwrap();
+        let bytes = body_bytes(response).await;
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+
+        let counts: Vec<_> = listed.iter().map(|entry| entry["count"].clone()).collect();
+        // Newest first, so the run of three comes last.
+        assert_eq!(counts, vec![1, 1, 3]);
+        assert_eq!(listed[1]["ids"], serde_json::json!([ids[3]]));
+        assert_eq!(listed[2]["id"], ids[2]);
+        assert_eq!(
+            listed[2]["ids"],
+            serde_json::json!([ids[2], ids[1], ids[0]])
+        );
+
+        // Grouped messages stay retrievable one by one.
+        for id in &ids[..3] {
+            assert!(state.lock().unwrap().storage.get(*id).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_small_bodies_are_inlined() {
+        let state = Mailtutan {
+            inline_body_size: Some(1024),
+            ..Default::default()
+        }
+        .init();
+
+        let small = MessageBuilder::new()
+            .subject("small")
+            .plain("short note")
+            .build();
+        let large = MessageBuilder::new()
+            .subject("large")
+            .plain(&"long report ".repeat(200))
+            .build();
+        for data in [small, large] {
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let response = list(
+            State(state),
+            HeaderMap::new(),
+            RawQuery(None),
+            Query(Filter::default()),
+        )
+        .await
+        .unwrap();
+        let bytes = body_bytes(response).await;
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        let entry = |subject: &str| {
+            listed
+                .iter()
+                .find(|message| message["subject"] == subject)
+                .unwrap()
+                .clone()
+        };
+
+        let small = entry("small");
+        assert_eq!(small["plain"], "short note");
+        assert!(small.get("html").is_some());
+        let large = entry("large");
+        assert!(large.get("plain").is_none());
+        assert!(large.get("html").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unpaginated_list_is_capped() {
+        let state = Mailtutan {
+            max_list_size: Some(2),
+            ..Default::default()
+        }
+        .init();
+
+        for subject in ["one", "two", "three"] {
+            let data = MessageBuilder::new().subject(subject).build();
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let response = list(
+            State(state.clone()),
+            HeaderMap::new(),
+            RawQuery(None),
+            Query(Filter::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()[TRUNCATED], "true");
+        assert_eq!(response.headers()[TOTAL_COUNT], "3");
+
+        let listed: Vec<serde_json::Value> =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        let subjects: Vec<&str> = listed
+            .iter()
+            .map(|message| message["subject"].as_str().unwrap())
+            .collect();
+        assert_eq!(subjects, vec!["three", "two"]);
+
+        // Paging with `since_id` isn't capped.
+        let filter = Filter {
+            since_id: Some(0),
+            ..Default::default()
+        };
+        let response = list(
+            State(state),
+            HeaderMap::new(),
+            RawQuery(None),
+            Query(filter),
+        )
+        .await
+        .unwrap();
+        assert!(response.headers().get(TRUNCATED).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_delete_by_sender() {
+        let state = Mailtutan::default().init();
+        let mut events = state.lock().unwrap().ws_sender.subscribe();
+
+        for (from, subject) in [
+            ("ci@example.com", "run 1"),
+            ("human@example.com", "keep"),
+            ("CI@example.com", "run 2"),
+        ] {
+            let data = MessageBuilder::new().from(from).subject(subject).build();
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+        while events.try_recv().is_ok() {}
+
+        let filter = Filter {
+            sender: Some("ci@example.com".to_owned()),
+            ..Default::default()
+        };
+        let query = RawQuery(Some("sender=ci@example.com".to_owned()));
+        let Json(deleted) = delete_all(State(state.clone()), query, Query(filter))
+            .await
+            .unwrap();
+        assert_eq!(deleted.deleted, 2);
+
+        let remaining = state.lock().unwrap().storage.list().unwrap();
+        let subjects: Vec<&str> = remaining.iter().map(|m| m.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["keep"]);
+
+        let deletes = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| event.contains("\"delete\""))
+            .count();
+        assert_eq!(deletes, 2);
+
+        let held = state.lock().unwrap().capture(message("held")).unwrap();
+        let Json(_) = quarantine(State(state.clone()), Path(held.id.unwrap()))
+            .await
+            .unwrap();
+        while events.try_recv().is_ok() {}
+
+        // Without filter parameters the inbox is cleared, quarantined
+        // messages included.
+        let query = RawQuery(None);
+        let Json(deleted) = delete_all(State(state.clone()), query, Query(Filter::default()))
+            .await
+            .unwrap();
+        assert_eq!(deleted.deleted, 2);
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+
+        let events: Vec<String> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("\"type\":\"clear\""));
+    }
 }
@@ -0,0 +1,479 @@
+use super::messages::with_bodies;
+use crate::models::Message;
+use crate::{AppState, Result};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use html2text::render::text_renderer::RichAnnotation;
+use printpdf::image_crate::DynamicImage;
+use printpdf::{
+    Actions, BorderArray, Color, Image, ImageTransform, IndirectFontRef, LinkAnnotation, Mm,
+    PdfDocument, PdfLayerReference, Rect, Rgb,
+};
+use std::io;
+
+/// DejaVu Sans Mono, see `fonts/LICENSE`. Only the glyphs a document uses
+/// are embedded.
+static FONT: &[u8] = include_bytes!("../../fonts/DejaVuSansMono.ttf");
+static BOLD_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSansMono-Bold.ttf");
+
+/// A4, in millimetres like the rest of the layout.
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 18.0;
+/// In points.
+const FONT_SIZE: f32 = 9.0;
+const LEADING: f32 = 3.9;
+/// Advance of every glyph of the monospace font, 0.602em.
+const CHAR_WIDTH: f32 = FONT_SIZE * 0.602 * 25.4 / 72.0;
+/// Characters per line between the margins.
+const COLUMNS: usize = 90;
+/// Images are drawn at the size a browser would show them.
+const IMAGE_DPI: f32 = 96.0;
+
+/// The message as a PDF: a short header followed by the html body laid out
+/// as text, or the plain body when there is no html.
+///
+/// The html goes through html2text, so scripts and styles are dropped and
+/// headings, emphasis, lists, tables and `<pre>` blocks keep their
+/// structure. Links stay clickable when they are `http`, `https` or
+/// `mailto`, and inline `cid:` images are drawn from their attachments.
+pub async fn show(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse> {
+    let message = with_bodies(&state, id)?;
+    let pdf = render(&message.subject, blocks(&message))?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", "application/pdf".to_owned()),
+            (
+                "Content-Disposition",
+                format!("inline; filename=\"message-{}.pdf\"", id),
+            ),
+        ],
+        pdf,
+    ))
+}
+
+/// A run of text on one line.
+#[derive(Debug, PartialEq)]
+struct Span {
+    text: String,
+    bold: bool,
+    link: Option<String>,
+}
+
+impl Span {
+    fn regular(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            bold: false,
+            link: None,
+        }
+    }
+
+    fn bold(text: impl Into<String>) -> Self {
+        Self {
+            bold: true,
+            ..Self::regular(text)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Block {
+    Line(Vec<Span>),
+    Image(DynamicImage),
+}
+
+fn blocks(message: &Message) -> Vec<Block> {
+    let mut headers = vec![
+        ("From", message.sender.clone()),
+        ("To", message.recipients.join(", ")),
+        ("Subject", message.subject.clone()),
+    ];
+    if let Some(created_at) = &message.created_at {
+        headers.push(("Date", created_at.to_string()));
+    }
+
+    let mut blocks = vec![];
+
+    for (name, value) in headers {
+        for (index, line) in wrap(&format!("{}: {}", name, value))
+            .into_iter()
+            .enumerate()
+        {
+            blocks.push(Block::Line(if index == 0 {
+                let (name, value) = line.split_at(name.len() + 1);
+                vec![Span::bold(name), Span::regular(value)]
+            } else {
+                vec![Span::regular(line)]
+            }));
+        }
+    }
+    blocks.push(Block::Line(vec![]));
+
+    match (&message.html, &message.plain) {
+        (Some(html), _) => blocks.extend(html_blocks(html, message)),
+        (None, Some(plain)) => {
+            for line in plain.lines() {
+                blocks.extend(
+                    wrap(line.trim_end())
+                        .into_iter()
+                        .map(|line| Block::Line(vec![Span::regular(line)])),
+                );
+            }
+        }
+        (None, None) => {}
+    }
+
+    blocks
+}
+
+fn html_blocks(html: &str, message: &Message) -> Vec<Block> {
+    let mut blocks = vec![];
+
+    for line in html2text::from_read_rich(html.as_bytes(), COLUMNS) {
+        let mut strings = line.into_tagged_strings().peekable();
+
+        // Headings come out as a `#` prefix of their own.
+        let heading = strings.next_if(|string| {
+            string.tag.is_empty()
+                && string.s.ends_with("# ")
+                && string.s.trim_end().chars().all(|c| c == '#')
+        });
+
+        let mut spans = vec![];
+        let mut images = vec![];
+
+        for string in strings {
+            let mut span = Span::regular(string.s);
+            span.bold = heading.is_some();
+
+            for annotation in string.tag {
+                match annotation {
+                    RichAnnotation::Strong => span.bold = true,
+                    RichAnnotation::Link(url) => span.link = Some(url),
+                    RichAnnotation::Image(src) => images.extend(inline_image(&src, message)),
+                    _ => {}
+                }
+            }
+            spans.push(span);
+        }
+
+        blocks.push(Block::Line(spans));
+        blocks.extend(images.into_iter().map(Block::Image));
+    }
+
+    blocks
+}
+
+/// The attachment a `cid:` image points at, when it decodes as an image.
+fn inline_image(src: &str, message: &Message) -> Option<DynamicImage> {
+    let content_id = src.strip_prefix("cid:")?;
+    let attachment = message
+        .attachments
+        .iter()
+        .find(|attachment| attachment.content_id.as_deref() == Some(content_id))?;
+
+    printpdf::image_crate::load_from_memory(&attachment.body).ok()
+}
+
+/// Breaks `line` at spaces into lines of at most `COLUMNS` characters,
+/// splitting words that are longer than that.
+fn wrap(line: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current = String::new();
+
+    for word in line.split(' ') {
+        let mut word: Vec<char> = word.chars().collect();
+
+        if !current.is_empty() && current.chars().count() + 1 + word.len() > COLUMNS {
+            lines.push(std::mem::take(&mut current));
+        }
+
+        while word.len() > COLUMNS {
+            lines.push(word.drain(..COLUMNS).collect());
+        }
+
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.extend(word);
+    }
+    lines.push(current);
+
+    lines
+}
+
+/// Lays `blocks` out top to bottom on as many pages as needed.
+fn render(title: &str, blocks: Vec<Block>) -> Result<Vec<u8>> {
+    let (document, page, layer) =
+        PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "content");
+    let regular = document.add_external_font(FONT).map_err(pdf_error)?;
+    let bold = document.add_external_font(BOLD_FONT).map_err(pdf_error)?;
+
+    let mut layer = document.get_page(page).get_layer(layer);
+    let mut y = PAGE_HEIGHT - MARGIN;
+
+    for block in blocks {
+        let height = match &block {
+            Block::Line(_) => LEADING,
+            Block::Image(image) => image_size(image).1,
+        };
+        if y - height < MARGIN {
+            let (page, next) = document.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "content");
+            layer = document.get_page(page).get_layer(next);
+            y = PAGE_HEIGHT - MARGIN;
+        }
+
+        match block {
+            Block::Line(spans) => {
+                y -= LEADING;
+                draw_line(&layer, &spans, y, &regular, &bold);
+            }
+            Block::Image(image) => {
+                let (width, height) = image_size(&image);
+                y -= height;
+                let scale = width / pixels_to_mm(image.width());
+
+                Image::from_dynamic_image(&image).add_to_layer(
+                    layer.clone(),
+                    ImageTransform {
+                        translate_x: Some(Mm(MARGIN)),
+                        translate_y: Some(Mm(y)),
+                        scale_x: Some(scale),
+                        scale_y: Some(scale),
+                        dpi: Some(IMAGE_DPI),
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    }
+
+    document.save_to_bytes().map_err(pdf_error)
+}
+
+fn draw_line(
+    layer: &PdfLayerReference,
+    spans: &[Span],
+    y: f32,
+    regular: &IndirectFontRef,
+    bold: &IndirectFontRef,
+) {
+    let mut x = MARGIN;
+
+    for span in spans {
+        let width = span.text.chars().count() as f32 * CHAR_WIDTH;
+        let font = if span.bold { bold } else { regular };
+
+        match &span.link {
+            Some(url) => {
+                layer.set_fill_color(Color::Rgb(Rgb::new(0.0, 0.2, 0.8, None)));
+                layer.use_text(&span.text, FONT_SIZE, Mm(x), Mm(y), font);
+                layer.set_fill_color(Color::Rgb(Rgb::new(0.0, 0.0, 0.0, None)));
+
+                if ["http:", "https:", "mailto:"]
+                    .iter()
+                    .any(|scheme| url.starts_with(scheme))
+                {
+                    layer.add_link_annotation(LinkAnnotation::new(
+                        Rect::new(Mm(x), Mm(y - 1.0), Mm(x + width), Mm(y + LEADING - 1.0)),
+                        Some(BorderArray::Solid([0.0, 0.0, 0.0])),
+                        None,
+                        Actions::uri(url.clone()),
+                        None,
+                    ));
+                }
+            }
+            None => layer.use_text(&span.text, FONT_SIZE, Mm(x), Mm(y), font),
+        }
+
+        x += width;
+    }
+}
+
+/// Width and height of `image` in millimetres, shrunk to fit a page.
+fn image_size(image: &DynamicImage) -> (f32, f32) {
+    let width = pixels_to_mm(image.width());
+    let height = pixels_to_mm(image.height());
+    let scale = ((PAGE_WIDTH - 2.0 * MARGIN) / width)
+        .min((PAGE_HEIGHT - 2.0 * MARGIN) / height)
+        .min(1.0);
+
+    (width * scale, height * scale)
+}
+
+fn pixels_to_mm(pixels: u32) -> f32 {
+    pixels as f32 / IMAGE_DPI * 25.4
+}
+
+fn pdf_error(err: printpdf::Error) -> crate::Error {
+    io::Error::other(err.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Attachment;
+    use crate::test_util::{body_bytes, MessageBuilder};
+    use crate::Mailtutan;
+    use printpdf::lopdf;
+
+    /// The text of the `ToUnicode` maps of every font, which map the
+    /// embedded glyphs back to the characters they draw.
+    fn to_unicode(document: &lopdf::Document) -> String {
+        let mut maps = String::new();
+
+        for object in document.objects.values() {
+            if let Ok(stream) = object.as_stream() {
+                let content = stream
+                    .decompressed_content()
+                    .unwrap_or_else(|_| stream.content.clone());
+                let content = String::from_utf8_lossy(&content);
+                if content.contains("begincmap") {
+                    maps.push_str(&content);
+                }
+            }
+        }
+
+        maps
+    }
+
+    /// Dictionaries of the objects, streams included, whose `key` is the
+    /// name `value`.
+    fn objects_with<'a>(
+        document: &'a lopdf::Document,
+        key: &'a [u8],
+        value: &'a [u8],
+    ) -> impl Iterator<Item = &'a lopdf::Dictionary> {
+        document.objects.values().filter_map(move |object| {
+            let dictionary = match object {
+                lopdf::Object::Dictionary(dictionary) => dictionary,
+                lopdf::Object::Stream(stream) => &stream.dict,
+                _ => return None,
+            };
+            (dictionary.get(key).and_then(|name| name.as_name()).ok() == Some(value))
+                .then_some(dictionary)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_html_message_as_pdf() {
+        let state = Mailtutan::default().init();
+        let data = MessageBuilder::new()
+            .subject("Отчёт (draft)")
+            .html(
+                "<h1>Квартальный отчёт</h1><p>Revenue is <strong>up</strong>.</p>\
+                 <p><a href=\"https://example.test/report\">Café ☕</a></p>",
+            )
+            .build();
+        let message = Message::try_from(&data).unwrap();
+        let id = state.lock().unwrap().capture(message).unwrap().id.unwrap();
+
+        let response = show(State(state), Path(id)).await.unwrap().into_response();
+        assert_eq!(response.headers()["Content-Type"], "application/pdf");
+
+        let pdf = body_bytes(response).await;
+        assert!(pdf.starts_with(b"%PDF-"));
+
+        let document = lopdf::Document::load_mem(&pdf).unwrap();
+        assert_eq!(document.get_pages().len(), 1);
+
+        // Cyrillic and the other non-Latin-1 characters have glyphs of
+        // their own rather than a replacement.
+        let maps = to_unicode(&document).to_ascii_uppercase();
+        for c in ['К', 'ё', 'é', '☕'] {
+            assert!(
+                maps.contains(&format!("<{:04X}>", c as u32)),
+                "no glyph for {}",
+                c
+            );
+        }
+
+        let uris: Vec<Vec<u8>> = objects_with(&document, b"Subtype", b"Link")
+            .filter_map(|annotation| annotation.get(b"A").ok()?.as_dict().ok())
+            .filter_map(|action| Some(action.get(b"URI").ok()?.as_str().ok()?.to_vec()))
+            .collect();
+        assert_eq!(uris, vec![b"https://example.test/report".to_vec()]);
+    }
+
+    #[test]
+    fn test_html_blocks() {
+        let message = Message::default();
+        let lines: Vec<Vec<Span>> = html_blocks(
+            "<h2>Title</h2><p>See <a href=\"https://example.test\">here</a></p>\
+             <script>alert(1)</script>",
+            &message,
+        )
+        .into_iter()
+        .filter_map(|block| match block {
+            Block::Line(spans) if !spans.is_empty() => Some(spans),
+            _ => None,
+        })
+        .collect();
+
+        assert_eq!(lines[0], vec![Span::bold("Title")]);
+        assert_eq!(lines[1][0], Span::regular("See "));
+        assert_eq!(lines[1][1].link.as_deref(), Some("https://example.test"));
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_inline_image() {
+        let mut png = io::Cursor::new(vec![]);
+        DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, printpdf::image_crate::ImageOutputFormat::Png)
+            .unwrap();
+
+        let message = Message {
+            html: Some("<p><img src=\"cid:logo\" alt=\"Logo\"></p>".to_owned()),
+            attachments: vec![Attachment {
+                content_id: Some("logo".to_owned()),
+                body: png.into_inner(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let blocks = blocks(&message);
+        assert!(blocks.iter().any(|block| matches!(block, Block::Image(_))));
+
+        let pdf = render(&message.subject, blocks).unwrap();
+        let document = lopdf::Document::load_mem(&pdf).unwrap();
+        assert_eq!(objects_with(&document, b"Subtype", b"Image").count(), 1);
+    }
+
+    #[test]
+    fn test_plain_message_as_pdf() {
+        let data = MessageBuilder::new().plain("Привет\nworld").build();
+        let message = Message::try_from(&data).unwrap();
+
+        let lines: Vec<String> = blocks(&message)
+            .into_iter()
+            .filter_map(|block| match block {
+                Block::Line(spans) => Some(spans.into_iter().map(|span| span.text).collect()),
+                Block::Image(_) => None,
+            })
+            .collect();
+        assert!(lines.ends_with(&["Привет".to_owned(), "world".to_owned()]));
+
+        let pdf = render(&message.subject, blocks(&message)).unwrap();
+        assert!(lopdf::Document::load_mem(&pdf).is_ok());
+    }
+
+    #[test]
+    fn test_wrap() {
+        let long = "word ".repeat(30);
+        let lines = wrap(long.trim_end());
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() <= COLUMNS));
+
+        assert_eq!(wrap(&"x".repeat(200)).len(), 3);
+        assert_eq!(wrap(""), vec![""]);
+    }
+}
@@ -0,0 +1,53 @@
+use axum::{
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Answers 403 to every request that could change state, leaving the GET
+/// endpoints and websocket working. SMTP capture isn't affected.
+pub async fn reject_mutations<B>(request: Request<B>, next: Next<B>) -> Response {
+    match *request.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => next.run(request).await,
+        _ => (StatusCode::FORBIDDEN, "Mailtutan is in read-only mode").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::Message;
+    use crate::test_util::{request, spawn_router, MessageBuilder};
+    use crate::Mailtutan;
+
+    #[tokio::test]
+    async fn test_read_only_rejects_mutations() {
+        let state = Mailtutan {
+            read_only: true,
+            ..Default::default()
+        }
+        .init();
+        let data = MessageBuilder::new()
+            .subject("keep me")
+            .plain("body")
+            .build();
+        let id = state
+            .lock()
+            .unwrap()
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap()
+            .id
+            .unwrap();
+
+        let addr = spawn_router(state.clone());
+
+        let response = request(addr, "DELETE", &format!("/api/messages/{}", id)).await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let response = request(addr, "DELETE", "/api/messages").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+        let response = request(addr, "GET", "/api/messages").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("keep me"));
+        assert_eq!(state.lock().unwrap().storage.size(), 1);
+    }
+}
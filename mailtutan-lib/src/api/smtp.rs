@@ -0,0 +1,28 @@
+use crate::AppState;
+use axum::extract::State;
+use axum::Json;
+
+pub async fn capabilities(State(state): State<AppState>) -> Json<Vec<String>> {
+    Json(crate::smtp::capabilities(&state.lock().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mailtutan;
+
+    #[tokio::test]
+    async fn test_capabilities_include_auth() {
+        let state = Mailtutan::default().init();
+        let Json(list) = capabilities(State(state)).await;
+        assert!(!list.iter().any(|capability| capability.starts_with("AUTH")));
+
+        let state = Mailtutan {
+            smtp_auth: true,
+            ..Default::default()
+        }
+        .init();
+        let Json(list) = capabilities(State(state)).await;
+        assert!(list.contains(&"AUTH PLAIN LOGIN CRAM-MD5".to_owned()));
+    }
+}
@@ -0,0 +1,89 @@
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::time::{Duration, Instant};
+
+const SERVER_TIMING: &str = "server-timing";
+
+/// Durations of the phases of a request, left in the response extensions
+/// by handlers for [`server_timing`] to report.
+#[derive(Clone, Debug, Default)]
+pub struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    /// Records the time since `started` as phase `name`.
+    pub fn record(&mut self, name: &'static str, started: Instant) {
+        self.0.push((name, started.elapsed()));
+    }
+}
+
+/// Adds a `Server-Timing` header with the phases the handler recorded and
+/// the `total` time spent in the app, when `server_timing` is set.
+pub async fn server_timing<B>(request: Request<B>, next: Next<B>) -> Response {
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+
+    let mut timings = response
+        .extensions_mut()
+        .remove::<Timings>()
+        .unwrap_or_default();
+    timings.record("total", started);
+
+    let value = timings
+        .0
+        .iter()
+        .map(|(name, duration)| format!("{};dur={:.3}", name, duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(SERVER_TIMING), value);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::Message;
+    use crate::test_util::{request, spawn_router, MessageBuilder};
+    use crate::Mailtutan;
+
+    #[tokio::test]
+    async fn test_server_timing_header() {
+        let state = Mailtutan {
+            server_timing: true,
+            ..Default::default()
+        }
+        .init();
+        let data = MessageBuilder::new().subject("timed").plain("body").build();
+        state
+            .lock()
+            .unwrap()
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap();
+
+        let addr = spawn_router(state);
+
+        let response = request(addr, "GET", "/api/messages").await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let header = response
+            .lines()
+            .find_map(|line| line.strip_prefix("server-timing: "))
+            .expect("Server-Timing header");
+        let names: Vec<&str> = header
+            .split(", ")
+            .map(|entry| {
+                let (name, duration) = entry.split_once(";dur=").unwrap();
+                duration.parse::<f64>().unwrap();
+                name
+            })
+            .collect();
+        assert_eq!(names, vec!["storage", "serialize", "total"]);
+    }
+}
@@ -1,27 +1,242 @@
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
 
-use futures::{sink::SinkExt, stream::StreamExt};
+use futures::{future, pin_mut, sink::SinkExt, stream::StreamExt};
+use serde::Deserialize;
+use serde_json::Value;
 
-use crate::APP;
+use crate::AppState;
 
-pub async fn websocket_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(websocket)
+/// `?mailbox=` subscribes to the events of a single mailbox.
+#[derive(Deserialize)]
+pub struct Topic {
+    mailbox: Option<String>,
 }
 
-async fn websocket(stream: WebSocket) {
-    let (mut sender, _) = stream.split();
+pub async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(topic): Query<Topic>,
+) -> Response {
+    let Some(subscription) = Subscription::acquire(&state) else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many websocket subscribers",
+        )
+            .into_response();
+    };
 
-    let mut rx = APP.get().unwrap().lock().unwrap().ws_sender.subscribe();
+    ws.on_upgrade(|stream| websocket(stream, state, topic, subscription))
+}
+
+/// Counts towards `ws_subscribers` for as long as it lives, so a failed
+/// upgrade or a closed socket frees the slot again.
+struct Subscription {
+    state: AppState,
+}
+
+impl Subscription {
+    fn acquire(state: &AppState) -> Option<Self> {
+        let mut app = state.lock().unwrap();
+
+        if app
+            .max_ws_subscribers
+            .is_some_and(|max| app.ws_subscribers >= max)
+        {
+            return None;
+        }
+
+        app.ws_subscribers += 1;
+
+        Some(Self {
+            state: state.clone(),
+        })
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().ws_subscribers -= 1;
+    }
+}
+
+async fn websocket(stream: WebSocket, state: AppState, topic: Topic, _subscription: Subscription) {
+    let (mut sender, mut receiver) = stream.split();
+
+    let (mut rx, camel_case_json) = {
+        let app = state.lock().unwrap();
+        (app.ws_sender.subscribe(), app.camel_case_json)
+    };
+
+    let forward = async move {
+        while let Ok(mut msg) = rx.recv().await {
+            if let Some(mailbox) = &topic.mailbox {
+                let event: Value = serde_json::from_str(&msg).unwrap_or_default();
+
+                if event["type"] != "clear" && event["message"]["mailbox"] != mailbox.as_str() {
+                    continue;
+                }
+            }
+
+            if camel_case_json {
+                if let Ok(value) = serde_json::from_str(&msg) {
+                    msg = super::case::camel_case(value).to_string();
+                }
+            }
 
-    tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
             // In any websocket error, break loop.
             if sender.send(Message::Text(msg)).await.is_err() {
                 break;
             }
         }
-    });
+    };
+
+    // Clients never send anything meaningful, but reading notices them
+    // going away without waiting for the next event.
+    let closed = async move {
+        while let Some(Ok(msg)) = receiver.next().await {
+            if let Message::Close(_) = msg {
+                break;
+            }
+        }
+    };
+
+    pin_mut!(forward, closed);
+    future::select(forward, closed).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::Message;
+    use crate::test_util::{spawn_router, MessageBuilder};
+    use crate::Mailtutan;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn upgrade(addr: std::net::SocketAddr) -> (TcpStream, String) {
+        upgrade_with(addr, "").await
+    }
+
+    /// Requests the upgrade with `headers`, CRLF-terminated lines, added.
+    async fn upgrade_with(addr: std::net::SocketAddr, headers: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    concat!(
+                        "GET /ws HTTP/1.1\r\n",
+                        "Host: localhost\r\n",
+                        "Connection: Upgrade\r\n",
+                        "Upgrade: websocket\r\n",
+                        "Sec-WebSocket-Version: 13\r\n",
+                        "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+                        "{}",
+                        "\r\n"
+                    ),
+                    headers
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            response.push(byte[0]);
+        }
+
+        let status = String::from_utf8(response).unwrap();
+        let status = status.lines().next().unwrap().to_owned();
+
+        (stream, status)
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_requires_http_auth() {
+        let state = Mailtutan {
+            http_auth: true,
+            ..Default::default()
+        }
+        .init();
+
+        let addr = spawn_router(state.clone());
+
+        let (_, status) = upgrade(addr).await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        // admin:wrong
+        let (_, status) = upgrade_with(addr, "Authorization: Basic YWRtaW46d3Jvbmc=\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        // admin:admin
+        let (mut stream, status) =
+            upgrade_with(addr, "Authorization: Basic YWRtaW46YWRtaW4=\r\n").await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+
+        for _ in 0..100 {
+            if state.lock().unwrap().ws_sender.receiver_count() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let data = MessageBuilder::new().subject("streamed").build();
+        state
+            .lock()
+            .unwrap()
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap();
+
+        // An unmasked text frame from the server.
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 0x81);
+        let length = match header[1] {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(event["type"], "add");
+        assert_eq!(event["message"]["subject"], "streamed");
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_cap() {
+        let state = Mailtutan {
+            max_ws_subscribers: Some(2),
+            ..Default::default()
+        }
+        .init();
+
+        let addr = spawn_router(state.clone());
+
+        let (first, status) = upgrade(addr).await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        let (_second, status) = upgrade(addr).await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+
+        let (_, status) = upgrade(addr).await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+
+        drop(first);
+        for _ in 0..100 {
+            if state.lock().unwrap().ws_subscribers < 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.lock().unwrap().ws_subscribers, 1);
+
+        let (_third, status) = upgrade(addr).await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+    }
 }
@@ -1,5 +1,5 @@
-use crate::APP;
-use axum::{headers::HeaderMapExt, response::IntoResponse};
+use crate::AppState;
+use axum::{extract::State, headers::HeaderMapExt, response::IntoResponse};
 
 use axum::{
     headers::authorization::{Authorization, Basic},
@@ -8,22 +8,24 @@ use axum::{
     middleware::Next,
     response::Response,
 };
-pub async fn basic<B>(request: Request<B>, next: Next<B>) -> Response {
+pub async fn basic<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
     if let Some(credential) = request.headers().typed_get::<Authorization<Basic>>() {
-        if let Some(app) = APP.get() {
-            let valid = {
-                if let Ok(app) = app.lock() {
-                    app.http_username == credential.0.username()
-                        && app.http_password == credential.0.password()
-                } else {
-                    false
-                }
-            };
-
-            if valid {
-                let res = next.run(request).await;
-                return res;
+        let valid = {
+            if let Ok(app) = state.lock() {
+                app.http_username == credential.0.username()
+                    && app.http_password == credential.0.password()
+            } else {
+                false
             }
+        };
+
+        if valid {
+            let res = next.run(request).await;
+            return res;
         }
     }
 
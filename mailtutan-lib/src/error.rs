@@ -0,0 +1,50 @@
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The raw bytes could not be parsed as an email message.
+    Parse(String),
+    /// The storage backend could not fulfil the request, e.g. an unknown id.
+    Storage(String),
+    Io(io::Error),
+    /// The configuration is invalid, e.g. a bad listen address.
+    Config(String),
+    /// The requested data was intentionally not kept, e.g. bodies in
+    /// headers-only mode.
+    Gone(String),
+    /// The message is over a configured size limit.
+    TooLarge(String),
+    /// The attachments are over the configured count or total size limits.
+    TooManyAttachments(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(reason) => write!(f, "parse error: {}", reason),
+            Error::Storage(reason) => write!(f, "storage error: {}", reason),
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Config(reason) => write!(f, "config error: {}", reason),
+            Error::Gone(reason) => write!(f, "gone: {}", reason),
+            Error::TooLarge(reason) => write!(f, "too large: {}", reason),
+            Error::TooManyAttachments(reason) => write!(f, "too many attachments: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,106 @@
+use crate::models::Message;
+use crate::{AppState, Mailtutan, Result};
+use chrono::{Local, NaiveDateTime};
+use std::time::Duration;
+
+/// Periodically purges messages older than `message_ttl`. The TTL is looked
+/// up again on every round, so a configuration reload can change it.
+pub async fn serve(state: AppState) -> Result<()> {
+    loop {
+        let period = state
+            .lock()
+            .unwrap()
+            .message_ttl
+            .map_or(Duration::from_secs(60), |ttl| {
+                (ttl / 10).clamp(Duration::from_secs(1), Duration::from_secs(60))
+            });
+        tokio::time::sleep(period).await;
+
+        let mut app = state.lock().unwrap();
+
+        if let Some(ttl) = app.message_ttl {
+            if let Err(err) = purge(&mut app, ttl) {
+                eprintln!("message expiry failed: {}", err);
+            }
+        }
+    }
+}
+
+/// Removes every message whose `created_at` is older than `ttl` and emits a
+/// `delete` event for it. Messages with a missing or unparseable timestamp
+/// are left alone.
+pub fn purge(app: &mut Mailtutan, ttl: Duration) -> Result<Vec<usize>> {
+    let now = Local::now().naive_local();
+    let expired: Vec<Message> = app
+        .storage
+        .list()?
+        .into_iter()
+        .filter(|message| {
+            created_at(message)
+                .and_then(|created_at| (now - created_at).to_std().ok())
+                .is_some_and(|age| age > ttl)
+        })
+        .collect();
+
+    let mut ids = vec![];
+
+    for message in expired {
+        if let Some(id) = message.id {
+            app.storage.remove(id)?;
+            app.notify("delete", &message);
+            ids.push(id);
+        }
+    }
+
+    Ok(ids)
+}
+
+fn created_at(message: &Message) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(message.created_at.as_deref()?, "%Y-%m-%d %H:%M:%S").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(subject: &str, created_at: Option<&str>) -> Message {
+        let data = format!("Subject: {}\r\n\r\nbody\r\n", subject).into_bytes();
+        let mut message = Message::try_from(&data).unwrap();
+
+        if let Some(created_at) = created_at {
+            message.created_at = Some(created_at.to_owned());
+        }
+
+        message
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let mut app = Mailtutan::default();
+        let mut events = app.ws_sender.subscribe();
+
+        let old = app
+            .capture(message("old", Some("2000-01-01 00:00:00")))
+            .unwrap();
+        app.capture(message("fresh", None)).unwrap();
+        app.capture(message("garbled", Some("yesterday"))).unwrap();
+        while events.try_recv().is_ok() {}
+
+        let purged = purge(&mut app, Duration::from_secs(60)).unwrap();
+        assert_eq!(purged, vec![old.id.unwrap()]);
+
+        let mut subjects: Vec<String> = app
+            .storage
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|message| message.subject)
+            .collect();
+        subjects.sort();
+        assert_eq!(subjects, vec!["fresh", "garbled"]);
+
+        let event = events.try_recv().unwrap();
+        assert!(event.contains("\"type\":\"delete\""));
+        assert!(event.contains("\"subject\":\"old\""));
+    }
+}
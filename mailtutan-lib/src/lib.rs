@@ -1,38 +1,566 @@
 pub mod api;
 pub mod auth;
+mod error;
+pub mod expiry;
 pub mod models;
 pub mod smtp;
 pub mod storage;
-use once_cell::sync::OnceCell;
-use storage::Storage;
-use tokio::sync::broadcast::Sender;
+pub mod syslog;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_util;
+use chrono::{NaiveDate, Utc};
+pub use error::{Error, Result};
+use futures::stream::{self, Stream};
+use models::{MailboxRule, Message, MessageEvent, ParseFailure, ParseOptions};
+use smtp::{Greylist, ParsePool, RateLimiter, RcptDelay};
+use storage::{Memory, Storage};
+use syslog::Syslog;
+use tokio::sync::broadcast::{self, error::RecvError, Sender};
+use tokio_rustls::TlsAcceptor;
 
-static APP: OnceCell<Mutex<Mailtutan>> = OnceCell::new();
+use std::{
+    collections::VecDeque,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use std::{net::Ipv4Addr, sync::Mutex};
+pub type AppState = Arc<Mutex<Mailtutan>>;
+
+/// Produces the current configuration when a reload is requested.
+pub type Reloader = Box<dyn Fn() -> Result<Settings> + Send + Sync>;
+
+/// The part of the configuration that can change without a restart.
+/// Connections already open keep the limits they started with.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub messages_limit: usize,
+    pub max_list_size: Option<usize>,
+    pub max_line_length: usize,
+    pub max_header_size: usize,
+    pub sender_allowlist: Vec<String>,
+    pub sender_denylist: Vec<String>,
+    pub spam_keywords: Vec<String>,
+    pub message_ttl: Option<Duration>,
+}
 
 pub struct Mailtutan {
+    /// Address shared by both listeners unless overridden.
     pub ip: Ipv4Addr,
+    pub http_ip: Option<Ipv4Addr>,
+    pub smtp_ip: Option<Ipv4Addr>,
     pub http_port: u16,
-    pub smtp_port: u16,
+    /// Every port gets its own SMTP listener.
+    pub smtp_ports: Vec<u16>,
     pub storage: Box<dyn Storage + 'static>,
     pub ws_sender: Sender<String>,
+    /// Typed counterpart of `ws_sender`, see [`Mailtutan::subscribe`].
+    pub event_sender: Sender<MessageEvent>,
     pub http_auth: bool,
     pub http_username: String,
     pub http_password: String,
+    /// Refuse every mutating HTTP request with 403.
+    pub read_only: bool,
+    /// Report phase durations of list and message requests in a
+    /// `Server-Timing` header.
+    pub server_timing: bool,
+    pub smtp_auth: bool,
+    pub smtp_username: String,
+    pub smtp_password: String,
     pub messages_limit: usize,
+    /// Most messages the list endpoint returns unless paging with
+    /// `since_id`; the newest are kept and the response says it was
+    /// truncated.
+    pub max_list_size: Option<usize>,
+    /// Include `plain` and `html` in the list entries of messages smaller
+    /// than this many bytes.
+    pub inline_body_size: Option<usize>,
+    /// Longest SMTP line, including CRLF, accepted from a client.
+    pub max_line_length: usize,
+    /// Largest message header block accepted during DATA.
+    pub max_header_size: usize,
+    pub parse_options: ParseOptions,
+    /// Defers first delivery attempts with a 450 when set.
+    pub greylist: Option<Greylist>,
+    /// Defers messages over the accepted rate with a 451 when set.
+    pub rate_limit: Option<RateLimiter>,
+    /// MAIL FROM domains accepted; empty accepts every domain.
+    pub sender_allowlist: Vec<String>,
+    /// MAIL FROM domains rejected with 550.
+    pub sender_denylist: Vec<String>,
+    /// Set once the SMTP listener is bound.
+    pub smtp_ready: bool,
+    /// Messages older than this are purged by the expiry task.
+    pub message_ttl: Option<Duration>,
+    /// Threads parsing received messages; 0 parses inline in the session.
+    /// Workers reply to DATA before parsing, so parse options that reject
+    /// messages, see [`ParseOptions::rejects_after_parse`], don't go with
+    /// them.
+    pub parse_workers: usize,
+    /// Started by [`Mailtutan::init`] when `parse_workers` is non-zero.
+    pub parse_pool: Option<ParsePool>,
+    /// Text of the 250 reply to a stored message, where `{id}` stands for
+    /// the assigned id. Messages handed to the parse workers get the id
+    /// reserved for them.
+    pub data_response: Option<String>,
+    /// Parse time in milliseconds over which a captured message is logged
+    /// and gets a warning.
+    pub slow_parse_ms: Option<u64>,
+    /// Expect a PROXY protocol header on SMTP connections and take the
+    /// client address from it.
+    pub proxy_protocol: bool,
+    /// Reply code for unrecognized SMTP verbs, 500 or 502.
+    pub unknown_command_code: u16,
+    pub log_unknown_commands: bool,
+    /// Resolve the client address of each SMTP connection.
+    pub reverse_dns: bool,
+    /// Collector receiving a record for each captured message.
+    pub syslog: Option<Syslog>,
+    /// Drop bodies and attachment bytes of captured messages.
+    pub headers_only: bool,
+    /// Prefix of links generated for the browser, e.g. when behind a proxy.
+    /// Links are relative when unset.
+    pub public_url: Option<String>,
+    /// Neutralize likely tracking pixels in served html bodies.
+    pub block_tracking_pixels: bool,
+    /// Prepend a `Received` header for the SMTP hop to stored sources.
+    pub received_header: bool,
+    /// Store the DATA bytes exactly as they came off the wire, dot-stuffing
+    /// and the terminating `.` line included, instead of the unstuffed
+    /// message. Parsing still uses the unstuffed message.
+    pub raw_source: bool,
+    /// Chaos testing: drop the connection without a reply once this many
+    /// DATA bytes of a transaction were received.
+    pub chaos_drop_after: Option<usize>,
+    /// Captured messages whose subject or body contains one of these words,
+    /// case-insensitively, are accepted but flagged as spam.
+    pub spam_keywords: Vec<String>,
+    /// Header names every message has to carry; others get 554 and aren't
+    /// stored.
+    pub required_headers: Vec<String>,
+    /// Captured messages go to the mailbox of the first matching rule, or
+    /// to [`models::DEFAULT_MAILBOX`].
+    pub mailbox_rules: Vec<MailboxRule>,
+    /// Websocket upgrades beyond this many open subscribers get 503.
+    pub max_ws_subscribers: Option<usize>,
+    /// Currently open websocket subscribers.
+    pub ws_subscribers: usize,
+    /// `host:port` of a server every captured message is also relayed to.
+    /// Its reply to the transaction is passed back to the client.
+    pub relay: Option<String>,
+    /// Accept XFORWARD from proxies, recording the original client instead
+    /// of the proxy.
+    pub xforward: bool,
+    /// Emit camelCase instead of snake_case keys in API and websocket JSON.
+    pub camel_case_json: bool,
+    /// Certificate offered through STARTTLS, which is only advertised when
+    /// set.
+    pub tls: Option<TlsAcceptor>,
+    /// Downgrade simulation: advertise STARTTLS but answer the command with
+    /// 454, whether or not a certificate is configured.
+    pub starttls_failure: bool,
+    /// Number of unparseable deliveries kept for inspection; 0 keeps none.
+    pub max_parse_failures: usize,
+    /// The newest unparseable deliveries, oldest first.
+    pub parse_failures: VecDeque<ParseFailure>,
+    /// Unparseable deliveries seen since startup, including evicted ones.
+    pub parse_failure_count: usize,
+    /// Source of the settings applied by [`Mailtutan::reload`].
+    pub reloader: Option<Reloader>,
+    /// Hold back the reply to RCPT TO for matching recipients.
+    pub rcpt_delays: Vec<RcptDelay>,
+    /// Give captured messages a per-day `sequence` like `2024-06-01/007`,
+    /// restarting every UTC day.
+    pub daily_sequence: bool,
+    /// UTC day of the last assigned `sequence` and its number.
+    pub sequence_day: Option<(NaiveDate, usize)>,
 }
 
 impl Mailtutan {
     pub fn get_api_uri(&self) -> String {
-        format!("{}:{}", self.ip, self.http_port)
+        format!("{}:{}", self.http_ip.unwrap_or(self.ip), self.http_port)
+    }
+
+    pub fn get_smtp_uris(&self) -> Vec<String> {
+        self.smtp_ports
+            .iter()
+            .map(|port| format!("{}:{}", self.smtp_ip.unwrap_or(self.ip), port))
+            .collect()
+    }
+
+    pub fn init(self) -> AppState {
+        let workers = self.parse_workers;
+        let state = Arc::new(Mutex::new(self));
+
+        if workers > 0 {
+            let pool = ParsePool::start(Arc::downgrade(&state), workers);
+            state.lock().unwrap().parse_pool = Some(pool);
+        }
+
+        state
+    }
+
+    /// Re-reads the configuration through the [`Reloader`] and applies it.
+    pub fn reload(&mut self) -> Result<()> {
+        let reloader = self
+            .reloader
+            .as_ref()
+            .ok_or_else(|| Error::Config("configuration reload is not available".to_owned()))?;
+        let settings = reloader()?;
+
+        self.apply(settings)
+    }
+
+    /// Switches to `settings`. The storage is resized first, so nothing
+    /// changes when that fails.
+    pub fn apply(&mut self, settings: Settings) -> Result<()> {
+        self.storage.set_messages_limit(settings.messages_limit)?;
+        self.notify_evicted();
+
+        self.messages_limit = settings.messages_limit;
+        self.max_list_size = settings.max_list_size;
+        self.max_line_length = settings.max_line_length;
+        self.max_header_size = settings.max_header_size;
+        self.sender_allowlist = settings.sender_allowlist;
+        self.sender_denylist = settings.sender_denylist;
+        self.spam_keywords = settings.spam_keywords;
+        self.message_ttl = settings.message_ttl;
+
+        Ok(())
+    }
+
+    /// Stores a parsed message and notifies the websocket subscribers. A
+    /// message that already carries an id, from [`Storage::reserve_id`],
+    /// is stored under it.
+    pub fn capture(&mut self, message: Message) -> Result<Message> {
+        self.capture_on(message, Utc::now().date_naive())
+    }
+
+    fn capture_on(&mut self, mut message: Message, today: NaiveDate) -> Result<Message> {
+        if self.daily_sequence {
+            message.sequence = Some(self.next_sequence(today)?);
+        }
+
+        if self.slow_parse_ms.is_some_and(|max| message.parse_ms > max) {
+            eprintln!(
+                "slow message parse: {} ms for {} bytes",
+                message.parse_ms, message.size
+            );
+            message
+                .warnings
+                .push(format!("parsing took {} ms", message.parse_ms));
+        }
+
+        message.spam = self.is_spam(&message);
+        message.mailbox = MailboxRule::route(&self.mailbox_rules, &message);
+
+        if self.headers_only {
+            message.discard_bodies();
+        }
+
+        let message = match message.id {
+            Some(_) => self.storage.insert(message)?,
+            None => self.storage.add(message)?,
+        };
+
+        self.notify("add", &message);
+        self.notify_evicted();
+
+        Ok(message)
+    }
+
+    fn next_sequence(&mut self, today: NaiveDate) -> Result<String> {
+        let last = match self.sequence_day {
+            Some((day, last)) if day == today => last,
+            // First message of the day or since startup: continue after
+            // the ones already stored for today.
+            _ => {
+                let prefix = format!("{}/", today);
+                self.storage
+                    .list()?
+                    .iter()
+                    .filter_map(|message| message.sequence.as_deref()?.strip_prefix(&prefix))
+                    .filter_map(|number| number.parse().ok())
+                    .max()
+                    .unwrap_or(0)
+            }
+        };
+
+        self.sequence_day = Some((today, last + 1));
+
+        Ok(format!("{}/{:03}", today, last + 1))
+    }
+
+    fn is_spam(&self, message: &Message) -> bool {
+        let texts = [
+            Some(&message.subject),
+            message.plain.as_ref(),
+            message.html.as_ref(),
+        ];
+        let texts: Vec<String> = texts
+            .into_iter()
+            .flatten()
+            .map(|text| text.to_lowercase())
+            .collect();
+
+        self.spam_keywords.iter().any(|keyword| {
+            let keyword = keyword.to_lowercase();
+            texts.iter().any(|text| text.contains(&keyword))
+        })
     }
 
-    pub fn get_smtp_uri(&self) -> String {
-        format!("{}:{}", self.ip, self.smtp_port)
+    /// Keeps `failure` for inspection, evicting the oldest beyond
+    /// `max_parse_failures`.
+    pub fn record_parse_failure(&mut self, failure: ParseFailure) {
+        self.parse_failure_count += 1;
+
+        if self.max_parse_failures == 0 {
+            return;
+        }
+
+        self.parse_failures.push_back(failure);
+
+        while self.parse_failures.len() > self.max_parse_failures {
+            self.parse_failures.pop_front();
+        }
+    }
+
+    /// Broadcasts a [`MessageEvent`] to the websocket subscribers and the
+    /// streams returned by [`Mailtutan::subscribe`].
+    pub fn notify(&self, event_type: &str, message: &Message) {
+        let event = MessageEvent {
+            event_type: event_type.to_owned(),
+            message: message.clone(),
+        };
+
+        if let Ok(json) = serde_json::to_string(&event) {
+            self.ws_sender.send(json).ok();
+        }
+
+        self.event_sender.send(event).ok();
+    }
+
+    /// Sends an `evict` event for every message the storage dropped to stay
+    /// within `messages_limit`.
+    fn notify_evicted(&mut self) {
+        for message in self.storage.take_evicted() {
+            self.notify("evict", &message);
+        }
+    }
+
+    /// Events of messages added, deleted, evicted or cleared from now on,
+    /// for code embedding the library.
+    ///
+    /// Events are buffered per subscriber by a bounded broadcast channel and
+    /// never slow down capturing. A subscriber falling more than the channel
+    /// capacity behind misses the oldest events and continues with the
+    /// ones still buffered. The stream ends when the [`Mailtutan`] is
+    /// dropped.
+    pub fn subscribe(&self) -> impl Stream<Item = MessageEvent> + Send + 'static {
+        let receiver = self.event_sender.subscribe();
+
+        stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+impl Default for Mailtutan {
+    fn default() -> Self {
+        Self {
+            ip: Ipv4Addr::UNSPECIFIED,
+            http_ip: None,
+            smtp_ip: None,
+            http_port: 1080,
+            smtp_ports: vec![1025],
+            storage: Box::new(Memory::new(1000)),
+            ws_sender: broadcast::channel(100).0,
+            event_sender: broadcast::channel(100).0,
+            http_auth: false,
+            http_username: "admin".to_owned(),
+            http_password: "admin".to_owned(),
+            read_only: false,
+            server_timing: false,
+            smtp_auth: false,
+            smtp_username: "admin".to_owned(),
+            smtp_password: "admin".to_owned(),
+            messages_limit: 1000,
+            max_list_size: None,
+            inline_body_size: None,
+            max_line_length: 64 * 1024,
+            max_header_size: 1024 * 1024,
+            parse_options: ParseOptions::default(),
+            greylist: None,
+            rate_limit: None,
+            sender_allowlist: vec![],
+            sender_denylist: vec![],
+            smtp_ready: false,
+            message_ttl: None,
+            parse_workers: 0,
+            parse_pool: None,
+            data_response: None,
+            slow_parse_ms: None,
+            proxy_protocol: false,
+            unknown_command_code: 500,
+            log_unknown_commands: false,
+            reverse_dns: false,
+            syslog: None,
+            headers_only: false,
+            public_url: None,
+            block_tracking_pixels: false,
+            received_header: false,
+            raw_source: false,
+            chaos_drop_after: None,
+            spam_keywords: vec![],
+            required_headers: vec![],
+            mailbox_rules: vec![],
+            max_ws_subscribers: None,
+            ws_subscribers: 0,
+            relay: None,
+            xforward: false,
+            camel_case_json: false,
+            tls: None,
+            starttls_failure: false,
+            max_parse_failures: 0,
+            parse_failures: VecDeque::new(),
+            parse_failure_count: 0,
+            reloader: None,
+            rcpt_delays: vec![],
+            daily_sequence: false,
+            sequence_day: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MessageBuilder;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_daily_sequence_restarts_each_day() {
+        let mut app = Mailtutan {
+            daily_sequence: true,
+            ..Default::default()
+        };
+        let first_day = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+        let second_day = first_day.succ_opt().unwrap();
+
+        let mut sequences = vec![];
+        for day in [first_day, first_day, second_day, second_day] {
+            let data = MessageBuilder::new().subject("numbered").build();
+            let message = app
+                .capture_on(Message::try_from(&data).unwrap(), day)
+                .unwrap();
+            sequences.push(message.sequence.unwrap());
+        }
+        assert_eq!(
+            sequences,
+            vec![
+                "2024-06-01/001",
+                "2024-06-01/002",
+                "2024-06-02/001",
+                "2024-06-02/002"
+            ]
+        );
+
+        // As after a restart, numbering continues after the stored ones.
+        app.sequence_day = None;
+        let data = MessageBuilder::new().subject("numbered").build();
+        let message = app
+            .capture_on(Message::try_from(&data).unwrap(), second_day)
+            .unwrap();
+        assert_eq!(message.sequence.as_deref(), Some("2024-06-02/003"));
     }
 
-    pub fn init(self) {
-        APP.get_or_init(|| Mutex::new(self));
+    #[test]
+    fn test_slow_parse_is_warned_about() {
+        let mut app = Mailtutan {
+            slow_parse_ms: Some(100),
+            ..Default::default()
+        };
+        let data = MessageBuilder::new().subject("timed").build();
+
+        // The measured time depends on the machine, so it is set here.
+        let mut slow = Message::try_from(&data).unwrap();
+        slow.parse_ms = 250;
+        let message = app.capture(slow).unwrap();
+        assert_eq!(message.warnings, vec!["parsing took 250 ms"]);
+
+        let mut fast = Message::try_from(&data).unwrap();
+        fast.parse_ms = 100;
+        let message = app.capture(fast).unwrap();
+        assert!(message.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_receives_captured_messages() {
+        let state = Mailtutan::default().init();
+        let events = state.lock().unwrap().subscribe();
+        futures::pin_mut!(events);
+
+        let data = MessageBuilder::new().subject("embedded").build();
+        let message = Message::try_from(&data).unwrap();
+        let id = state.lock().unwrap().capture(message).unwrap().id;
+        state.lock().unwrap().storage.remove(id.unwrap()).unwrap();
+        state.lock().unwrap().notify(
+            "delete",
+            &Message {
+                id,
+                ..Default::default()
+            },
+        );
+
+        let event = events.next().await.unwrap();
+        assert_eq!(event.event_type, "add");
+        assert_eq!(event.message.subject, "embedded");
+        assert_eq!(event.message.id, id);
+
+        let event = events.next().await.unwrap();
+        assert_eq!(event.event_type, "delete");
+        assert_eq!(event.message.id, id);
+
+        drop(state);
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evicted_messages_are_announced() {
+        let state = Mailtutan {
+            storage: Box::new(Memory::new(2)),
+            ..Default::default()
+        }
+        .init();
+        let events = state.lock().unwrap().subscribe();
+        futures::pin_mut!(events);
+
+        for subject in ["one", "two", "three"] {
+            let data = MessageBuilder::new().subject(subject).build();
+            let message = Message::try_from(&data).unwrap();
+            state.lock().unwrap().capture(message).unwrap();
+        }
+
+        let mut received = vec![];
+        for _ in 0..4 {
+            let event = events.next().await.unwrap();
+            received.push((event.event_type, event.message.id.unwrap()));
+        }
+
+        assert_eq!(
+            received,
+            vec![
+                ("add".to_owned(), 1),
+                ("add".to_owned(), 2),
+                ("add".to_owned(), 3),
+                ("evict".to_owned(), 1)
+            ]
+        );
     }
 }
@@ -1,4 +1,25 @@
+mod calendar;
+mod failure;
+mod links;
+mod lint;
+mod mailbox;
 mod message;
+mod part;
+mod rfc5322;
+mod text;
 
+pub use calendar::Calendar;
+pub use failure::ParseFailure;
+pub use lint::{lint, Lint, Severity};
+pub use mailbox::{MailboxRule, Matcher, DEFAULT_MAILBOX};
+pub(crate) use message::content_id;
+pub use message::Attachment;
+pub use message::AttachmentOverflow;
+pub use message::AuthResult;
+pub use message::DeliveryStatus;
+pub use message::LineEnding;
 pub use message::Message;
 pub use message::MessageEvent;
+pub use message::ParseOptions;
+pub use part::MimePart;
+pub use text::{html_to_text, neutralize_tracking_pixels, PIXEL_PLACEHOLDER};
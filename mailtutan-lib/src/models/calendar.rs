@@ -0,0 +1,145 @@
+use ical::{property::Property, IcalParser};
+use serde::Serialize;
+
+/// The first event of a `text/calendar` (RFC 5545) part, e.g. an invite.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Calendar {
+    /// `REQUEST`, `CANCEL`, `REPLY`, ...
+    pub method: Option<String>,
+    pub summary: Option<String>,
+    /// DTSTART/DTEND as written, e.g. `20230915T140000Z`.
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub location: Option<String>,
+    /// Addresses without the `mailto:` prefix.
+    pub organizer: Option<String>,
+    pub attendees: Vec<String>,
+}
+
+impl Calendar {
+    /// `None` when the text isn't a complete VCALENDAR or holds no VEVENT.
+    /// Parameters, quoted ones such as `;TZID="(UTC-05:00) Eastern Time"`
+    /// included, are dropped, only the value is kept.
+    pub fn parse(text: &str) -> Option<Self> {
+        let parsed = IcalParser::new(text.as_bytes()).next()?.ok()?;
+        let event = parsed.events.first()?;
+
+        let mut calendar = Self {
+            method: parsed
+                .properties
+                .iter()
+                .find(|property| property.name.eq_ignore_ascii_case("METHOD"))
+                .and_then(value),
+            ..Default::default()
+        };
+
+        for property in &event.properties {
+            let Some(value) = value(property) else {
+                continue;
+            };
+
+            match property.name.to_ascii_uppercase().as_str() {
+                "SUMMARY" => calendar.summary = Some(value),
+                "DTSTART" => calendar.start = Some(value),
+                "DTEND" => calendar.end = Some(value),
+                "LOCATION" => calendar.location = Some(value),
+                "ORGANIZER" => calendar.organizer = Some(mailbox(&value)),
+                "ATTENDEE" => calendar.attendees.push(mailbox(&value)),
+                _ => {}
+            }
+        }
+
+        Some(calendar)
+    }
+}
+
+/// ical leaves TEXT escapes (RFC 5545 section 3.3.11) in the value.
+fn value(property: &Property) -> Option<String> {
+    property.value.as_deref().map(unescape)
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.trim().chars();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n' | 'N')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some(escaped @ ('\\' | ',' | ';'))) => {
+                out.push(escaped);
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn mailbox(value: &str) -> String {
+    match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => value[7..].to_owned(),
+        _ => value.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folded_lines() {
+        let text = concat!(
+            "BEGIN:VCALENDAR\r\n",
+            "BEGIN:VEVENT\r\n",
+            "SUMMARY:Quarterly planning for the whole\r\n",
+            "  team\\, with lunch\r\n",
+            "ATTENDEE;CN=Bob;RSVP=TRUE:mailto:bob@exa\r\n",
+            "\tmple.com\r\n",
+            "END:VEVENT\r\n",
+            "END:VCALENDAR\r\n"
+        );
+
+        let calendar = Calendar::parse(text).unwrap();
+        assert_eq!(
+            calendar.summary.as_deref(),
+            Some("Quarterly planning for the whole team, with lunch")
+        );
+        assert_eq!(calendar.attendees, vec!["bob@example.com".to_owned()]);
+    }
+
+    #[test]
+    fn test_quoted_parameters() {
+        let text = concat!(
+            "BEGIN:VCALENDAR\r\n",
+            "METHOD:REQUEST\r\n",
+            "BEGIN:VEVENT\r\n",
+            "DTSTART;TZID=\"(UTC-05:00) Eastern Time (US & Canada)\":20230915T140000\r\n",
+            "DTEND;TZID=\"(UTC-05:00) Eastern Time (US & Canada)\":20230915T150000\r\n",
+            "ORGANIZER;CN=\"Doe, Jane: Ops\":mailto:jane@example.com\r\n",
+            "END:VEVENT\r\n",
+            "END:VCALENDAR\r\n"
+        );
+
+        assert_eq!(
+            Calendar::parse(text),
+            Some(Calendar {
+                method: Some("REQUEST".to_owned()),
+                start: Some("20230915T140000".to_owned()),
+                end: Some("20230915T150000".to_owned()),
+                organizer: Some("jane@example.com".to_owned()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_without_event() {
+        let text = "BEGIN:VCALENDAR\r\nMETHOD:PUBLISH\r\nEND:VCALENDAR\r\n";
+        assert_eq!(Calendar::parse(text), None);
+        assert_eq!(Calendar::parse("not a calendar"), None);
+    }
+}
@@ -0,0 +1,32 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use serde::{Serialize, Serializer};
+
+/// A delivery whose data could not be parsed into a message.
+#[derive(Serialize, Debug, Clone)]
+pub struct ParseFailure {
+    pub created_at: String,
+    pub envelope_sender: String,
+    pub envelope_recipients: Vec<String>,
+    pub error: String,
+    /// The DATA bytes as received, base64 encoded in JSON.
+    #[serde(serialize_with = "base64")]
+    pub source: Vec<u8>,
+}
+
+impl ParseFailure {
+    pub fn new(source: &[u8], error: String) -> Self {
+        Self {
+            created_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            envelope_sender: String::new(),
+            envelope_recipients: vec![],
+            error,
+            source: source.to_owned(),
+        }
+    }
+}
+
+fn base64<S: Serializer>(source: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(source))
+}
@@ -0,0 +1,90 @@
+/// URLs of `href` and `src` attributes in `html` and `http(s)://` URLs in
+/// `plain`, deduplicated in order of first appearance.
+pub fn extract(html: Option<&str>, plain: Option<&str>) -> Vec<String> {
+    let mut links: Vec<String> = vec![];
+
+    let found = html.map(attribute_links).into_iter().flatten();
+    let found = found.chain(plain.map(text_links).into_iter().flatten());
+
+    for link in found {
+        if !link.is_empty() && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+
+    links
+}
+
+fn attribute_links(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = vec![];
+
+    for name in ["href", "src"] {
+        let mut start = 0;
+
+        while let Some(found) = lower[start..].find(name) {
+            let position = start + found;
+            start = position + name.len();
+
+            // Skip e.g. `data-src` or text that merely contains the word.
+            if !lower[..position].ends_with(|c: char| c.is_ascii_whitespace()) {
+                continue;
+            }
+
+            let rest = html[start..].trim_start();
+            let Some(rest) = rest.strip_prefix('=') else {
+                continue;
+            };
+            let rest = rest.trim_start();
+
+            let value = match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => rest[1..].split(quote).next(),
+                _ => rest
+                    .split(|c: char| c.is_ascii_whitespace() || c == '>')
+                    .next(),
+            };
+
+            if let Some(value) = value {
+                links.push(value.trim().replace("&amp;", "&"));
+            }
+        }
+    }
+
+    links
+}
+
+fn text_links(text: &str) -> Vec<String> {
+    text.split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\''))
+        .filter_map(|word| {
+            let start = word.find("http://").or_else(|| word.find("https://"))?;
+            let url = word[start..].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']']);
+
+            Some(url.to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract;
+
+    #[test]
+    fn test_extract_links() {
+        let html = concat!(
+            "<p>Hi, <a href=\"https://example.com/a?x=1&amp;y=2\">first</a> and ",
+            "<A HREF='https://example.org/b'>second</A>",
+            "<img data-src=\"skip\" src=https://t.example.net/p.gif></p>"
+        );
+        let plain = "Visit https://example.org/b or (http://plain.example/c).";
+
+        assert_eq!(
+            extract(Some(html), Some(plain)),
+            vec![
+                "https://example.com/a?x=1&y=2",
+                "https://example.org/b",
+                "https://t.example.net/p.gif",
+                "http://plain.example/c",
+            ]
+        );
+    }
+}
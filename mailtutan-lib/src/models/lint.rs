@@ -0,0 +1,154 @@
+use super::{Message, MimePart};
+use serde::Serialize;
+
+/// Images over this size are flagged, as many clients block or drop them.
+const MAX_IMAGE_SIZE: usize = 1024 * 1024;
+/// Gmail clips html bodies beyond this size.
+const MAX_HTML_SIZE: usize = 102 * 1024;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A deliverability rule the message breaks.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Lint {
+    fn new(id: &'static str, severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            id,
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+/// Checks `message` against basic deliverability rules, returning the ones
+/// it breaks. An empty list means no rule fired.
+pub fn lint(message: &Message) -> Vec<Lint> {
+    let mut lints = vec![];
+
+    if message.sender.is_empty() {
+        lints.push(Lint::new(
+            "missing-from",
+            Severity::Error,
+            "no From address",
+        ));
+    }
+
+    if message.recipients.is_empty() {
+        lints.push(Lint::new("missing-to", Severity::Warning, "no To address"));
+    }
+
+    if message.subject.trim().is_empty() {
+        lints.push(Lint::new(
+            "missing-subject",
+            Severity::Warning,
+            "no Subject",
+        ));
+    }
+
+    // `plain` is derived from the html when there is no text part, so the
+    // MIME tree has to be looked at.
+    if message.html.is_some() {
+        if let Ok(root) = MimePart::parse(&message.source) {
+            if !has_text_part(&root) {
+                lints.push(Lint::new(
+                    "no-plaintext-alternative",
+                    Severity::Warning,
+                    "html body without a text/plain alternative",
+                ));
+            }
+        }
+    }
+
+    if let Some(html) = &message.html {
+        if html.len() > MAX_HTML_SIZE {
+            lints.push(Lint::new(
+                "html-clipped",
+                Severity::Warning,
+                format!("html body of {} bytes gets clipped by Gmail", html.len()),
+            ));
+        }
+    }
+
+    for attachment in &message.attachments {
+        if attachment.mime_type.starts_with("image/") && attachment.body.len() > MAX_IMAGE_SIZE {
+            lints.push(Lint::new(
+                "oversized-image",
+                Severity::Warning,
+                format!(
+                    "image {} is {} bytes",
+                    attachment.filename,
+                    attachment.body.len()
+                ),
+            ));
+        }
+    }
+
+    lints
+}
+
+fn has_text_part(part: &MimePart) -> bool {
+    let inline = part.disposition.as_deref() != Some("attachment");
+
+    (part.content_type == "text/plain" && inline)
+        || (part.content_type.starts_with("multipart/") && part.parts.iter().any(has_text_part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MessageBuilder;
+
+    fn ids(data: &[u8]) -> Vec<&'static str> {
+        let message = Message::try_from(&data.to_vec()).unwrap();
+        lint(&message).into_iter().map(|lint| lint.id).collect()
+    }
+
+    #[test]
+    fn test_html_only_message() {
+        let data = MessageBuilder::new()
+            .from("sender@example.com")
+            .to("rcpt@example.com")
+            .subject("newsletter")
+            .html("<p>Hello</p>")
+            .build();
+
+        assert_eq!(ids(&data), vec!["no-plaintext-alternative"]);
+    }
+
+    #[test]
+    fn test_clean_and_broken_messages() {
+        let data = MessageBuilder::new()
+            .from("sender@example.com")
+            .to("rcpt@example.com")
+            .subject("newsletter")
+            .plain("Hello")
+            .html("<p>Hello</p>")
+            .build();
+        assert!(ids(&data).is_empty());
+
+        let data = MessageBuilder::new()
+            .plain("Hello")
+            .attachment("photo.png", "image/png", &vec![0; MAX_IMAGE_SIZE + 1])
+            .build();
+        assert_eq!(
+            ids(&data),
+            vec![
+                "missing-from",
+                "missing-to",
+                "missing-subject",
+                "oversized-image"
+            ]
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use super::Message;
+use crate::{Error, Result};
+use std::str::FromStr;
+
+/// Mailbox of messages matching no [`MailboxRule`].
+pub const DEFAULT_MAILBOX: &str = "default";
+
+/// Routes captured messages into the named mailbox, written as
+/// `name=domain:example.com` or `name=subject:text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxRule {
+    pub mailbox: String,
+    pub matcher: Matcher,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Matcher {
+    /// Any envelope or header recipient at this domain.
+    Domain(String),
+    /// Subject containing the text, case-insensitively.
+    Subject(String),
+}
+
+impl MailboxRule {
+    /// Name of the first mailbox whose rule matches `message`.
+    pub fn route(rules: &[Self], message: &Message) -> String {
+        rules
+            .iter()
+            .find(|rule| rule.matches(message))
+            .map_or(DEFAULT_MAILBOX, |rule| rule.mailbox.as_str())
+            .to_owned()
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        match &self.matcher {
+            Matcher::Domain(domain) => message
+                .envelope_recipients
+                .iter()
+                .chain(message.recipients.iter())
+                .filter_map(|recipient| recipient.rsplit_once('@'))
+                .any(|(_, host)| host.trim_end_matches('>').eq_ignore_ascii_case(domain)),
+            Matcher::Subject(text) => message
+                .subject
+                .to_lowercase()
+                .contains(&text.to_lowercase()),
+        }
+    }
+}
+
+impl FromStr for MailboxRule {
+    type Err = Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid mailbox rule {}", rule));
+
+        let (mailbox, matcher) = rule.split_once('=').ok_or_else(invalid)?;
+        let (kind, value) = matcher.split_once(':').ok_or_else(invalid)?;
+
+        if mailbox.is_empty() || value.is_empty() {
+            return Err(invalid());
+        }
+
+        let matcher = match kind {
+            "domain" => Matcher::Domain(value.to_owned()),
+            "subject" => Matcher::Subject(value.to_owned()),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            mailbox: mailbox.to_owned(),
+            matcher,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            "billing=domain:example.com".parse::<MailboxRule>().unwrap(),
+            MailboxRule {
+                mailbox: "billing".to_owned(),
+                matcher: Matcher::Domain("example.com".to_owned()),
+            }
+        );
+        assert_eq!(
+            "alerts=subject:[ALERT]".parse::<MailboxRule>().unwrap(),
+            MailboxRule {
+                mailbox: "alerts".to_owned(),
+                matcher: Matcher::Subject("[ALERT]".to_owned()),
+            }
+        );
+        assert!("alerts".parse::<MailboxRule>().is_err());
+        assert!("alerts=header:x".parse::<MailboxRule>().is_err());
+    }
+}
@@ -1,23 +1,115 @@
+use super::{html_to_text, links, Calendar};
+use crate::{Error, Result};
 use chrono::Local;
 use mail_parser;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Instant;
 use uuid::Uuid;
 
 #[derive(Serialize, Debug, Default, Clone)]
 pub struct Message {
     pub id: Option<usize>,
+    /// SHA-256 of `source`, stable across repeated deliveries of the same bytes.
+    pub content_id: String,
     pub sender: String,
     pub recipients: Vec<String>,
+    /// `From` address with the domain lowercased, when
+    /// `normalize_addresses` is set. `sender` keeps the header as written.
+    pub normalized_sender: Option<String>,
+    /// `recipients` with their domains lowercased, when
+    /// `normalize_addresses` is set.
+    pub normalized_recipients: Vec<String>,
+    pub envelope_sender: String,
+    pub envelope_recipients: Vec<String>,
+    /// Address of the SMTP client that delivered the message.
+    pub client_ip: Option<String>,
+    /// Reverse DNS name of `client_ip`, when lookups are enabled.
+    pub client_host: Option<String>,
+    /// Name the client announced in HELO/EHLO.
+    pub helo: Option<String>,
+    /// Negotiated TLS version, e.g. `TLSv1.3`, for deliveries after STARTTLS.
+    pub tls_version: Option<String>,
+    /// Negotiated cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`.
+    pub tls_cipher: Option<String>,
+    /// Milliseconds from MAIL FROM to the end of DATA.
+    pub transaction_ms: Option<u64>,
+    /// Milliseconds [`Message::parse`] took when the message was captured.
+    pub parse_ms: u64,
+    /// SMTP commands (`C:`) and replies (`S:`) of the delivery, with AUTH
+    /// credentials redacted.
+    #[serde(skip_serializing)]
+    pub transcript: Vec<String>,
     pub subject: String,
+    /// Unfolded `Received` headers, topmost (most recent hop) first.
+    /// mailtutan only adds a hop of its own when `received_header` is set.
+    pub received: Vec<String>,
+    /// Results of all `Authentication-Results` headers, in header order.
+    pub auth_results: Vec<AuthResult>,
     pub created_at: Option<String>,
+    pub labels: Vec<String>,
     pub attachments: Vec<Attachment>,
+    /// The message as transmitted in DATA: dot-unstuffed (RFC 5321 section
+    /// 4.5.2) and without the terminating `.` line. With `raw_source` set
+    /// it is the untouched wire bytes instead.
     #[serde(skip_serializing)]
     pub source: Vec<u8>,
+    /// Length of the parsed message in bytes, kept when the bodies are
+    /// discarded.
+    pub size: usize,
     pub formats: Vec<String>,
+    /// URLs from html `href`/`src` attributes and the plain text body.
+    pub links: Vec<String>,
     #[serde(skip_serializing)]
     pub html: Option<String>,
     #[serde(skip_serializing)]
     pub plain: Option<String>,
+    /// Set when the message has no text part and `plain` was converted
+    /// from the html body.
+    pub plain_derived: bool,
+    /// `plain` pretty-printed, when `pretty_json` is set and the plain body
+    /// is a JSON document, with object keys sorted. `plain` keeps the body
+    /// as sent.
+    #[serde(skip_serializing)]
+    pub pretty_json: Option<String>,
+    /// `Content-Language` of the html body part, if declared.
+    pub html_language: Option<String>,
+    /// `Content-Language` of the plain body part, if declared.
+    pub plain_language: Option<String>,
+    /// First recipient block of a `message/delivery-status` part, for bounces.
+    pub delivery_status: Option<DeliveryStatus>,
+    /// Event details of the first `text/calendar` part, e.g. an invite.
+    pub calendar: Option<Calendar>,
+    /// URIs listed in `List-Unsubscribe`, in header order.
+    pub unsubscribe: Vec<String>,
+    /// RFC 8058: `List-Unsubscribe-Post: List-Unsubscribe=One-Click` along
+    /// with an https unsubscribe URI.
+    pub one_click_unsubscribe: bool,
+    /// Set for S/MIME enveloped and PGP/MIME encrypted messages, whose
+    /// encrypted blob is kept as an attachment.
+    pub encrypted: bool,
+    /// `smime` or `pgp` when `encrypted`.
+    pub encryption_type: Option<String>,
+    /// Set in headers-only mode: `source` holds just the header block and
+    /// the decoded bodies and attachment bytes were dropped.
+    pub bodies_discarded: bool,
+    /// Set when `html`, `plain` or `pretty_json` was cut to
+    /// `max_body_length`. `source` still holds the whole message.
+    pub body_truncated: bool,
+    /// Problems found while parsing that didn't prevent storing the message.
+    pub warnings: Vec<String>,
+    /// Matched one of the configured spam keywords on capture.
+    pub spam: bool,
+    /// Mailbox the message was routed into on capture.
+    pub mailbox: String,
+    /// Held for review: left out of the message list unless asked for.
+    pub quarantined: bool,
+    /// `cid:` references in the html without a part of that Content-ID,
+    /// when `verify_cids` is set.
+    pub dangling_cids: Vec<String>,
+    /// Per-day number like `2024-06-01/007`, when `daily_sequence` is set.
+    pub sequence: Option<String>,
 }
 
 #[derive(Serialize, Debug, Default, Clone)]
@@ -32,24 +124,251 @@ pub struct Attachment {
     pub cid: String,
     #[serde(rename = "type")]
     pub file_type: String,
+    /// Full `type/subtype`, e.g. `application/pdf`.
+    pub mime_type: String,
     pub filename: String,
+    /// MIME `Content-ID`, without the angle brackets, used by `cid:` links.
+    pub content_id: Option<String>,
     #[serde(skip_serializing)]
     pub body: Vec<u8>,
 }
 
-impl From<&Vec<u8>> for Message {
-    fn from(data: &Vec<u8>) -> Self {
+/// One `method=result` entry of an `Authentication-Results` header
+/// (RFC 8601), e.g. `spf=pass`.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuthResult {
+    /// Lowercased method without its version, e.g. `dkim`.
+    pub method: String,
+    /// Lowercased result, e.g. `pass` or `fail`.
+    pub result: String,
+}
+
+impl AuthResult {
+    /// Parses the value of one header. The leading authserv-id and the
+    /// properties following each result are skipped.
+    fn parse(value: &str) -> Vec<Self> {
+        let mut value = String::from(value);
+
+        // Drop CFWS comments, e.g. `dkim=pass (good signature)`.
+        while let Some(start) = value.find('(') {
+            match value[start..].find(')') {
+                Some(end) => value.replace_range(start..start + end + 1, " "),
+                None => value.truncate(start),
+            }
+        }
+
+        value
+            .split(';')
+            .skip(1)
+            .filter_map(|entry| {
+                let (method, result) = entry.split_whitespace().next()?.split_once('=')?;
+                let method = method.split('/').next().unwrap_or(method);
+
+                Some(Self {
+                    method: method.to_ascii_lowercase(),
+                    result: result.to_ascii_lowercase(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Per-recipient fields of a DSN (RFC 3464) delivery-status report.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeliveryStatus {
+    pub final_recipient: Option<String>,
+    pub action: Option<String>,
+    pub status: Option<String>,
+    pub diagnostic: Option<String>,
+}
+
+impl DeliveryStatus {
+    /// Parses the body of a `message/delivery-status` part. The first field
+    /// group describes the reporting MTA and is skipped.
+    fn parse(body: &str) -> Option<Self> {
+        let body = body.replace("\r\n", "\n");
+        let group = body
+            .split("\n\n")
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .nth(1)?;
+
+        let mut fields: Vec<(String, String)> = vec![];
+
+        for line in group.lines() {
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some((name, value)) = line.split_once(':') {
+                fields.push((name.trim().to_lowercase(), value.trim().to_owned()));
+            }
+        }
+
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        Some(Self {
+            final_recipient: field("final-recipient"),
+            action: field("action"),
+            status: field("status"),
+            diagnostic: field("diagnostic-code"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    pub fn normalize(self, text: &str) -> String {
+        let text = text.replace("\r\n", "\n");
+
+        match self {
+            LineEnding::Lf => text,
+            LineEnding::Crlf => text.replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Knobs applied while turning raw bytes into a [`Message`].
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Rewrite the line endings of the decoded `plain`/`html` bodies.
+    /// `source` is always kept as received.
+    pub line_endings: Option<LineEnding>,
+    /// Reject malformed input instead of keeping a best-effort parse.
+    pub strict: bool,
+    pub max_attachments: Option<usize>,
+    /// Limit on the summed decoded size of all attachments, in bytes.
+    pub max_attachments_size: Option<usize>,
+    /// Limit on the decoded size of any single attachment, in bytes. Over
+    /// it, rejecting answers 552 and truncating drops just that attachment.
+    pub max_attachment_size: Option<usize>,
+    /// What to do with a message over either attachment limit.
+    pub attachment_overflow: AttachmentOverflow,
+    /// Charset used for text bodies that declare none and aren't valid
+    /// UTF-8, e.g. `windows-1252`.
+    pub default_charset: Option<String>,
+    /// Check `cid:` references in the html against the attachments.
+    pub verify_cids: bool,
+    /// Pretty-print plain bodies that parse as a JSON object or array.
+    pub pretty_json: bool,
+    /// Longest decoded body kept for preview, in characters.
+    pub max_body_length: Option<usize>,
+    /// Derive `plain` of html-only messages with [`html_to_text`], which
+    /// keeps block elements, tables and link targets, instead of
+    /// mail-parser's conversion.
+    pub html_to_text: bool,
+    /// Fill the normalized address fields. Local parts keep their case, as
+    /// only the domain is case-insensitive (RFC 5321).
+    pub normalize_addresses: bool,
+}
+
+impl ParseOptions {
+    /// Whether `charset` can be used as `default_charset`.
+    pub fn is_known_charset(charset: &str) -> bool {
+        mail_parser::decoders::charsets::map::charset_decoder(charset.as_bytes()).is_some()
+    }
+
+    /// Whether parsing can turn a delivery down with its own SMTP reply,
+    /// in strict mode or with attachment limits that reject.
+    pub fn rejects_after_parse(&self) -> bool {
+        let limited = self.max_attachments.is_some()
+            || self.max_attachments_size.is_some()
+            || self.max_attachment_size.is_some();
+
+        self.strict || (limited && self.attachment_overflow == AttachmentOverflow::Reject)
+    }
+}
+
+/// Lowercases the domain of `address`, leaving the local part alone.
+fn normalize_address(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_lowercase()),
+        None => address.to_owned(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AttachmentOverflow {
+    /// Fail the parse, which SMTP reports as 554, or 552 for a single
+    /// attachment over its limit.
+    #[default]
+    Reject,
+    /// Keep the attachments that fit and record a warning.
+    Truncate,
+}
+
+impl TryFrom<&Vec<u8>> for Message {
+    type Error = Error;
+
+    fn try_from(data: &Vec<u8>) -> Result<Self> {
+        Self::parse(data, &ParseOptions::default())
+    }
+}
+
+impl Message {
+    /// Replaces `source` and recomputes `content_id`, leaving the parsed
+    /// fields as they are.
+    pub fn set_source(&mut self, source: Vec<u8>) {
+        self.content_id = content_id(&source);
+        self.source = source;
+    }
+
+    /// Keeps the metadata and the header block of `source`, dropping
+    /// everything else that holds body bytes.
+    pub fn discard_bodies(&mut self) {
+        let end = [&b"\r\n\r\n"[..], b"\n\n"]
+            .iter()
+            .filter_map(|separator| {
+                self.source
+                    .windows(separator.len())
+                    .position(|window| window == *separator)
+                    .map(|position| position + separator.len())
+            })
+            .min()
+            .unwrap_or(self.source.len());
+
+        self.source.truncate(end);
+        self.html = None;
+        self.plain = None;
+        self.pretty_json = None;
+
+        for attachment in &mut self.attachments {
+            attachment.body = vec![];
+        }
+
+        self.bodies_discarded = true;
+    }
+
+    pub fn parse(data: &[u8], options: &ParseOptions) -> Result<Self> {
         use mail_parser::HeaderValue;
 
-        let message = mail_parser::Message::parse(data.as_ref()).unwrap();
+        let started = Instant::now();
+        let message = mail_parser::Message::parse(data)
+            .ok_or_else(|| Error::Parse("no message headers found".to_owned()))?;
+
+        if options.strict {
+            validate(data, &message)?;
+        }
 
         let sender = {
             if let HeaderValue::Address(addr) = message.from() {
-                format!(
-                    "{} {}",
-                    addr.name.as_ref().unwrap(),
-                    addr.address.as_ref().unwrap()
-                )
+                match (addr.name.as_ref(), addr.address.as_ref()) {
+                    (Some(name), Some(address)) => format!("{} {}", name, address),
+                    (None, Some(address)) => address.to_string(),
+                    (Some(name), None) => name.to_string(),
+                    (None, None) => "".to_owned(),
+                }
             } else {
                 "".to_owned()
             }
@@ -59,60 +378,522 @@ impl From<&Vec<u8>> for Message {
             let mut list: Vec<String> = vec![];
 
             if let HeaderValue::Address(addr) = message.to() {
-                list.push(format!("{}", addr.address.as_ref().unwrap()));
+                if let Some(address) = addr.address.as_ref() {
+                    list.push(address.to_string());
+                }
             }
 
             list
         };
+        let (normalized_sender, normalized_recipients) = if options.normalize_addresses {
+            let from = match message.from() {
+                HeaderValue::Address(addr) => addr.address.as_deref().map(normalize_address),
+                _ => None,
+            };
+
+            (
+                from,
+                recipients
+                    .iter()
+                    .map(|item| normalize_address(item))
+                    .collect(),
+            )
+        } else {
+            (None, vec![])
+        };
         let subject = message.subject().unwrap_or("").to_string();
 
+        let received = message
+            .headers()
+            .iter()
+            .filter(|header| header.name().eq_ignore_ascii_case("Received"))
+            .filter_map(|header| data.get(header.offset_start..header.offset_end))
+            .map(|value| {
+                String::from_utf8_lossy(value)
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+
+        let auth_results = message
+            .headers()
+            .iter()
+            .filter(|header| header.name().eq_ignore_ascii_case("Authentication-Results"))
+            .filter_map(|header| data.get(header.offset_start..header.offset_end))
+            .flat_map(|value| AuthResult::parse(&String::from_utf8_lossy(value)))
+            .collect();
+
         let mut formats = vec!["source".to_owned()];
         let mut html: Option<String> = None;
         let mut plain: Option<String> = None;
+        let mut plain_derived = false;
 
         if message.html_body_count() > 0 {
             formats.push("html".to_owned());
-            html = Some(message.body_html(0).unwrap().to_string());
+            html = message
+                .html_part(0)
+                .and_then(|part| default_charset_text(data, part, options))
+                .or_else(|| message.body_html(0).map(|body| body.to_string()));
         }
 
         if message.text_body_count() > 0 {
             formats.push("plain".to_owned());
-            plain = Some(message.body_text(0).unwrap().to_string());
+            // Without a text part mail-parser hands out its own rendering
+            // of the html part here.
+            plain_derived = message
+                .text_part(0)
+                .is_some_and(|part| matches!(part.body, mail_parser::PartType::Html(_)));
+            plain = match &html {
+                Some(html) if plain_derived && options.html_to_text => Some(html_to_text(html)),
+                _ => message
+                    .text_part(0)
+                    .and_then(|part| default_charset_text(data, part, options))
+                    .or_else(|| message.body_text(0).map(|body| body.to_string())),
+            };
+        }
+
+        let mut pretty_json = match &plain {
+            Some(plain) if options.pretty_json => pretty_print_json(plain),
+            _ => None,
+        };
+        if pretty_json.is_some() {
+            formats.push("json".to_owned());
+        }
+
+        let links = links::extract(html.as_deref(), plain.as_deref());
+
+        let html_language = message.html_part(0).and_then(content_language);
+        let plain_language = message.text_part(0).and_then(content_language);
+
+        if let Some(line_endings) = options.line_endings {
+            html = html.map(|body| line_endings.normalize(&body));
+            plain = plain.map(|body| line_endings.normalize(&body));
         }
 
         use mail_parser::MimeHeaders;
 
-        let attachments = message
+        let delivery_status = message
+            .attachments()
+            .find(|part| {
+                part.content_type().is_some_and(|content_type| {
+                    content_type.ctype().eq_ignore_ascii_case("message")
+                        && content_type.subtype().is_some_and(|subtype| {
+                            subtype.eq_ignore_ascii_case("delivery-status")
+                                || subtype.eq_ignore_ascii_case("global-delivery-status")
+                        })
+                })
+            })
+            .and_then(|part| part.text_contents())
+            .and_then(DeliveryStatus::parse);
+
+        let calendar = message
+            .parts
+            .iter()
+            .filter(|part| {
+                part.content_type().is_some_and(|content_type| {
+                    content_type.ctype().eq_ignore_ascii_case("text")
+                        && content_type
+                            .subtype()
+                            .is_some_and(|subtype| subtype.eq_ignore_ascii_case("calendar"))
+                })
+            })
+            .find_map(|part| part.text_contents().and_then(Calendar::parse));
+
+        let unsubscribe: Vec<String> = message
+            .header_raw("List-Unsubscribe")
+            .map(angle_bracketed)
+            .unwrap_or_default();
+        let one_click_unsubscribe =
+            message
+                .header_raw("List-Unsubscribe-Post")
+                .is_some_and(|post| {
+                    post.trim()
+                        .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+                })
+                && unsubscribe
+                    .iter()
+                    .any(|uri| uri.to_ascii_lowercase().starts_with("https://"));
+
+        let mut attachments: Vec<Attachment> = message
             .attachments()
             .map(|attachment| Attachment {
                 filename: attachment
                     .attachment_name()
-                    .unwrap_or("unknown")
+                    .unwrap_or(if attachment.is_message() {
+                        "message.eml"
+                    } else {
+                        "unknown"
+                    })
+                    .to_string(),
+                file_type: attachment
+                    .content_type()
+                    .map_or("application", |content_type| content_type.ctype())
                     .to_string(),
-                file_type: attachment.content_type().unwrap().ctype().to_string(),
-                body: attachment.contents().to_vec(),
+                mime_type: attachment.content_type().map_or_else(
+                    || "application/octet-stream".to_owned(),
+                    |content_type| match content_type.subtype() {
+                        Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+                        None => content_type.ctype().to_owned(),
+                    },
+                ),
+                body: match attachment.message() {
+                    // Nested messages borrow the outer buffer, so slice the
+                    // part's own bytes out of it.
+                    Some(_) => data
+                        .get(attachment.offset_body..attachment.offset_end)
+                        .unwrap_or_default()
+                        .to_vec(),
+                    None => attachment.contents().to_vec(),
+                },
+                content_id: attachment.content_id().map(|id| id.to_owned()),
                 cid: Uuid::new_v4().to_string(),
             })
             .collect();
 
-        Self {
+        let encryption_type = message.parts.first().and_then(encryption_type);
+
+        let mut warnings = vec![];
+        limit_attachments(&mut attachments, options, &mut warnings)?;
+        disambiguate_content_ids(&mut attachments, &mut warnings);
+
+        let dangling_cids = if options.verify_cids {
+            dangling_cids(&links, &attachments)
+        } else {
+            vec![]
+        };
+        if !dangling_cids.is_empty() {
+            warnings.push(format!(
+                "html references missing parts: {}",
+                dangling_cids.join(", ")
+            ));
+        }
+
+        let mut body_truncated = false;
+        if let Some(max) = options.max_body_length {
+            for body in [&mut html, &mut plain, &mut pretty_json]
+                .into_iter()
+                .flatten()
+            {
+                if let Some((end, _)) = body.char_indices().nth(max) {
+                    body.truncate(end);
+                    body_truncated = true;
+                }
+            }
+        }
+
+        Ok(Self {
             id: None,
+            content_id: content_id(data),
             sender,
             recipients,
+            normalized_sender,
+            normalized_recipients,
+            envelope_sender: String::new(),
+            envelope_recipients: vec![],
+            client_ip: None,
+            client_host: None,
+            helo: None,
+            tls_version: None,
+            tls_cipher: None,
+            transaction_ms: None,
+            parse_ms: started.elapsed().as_millis() as u64,
+            transcript: vec![],
             subject,
+            received,
+            auth_results,
             created_at: Some(Local::now().format("%Y-%m-%d %H:%M:%S").to_string()),
+            labels: vec![],
             attachments,
             source: data.to_owned(),
+            size: data.len(),
             formats,
+            links,
             html,
             plain,
+            plain_derived,
+            pretty_json,
+            html_language,
+            plain_language,
+            delivery_status,
+            calendar,
+            unsubscribe,
+            one_click_unsubscribe,
+            encrypted: encryption_type.is_some(),
+            encryption_type,
+            bodies_discarded: false,
+            body_truncated,
+            warnings,
+            spam: false,
+            mailbox: String::new(),
+            quarantined: false,
+            dangling_cids,
+            sequence: None,
+        })
+    }
+}
+
+/// Decodes a text part that declares no charset with
+/// `options.default_charset`. `None` when the part declares one, isn't
+/// text, or is valid UTF-8 anyway, in which case mail-parser's text is kept.
+fn default_charset_text(
+    data: &[u8],
+    part: &mail_parser::MessagePart,
+    options: &ParseOptions,
+) -> Option<String> {
+    use mail_parser::decoders::base64::base64_decode;
+    use mail_parser::decoders::charsets::map::charset_decoder;
+    use mail_parser::decoders::quoted_printable::quoted_printable_decode;
+    use mail_parser::{Encoding, MimeHeaders, PartType};
+
+    let decoder = charset_decoder(options.default_charset.as_ref()?.as_bytes())?;
+
+    if !matches!(part.body, PartType::Text(_) | PartType::Html(_))
+        || part
+            .content_type()
+            .is_some_and(|content_type| content_type.attribute("charset").is_some())
+    {
+        return None;
+    }
+
+    let raw = data.get(part.offset_body..part.offset_end)?;
+    let bytes = match part.encoding {
+        Encoding::Base64 => base64_decode(raw)?,
+        Encoding::QuotedPrintable => quoted_printable_decode(raw)?,
+        Encoding::None => raw.to_vec(),
+    };
+
+    match std::str::from_utf8(&bytes) {
+        Ok(_) => None,
+        Err(_) => Some(decoder(&bytes)),
+    }
+}
+
+/// `text` re-indented when it is a JSON document, `None` otherwise.
+/// Only objects and arrays count, so bodies that happen to be a bare number
+/// or quoted string are left alone.
+fn pretty_print_json(text: &str) -> Option<String> {
+    if !text.trim_start().starts_with(['{', '[']) {
+        return None;
+    }
+
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+
+    serde_json::to_string_pretty(&value).ok()
+}
+
+/// Content-IDs of `cid:` links that no attachment carries.
+fn dangling_cids(links: &[String], attachments: &[Attachment]) -> Vec<String> {
+    links
+        .iter()
+        .filter_map(|link| {
+            let scheme = link.get(..4)?;
+            scheme
+                .eq_ignore_ascii_case("cid:")
+                .then(|| link[4..].to_owned())
+        })
+        .filter(|cid| {
+            !attachments
+                .iter()
+                .any(|attachment| attachment.content_id.as_deref() == Some(cid.as_str()))
+        })
+        .collect()
+}
+
+/// Appends `-2`, `-3`, ... to repeated Content-IDs so every part stays
+/// addressable; `cid:` links keep pointing at the first one.
+fn disambiguate_content_ids(attachments: &mut [Attachment], warnings: &mut Vec<String>) {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for attachment in attachments {
+        let Some(content_id) = &attachment.content_id else {
+            continue;
+        };
+
+        let count = seen.entry(content_id.clone()).or_default();
+        *count += 1;
+        if *count == 2 {
+            warnings.push(format!("duplicate Content-ID: {}", content_id));
         }
+        if *count > 1 {
+            attachment.content_id = Some(format!("{}-{}", content_id, count));
+        }
+    }
+}
+
+fn content_language(part: &mail_parser::MessagePart) -> Option<String> {
+    use mail_parser::{HeaderValue, MimeHeaders};
+
+    match part.content_language() {
+        HeaderValue::Text(language) => Some(language.to_string()),
+        HeaderValue::TextList(languages) => Some(languages.join(", ")),
+        _ => None,
     }
 }
 
+fn encryption_type(part: &mail_parser::MessagePart) -> Option<String> {
+    use mail_parser::MimeHeaders;
+
+    let content_type = part.content_type()?;
+    let ctype = content_type.ctype().to_ascii_lowercase();
+    let subtype = content_type.subtype()?.to_ascii_lowercase();
+
+    match (ctype.as_str(), subtype.as_str()) {
+        ("multipart", "encrypted") => Some("pgp".to_owned()),
+        ("application", "pkcs7-mime" | "x-pkcs7-mime") => {
+            // Opaque signed-data uses the same type but isn't encrypted.
+            let signed = content_type
+                .attribute("smime-type")
+                .is_some_and(|smime_type| smime_type.eq_ignore_ascii_case("signed-data"));
+
+            (!signed).then(|| "smime".to_owned())
+        }
+        _ => None,
+    }
+}
+
+fn limit_attachments(
+    attachments: &mut Vec<Attachment>,
+    options: &ParseOptions,
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    if let Some(max_attachment_size) = options.max_attachment_size {
+        let oversized: Vec<String> = attachments
+            .iter()
+            .filter(|attachment| attachment.body.len() > max_attachment_size)
+            .map(|attachment| attachment.filename.clone())
+            .collect();
+
+        if !oversized.is_empty() {
+            match options.attachment_overflow {
+                AttachmentOverflow::Reject => {
+                    return Err(Error::TooLarge(format!(
+                        "attachment {} exceeds {} bytes",
+                        oversized[0], max_attachment_size
+                    )))
+                }
+                AttachmentOverflow::Truncate => {
+                    warnings.push(format!(
+                        "dropped attachments over {} bytes: {}",
+                        max_attachment_size,
+                        oversized.join(", ")
+                    ));
+                    attachments.retain(|attachment| attachment.body.len() <= max_attachment_size);
+                }
+            }
+        }
+    }
+
+    let max_count = options.max_attachments.unwrap_or(usize::MAX);
+    let max_size = options.max_attachments_size.unwrap_or(usize::MAX);
+
+    let mut size = 0;
+    let fitting = attachments
+        .iter()
+        .take_while(|attachment| {
+            size += attachment.body.len();
+            size <= max_size
+        })
+        .take(max_count)
+        .count();
+
+    if fitting == attachments.len() {
+        return Ok(());
+    }
+
+    match options.attachment_overflow {
+        AttachmentOverflow::Reject => Err(Error::TooManyAttachments(format!(
+            "{} attachments exceed the configured limits",
+            attachments.len()
+        ))),
+        AttachmentOverflow::Truncate => {
+            warnings.push(format!(
+                "dropped {} of {} attachments over the configured limits",
+                attachments.len() - fitting,
+                attachments.len()
+            ));
+            attachments.truncate(fitting);
+            Ok(())
+        }
+    }
+}
+
+/// Checks done in strict mode on top of what mail-parser accepts: every
+/// header line must be a `Name: value` field or a continuation, and every
+/// multipart must be closed by its final boundary.
+fn validate(data: &[u8], message: &mail_parser::Message) -> Result<()> {
+    use mail_parser::MimeHeaders;
+
+    for (number, line) in data.split(|byte| *byte == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if line.is_empty() {
+            break;
+        }
+
+        let continuation = number > 0 && matches!(line[0], b' ' | b'\t');
+        let field = line
+            .iter()
+            .position(|byte| *byte == b':')
+            .is_some_and(|colon| {
+                colon > 0 && line[..colon].iter().all(|byte| byte.is_ascii_graphic())
+            });
+
+        if !continuation && !field {
+            return Err(Error::Parse(format!(
+                "malformed header line {}",
+                number + 1
+            )));
+        }
+    }
+
+    for part in message.parts.iter() {
+        let boundary = part
+            .content_type()
+            .filter(|content_type| content_type.ctype().eq_ignore_ascii_case("multipart"))
+            .and_then(|content_type| content_type.attribute("boundary"));
+
+        if let Some(boundary) = boundary {
+            let closing = format!("--{}--", boundary);
+
+            if !data
+                .windows(closing.len())
+                .any(|window| window == closing.as_bytes())
+            {
+                return Err(Error::Parse(format!(
+                    "multipart boundary {:?} is never closed",
+                    boundary
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Values of a `<uri>, <uri>` list header, ignoring anything outside the
+/// brackets.
+fn angle_bracketed(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|item| item.split_once('>'))
+        .map(|(uri, _)| uri.split_whitespace().collect())
+        .collect()
+}
+
+/// Hex SHA-256 of `data`, used as `content_id`.
+pub(crate) fn content_id(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::MessageBuilder;
 
     #[test]
     fn test_subject() {
@@ -126,7 +907,7 @@ mod test {
         .as_bytes()
         .to_vec();
 
-        let message = Message::from(&data);
+        let message = Message::try_from(&data).unwrap();
         assert_eq!(message.subject, "SMTP e-mail test");
     }
 
@@ -154,7 +935,7 @@ mod test {
         .as_bytes()
         .to_vec();
 
-        let message = Message::from(&data);
+        let message = Message::try_from(&data).unwrap();
         assert_eq!(message.subject, "This is a test email");
     }
 
@@ -181,7 +962,702 @@ mod test {
         .as_bytes()
         .to_vec();
 
-        let message = Message::from(&data);
+        let message = Message::try_from(&data).unwrap();
         assert_eq!(message.subject, "");
     }
+
+    #[test]
+    fn test_empty_input_is_parse_error() {
+        assert!(matches!(Message::try_from(&vec![]), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_multipart_signed() {
+        let data = concat!(
+            "From: Private Person <me@fromdomain.com>\r\n",
+            "Subject: signed\r\n",
+            "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\";\r\n",
+            " micalg=sha-256; boundary=\"signed\"\r\n",
+            "\r\n",
+            "--signed\r\n",
+            "Content-Type: multipart/alternative; boundary=\"alternative\"\r\n",
+            "\r\n",
+            "--alternative\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "plain body\r\n",
+            "--alternative\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<p>html body</p>\r\n",
+            "--alternative--\r\n",
+            "--signed\r\n",
+            "Content-Type: application/pkcs7-signature; name=\"smime.p7s\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "c2lnbmF0dXJl\r\n",
+            "--signed--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert_eq!(message.plain.as_deref(), Some("plain body"));
+        assert_eq!(message.html.as_deref(), Some("<p>html body</p>"));
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(message.attachments[0].filename, "smime.p7s");
+        assert_eq!(message.attachments[0].body, b"signature");
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let data = b"Subject: crlf\r\n\r\nfirst line\r\nsecond line\r\n".to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert_eq!(
+            message.plain.as_deref(),
+            Some("first line\r\nsecond line\r\n")
+        );
+
+        let options = ParseOptions {
+            line_endings: Some(LineEnding::Lf),
+            ..Default::default()
+        };
+        let message = Message::parse(&data, &options).unwrap();
+        assert_eq!(message.plain.as_deref(), Some("first line\nsecond line\n"));
+        assert_eq!(message.source, data);
+    }
+
+    #[test]
+    fn test_content_language() {
+        let data = concat!(
+            "Subject: localized\r\n",
+            "Content-Type: multipart/alternative; boundary=\"alternative\"\r\n",
+            "\r\n",
+            "--alternative\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Language: de\r\n",
+            "\r\n",
+            "Hallo\r\n",
+            "--alternative\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<p>Hallo</p>\r\n",
+            "--alternative--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert_eq!(message.plain_language.as_deref(), Some("de"));
+        assert_eq!(message.html_language, None);
+    }
+
+    #[test]
+    fn test_delivery_status_report() {
+        let data = concat!(
+            "From: MAILER-DAEMON@mx.example.com\r\n",
+            "Subject: Undelivered Mail Returned to Sender\r\n",
+            "Content-Type: multipart/report; report-type=delivery-status;\r\n",
+            " boundary=\"report\"\r\n",
+            "\r\n",
+            "--report\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Your message could not be delivered.\r\n",
+            "--report\r\n",
+            "Content-Type: message/delivery-status\r\n",
+            "\r\n",
+            "Reporting-MTA: dns; mx.example.com\r\n",
+            "\r\n",
+            "Final-Recipient: rfc822; nobody@example.com\r\n",
+            "Action: failed\r\n",
+            "Status: 5.1.1\r\n",
+            "Diagnostic-Code: smtp; 550 5.1.1 user unknown\r\n",
+            "    in virtual alias table\r\n",
+            "\r\n",
+            "--report\r\n",
+            "Content-Type: message/rfc822\r\n",
+            "\r\n",
+            "From: me@example.com\r\n",
+            "To: nobody@example.com\r\n",
+            "Subject: Original message\r\n",
+            "\r\n",
+            "Hello\r\n",
+            "--report--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert_eq!(
+            message.plain.as_deref(),
+            Some("Your message could not be delivered.")
+        );
+        assert_eq!(
+            message.delivery_status,
+            Some(DeliveryStatus {
+                final_recipient: Some("rfc822; nobody@example.com".to_owned()),
+                action: Some("failed".to_owned()),
+                status: Some("5.1.1".to_owned()),
+                diagnostic: Some("smtp; 550 5.1.1 user unknown in virtual alias table".to_owned()),
+            })
+        );
+
+        let original = message
+            .attachments
+            .iter()
+            .find(|attachment| attachment.filename == "message.eml")
+            .unwrap();
+        let original = Message::try_from(&original.body).unwrap();
+        assert_eq!(original.subject, "Original message");
+        assert_eq!(original.sender, "me@example.com");
+    }
+
+    #[test]
+    fn test_one_click_unsubscribe() {
+        let data = concat!(
+            "Subject: newsletter\r\n",
+            "List-Unsubscribe: <mailto:unsubscribe@example.com?subject=unsubscribe>,\r\n",
+            " <https://example.com/unsubscribe/opaque123>\r\n",
+            "List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n",
+            "\r\n",
+            "body\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert!(message.one_click_unsubscribe);
+        assert_eq!(
+            message.unsubscribe,
+            vec![
+                "mailto:unsubscribe@example.com?subject=unsubscribe",
+                "https://example.com/unsubscribe/opaque123"
+            ]
+        );
+
+        let data = concat!(
+            "Subject: newsletter\r\n",
+            "List-Unsubscribe: <mailto:unsubscribe@example.com>\r\n",
+            "List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n",
+            "\r\n",
+            "body\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert!(!message.one_click_unsubscribe);
+        assert_eq!(message.unsubscribe, vec!["mailto:unsubscribe@example.com"]);
+    }
+
+    #[test]
+    fn test_strict_parse() {
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+
+        let data = b"Subject: fine\r\nX-Folded: one\r\n two\r\n\r\nbody\r\n".to_vec();
+        assert!(Message::parse(&data, &strict).is_ok());
+
+        let data = b"Subject: broken\r\nthis is not a header\r\n\r\nbody\r\n".to_vec();
+        assert!(Message::try_from(&data).is_ok());
+        assert!(matches!(
+            Message::parse(&data, &strict),
+            Err(Error::Parse(_))
+        ));
+
+        let data = concat!(
+            "Subject: truncated\r\n",
+            "Content-Type: multipart/mixed; boundary=\"mixed\"\r\n",
+            "\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "cut off here\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+        assert!(Message::try_from(&data).is_ok());
+        assert!(matches!(
+            Message::parse(&data, &strict),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_attachment_limits() {
+        let data = (0..5)
+            .fold(MessageBuilder::new().subject("many parts"), |builder, i| {
+                builder.attachment(&format!("{}.txt", i), "text/plain", b"tiny")
+            })
+            .build();
+
+        let mut options = ParseOptions {
+            max_attachments: Some(3),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Message::parse(&data, &options),
+            Err(Error::TooManyAttachments(_))
+        ));
+
+        options.attachment_overflow = AttachmentOverflow::Truncate;
+        let message = Message::parse(&data, &options).unwrap();
+        assert_eq!(message.attachments.len(), 3);
+        assert_eq!(message.warnings.len(), 1);
+
+        let options = ParseOptions {
+            max_attachments_size: Some(10),
+            attachment_overflow: AttachmentOverflow::Truncate,
+            ..Default::default()
+        };
+        let message = Message::parse(&data, &options).unwrap();
+        assert_eq!(message.attachments.len(), 2);
+
+        let message = Message::try_from(&data).unwrap();
+        assert_eq!(message.attachments.len(), 5);
+        assert!(message.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_single_attachment_limit() {
+        let data = MessageBuilder::new()
+            .subject("one big part")
+            .plain("see attached")
+            .attachment("small.txt", "text/plain", b"tiny")
+            .attachment("big.bin", "application/octet-stream", &[0; 64])
+            .build();
+
+        let mut options = ParseOptions {
+            max_attachment_size: Some(16),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Message::parse(&data, &options),
+            Err(Error::TooLarge(_))
+        ));
+
+        options.attachment_overflow = AttachmentOverflow::Truncate;
+        let message = Message::parse(&data, &options).unwrap();
+        let names: Vec<&str> = message
+            .attachments
+            .iter()
+            .map(|attachment| attachment.filename.as_str())
+            .collect();
+        assert_eq!(names, vec!["small.txt"]);
+        assert_eq!(
+            message.warnings,
+            vec!["dropped attachments over 16 bytes: big.bin"]
+        );
+    }
+
+    #[test]
+    fn test_smime_encrypted() {
+        let data = concat!(
+            "Subject: secret\r\n",
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"\r\n",
+            "Content-Disposition: attachment; filename=\"smime.p7m\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "ZW5jcnlwdGVk\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert!(message.encrypted);
+        assert_eq!(message.encryption_type.as_deref(), Some("smime"));
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(message.attachments[0].filename, "smime.p7m");
+        assert_eq!(message.attachments[0].body, b"encrypted");
+    }
+
+    #[test]
+    fn test_rfc2231_filenames() {
+        let data = concat!(
+            "Subject: files\r\n",
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: multipart/mixed; boundary=\"mixed\"\r\n",
+            "\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "see attached\r\n",
+            "--mixed\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment;\r\n",
+            " filename*=UTF-8''%E2%82%AC%20rates.pdf\r\n",
+            "\r\n",
+            "%PDF\r\n",
+            "--mixed\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment;\r\n",
+            " filename*0*=utf-8''na%C3%AFve%20;\r\n",
+            " filename*1*=r%C3%A9sum%C3%A9;\r\n",
+            " filename*2=\".pdf\"\r\n",
+            "\r\n",
+            "%PDF\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Disposition: attachment; filename*=iso-8859-1'fr'caf%E9.txt\r\n",
+            "\r\n",
+            "text\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain; name*0=\"a-very-long-\"; name*1=\"name.txt\"\r\n",
+            "Content-Disposition: attachment\r\n",
+            "\r\n",
+            "text\r\n",
+            "--mixed--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        let filenames: Vec<&str> = message
+            .attachments
+            .iter()
+            .map(|attachment| attachment.filename.as_str())
+            .collect();
+        assert_eq!(
+            filenames,
+            vec![
+                "€ rates.pdf",
+                "naïve résumé.pdf",
+                "café.txt",
+                "a-very-long-name.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pgp_encrypted() {
+        let data = concat!(
+            "Subject: secret\r\n",
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\";\r\n",
+            " boundary=\"encrypted\"\r\n",
+            "\r\n",
+            "--encrypted\r\n",
+            "Content-Type: application/pgp-encrypted\r\n",
+            "\r\n",
+            "Version: 1\r\n",
+            "--encrypted\r\n",
+            "Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n",
+            "\r\n",
+            "-----BEGIN PGP MESSAGE-----\r\n",
+            "hQEMA1234\r\n",
+            "-----END PGP MESSAGE-----\r\n",
+            "--encrypted--\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert!(message.encrypted);
+        assert_eq!(message.encryption_type.as_deref(), Some("pgp"));
+
+        let blob = message
+            .attachments
+            .iter()
+            .find(|attachment| attachment.filename == "encrypted.asc")
+            .unwrap();
+        assert!(blob.body.starts_with(b"-----BEGIN PGP MESSAGE-----"));
+
+        let plain = Message::try_from(&b"Subject: plain\r\n\r\nhi\r\n".to_vec()).unwrap();
+        assert!(!plain.encrypted);
+        assert_eq!(plain.encryption_type, None);
+    }
+
+    #[test]
+    fn test_discard_bodies() {
+        let data = concat!(
+            "Subject: headers only\r\n",
+            "X-Trace: abc\r\n",
+            "\r\n",
+            "body\r\n",
+            "\r\n",
+            "more body\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let mut message = Message::try_from(&data).unwrap();
+        message.discard_bodies();
+
+        assert!(message.bodies_discarded);
+        assert_eq!(
+            message.source,
+            b"Subject: headers only\r\nX-Trace: abc\r\n\r\n"
+        );
+        assert_eq!(message.subject, "headers only");
+        assert_eq!(message.plain, None);
+    }
+
+    #[test]
+    fn test_received_chain() {
+        let data = concat!(
+            "Received: from relay.example.com (relay.example.com [192.0.2.2])\r\n",
+            "\tby mx.example.org with ESMTP id 2; Mon, 1 May 2023 10:00:02 +0000\r\n",
+            "Received: from app.example.com ([192.0.2.1])\r\n",
+            "\tby relay.example.com with ESMTP id 1; Mon, 1 May 2023 10:00:01 +0000\r\n",
+            "Subject: routed\r\n",
+            "\r\n",
+            "body\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert_eq!(
+            message.received,
+            vec![
+                "from relay.example.com (relay.example.com [192.0.2.2]) by mx.example.org with ESMTP id 2; Mon, 1 May 2023 10:00:02 +0000",
+                "from app.example.com ([192.0.2.1]) by relay.example.com with ESMTP id 1; Mon, 1 May 2023 10:00:01 +0000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_charset() {
+        let mut data = b"Subject: legacy\r\nContent-Type: text/plain\r\n\r\n".to_vec();
+        data.extend_from_slice(b"caf\xe9 \x80 5\r\n");
+
+        let options = ParseOptions {
+            default_charset: Some("windows-1252".to_owned()),
+            ..Default::default()
+        };
+        let message = Message::parse(&data, &options).unwrap();
+        assert_eq!(message.plain.as_deref(), Some("caf\u{e9} \u{20ac} 5\r\n"));
+
+        let message = Message::parse(&data, &ParseOptions::default()).unwrap();
+        assert_ne!(message.plain.as_deref(), Some("caf\u{e9} \u{20ac} 5\r\n"));
+
+        let mut declared =
+            b"Subject: utf8\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n".to_vec();
+        declared.extend_from_slice("caf\u{e9}\r\n".as_bytes());
+        let message = Message::parse(&declared, &options).unwrap();
+        assert_eq!(message.plain.as_deref(), Some("caf\u{e9}\r\n"));
+
+        let multipart = concat!(
+            "Subject: legacy\r\n",
+            "Content-Type: multipart/mixed; boundary=b\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Transfer-Encoding: quoted-printable\r\n",
+            "\r\n",
+            "caf=E9\r\n",
+            "--b--\r\n"
+        );
+        let message = Message::parse(multipart.as_bytes(), &options).unwrap();
+        assert_eq!(message.plain.as_deref(), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn test_calendar_invite() {
+        let data = concat!(
+            "Subject: Invitation: Planning\r\n",
+            "Content-Type: multipart/alternative; boundary=b\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "You are invited.\r\n",
+            "--b\r\n",
+            "Content-Type: text/calendar; method=REQUEST; charset=utf-8\r\n",
+            "\r\n",
+            "BEGIN:VCALENDAR\r\n",
+            "METHOD:REQUEST\r\n",
+            "BEGIN:VEVENT\r\n",
+            "SUMMARY:Quarterly planning\\, Q3\r\n",
+            "DTSTART;TZID=Europe/Berlin:20230915T140000\r\n",
+            "DTEND;TZID=Europe/Berlin:20230915T150000\r\n",
+            "ORGANIZER;CN=Jane:mailto:jane@example.com\r\n",
+            "ATTENDEE;CN=Bob;RSVP=TRUE:mailto:bob@exa\r\n",
+            " mple.com\r\n",
+            "ATTENDEE:MAILTO:carol@example.com\r\n",
+            "END:VEVENT\r\n",
+            "END:VCALENDAR\r\n",
+            "--b--\r\n"
+        );
+
+        let message = Message::try_from(&data.as_bytes().to_vec()).unwrap();
+
+        assert_eq!(
+            message.calendar,
+            Some(Calendar {
+                method: Some("REQUEST".to_owned()),
+                summary: Some("Quarterly planning, Q3".to_owned()),
+                start: Some("20230915T140000".to_owned()),
+                end: Some("20230915T150000".to_owned()),
+                location: None,
+                organizer: Some("jane@example.com".to_owned()),
+                attendees: vec!["bob@example.com".to_owned(), "carol@example.com".to_owned()],
+            })
+        );
+        assert_eq!(message.plain.as_deref(), Some("You are invited."));
+
+        let plain = b"Subject: no invite\r\n\r\nbody\r\n".to_vec();
+        assert_eq!(Message::try_from(&plain).unwrap().calendar, None);
+    }
+
+    #[test]
+    fn test_authentication_results() {
+        let data = concat!(
+            "Authentication-Results: mx.example.net;\r\n",
+            "\tspf=pass smtp.mailfrom=sender@example.com;\r\n",
+            "\tdkim=pass (2048-bit key; secure) header.d=example.com;\r\n",
+            "\tdmarc=FAIL header.from=example.com\r\n",
+            "Authentication-Results: relay.example.org 1; arc/1=none\r\n",
+            "Authentication-Results: other.example.org; none\r\n",
+            "Subject: authenticated\r\n",
+            "\r\n",
+            "body\r\n"
+        );
+
+        let message = Message::try_from(&data.as_bytes().to_vec()).unwrap();
+        let results: Vec<(&str, &str)> = message
+            .auth_results
+            .iter()
+            .map(|entry| (entry.method.as_str(), entry.result.as_str()))
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![
+                ("spf", "pass"),
+                ("dkim", "pass"),
+                ("dmarc", "fail"),
+                ("arc", "none")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dangling_cids() {
+        let data = concat!(
+            "Subject: images\r\n",
+            "Content-Type: multipart/related; boundary=b\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<img src=\"cid:logo@example.com\"><img src=\"cid:missing@example.com\">\r\n",
+            "--b\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-ID: <logo@example.com>\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "iVBORw0KGgo=\r\n",
+            "--b--\r\n"
+        );
+
+        let options = ParseOptions {
+            verify_cids: true,
+            ..Default::default()
+        };
+        let message = Message::parse(data.as_bytes(), &options).unwrap();
+        assert_eq!(message.dangling_cids, vec!["missing@example.com"]);
+        assert_eq!(
+            message.warnings,
+            vec!["html references missing parts: missing@example.com"]
+        );
+
+        let message = Message::parse(data.as_bytes(), &ParseOptions::default()).unwrap();
+        assert!(message.dangling_cids.is_empty());
+        assert!(message.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_pretty_json() {
+        let data = concat!(
+            "Subject: webhook\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "{\"amount\":[1,2],\"event\":\"paid\"}\r\n"
+        );
+        let options = ParseOptions {
+            pretty_json: true,
+            ..Default::default()
+        };
+
+        let message = Message::parse(data.as_bytes(), &options).unwrap();
+        assert_eq!(
+            message.plain.as_deref().map(str::trim_end),
+            Some("{\"amount\":[1,2],\"event\":\"paid\"}")
+        );
+        assert_eq!(
+            message.pretty_json.as_deref(),
+            Some("{\n  \"amount\": [\n    1,\n    2\n  ],\n  \"event\": \"paid\"\n}")
+        );
+        assert!(message.formats.contains(&"json".to_owned()));
+
+        let message = Message::parse(data.as_bytes(), &ParseOptions::default()).unwrap();
+        assert_eq!(message.pretty_json, None);
+
+        let data = "Subject: not json\r\n\r\n{ looks like json, but isn't }\r\n";
+        let message = Message::parse(data.as_bytes(), &options).unwrap();
+        assert_eq!(message.pretty_json, None);
+        assert!(!message.formats.contains(&"json".to_owned()));
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let data = concat!(
+            "Subject: html only\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<html><head><style>p { color: red; }</style></head><body>",
+            "<h1>Welcome</h1><p>Hello&nbsp;<b>World</b></p>",
+            "<ul><li>one</li><li>two</li></ul></body></html>\r\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let message = Message::try_from(&data).unwrap();
+        assert!(message.plain_derived);
+        assert!(message.plain.is_some());
+
+        let options = ParseOptions {
+            html_to_text: true,
+            ..Default::default()
+        };
+        let message = Message::parse(&data, &options).unwrap();
+        assert!(message.plain_derived);
+        assert_eq!(
+            message.plain.as_deref(),
+            Some("# Welcome\n\nHello World\n\n* one\n* two")
+        );
+
+        let data = MessageBuilder::new()
+            .plain("typed by hand")
+            .html("<p>rendered</p>")
+            .build();
+        let message = Message::parse(&data, &options).unwrap();
+        assert!(!message.plain_derived);
+        assert_eq!(message.plain.as_deref(), Some("typed by hand"));
+    }
+
+    #[test]
+    fn test_max_body_length() {
+        let plain = "é".repeat(50);
+        let data = MessageBuilder::new().plain(&plain).build();
+        let options = ParseOptions {
+            max_body_length: Some(10),
+            ..Default::default()
+        };
+
+        let message = Message::parse(&data, &options).unwrap();
+        assert_eq!(message.plain.as_deref(), Some("é".repeat(10).as_str()));
+        assert!(message.body_truncated);
+        assert!(String::from_utf8_lossy(&message.source).contains(&plain));
+
+        let message = Message::parse(&data, &ParseOptions::default()).unwrap();
+        assert!(message.plain.unwrap().starts_with(&plain));
+        assert!(!message.body_truncated);
+    }
 }
@@ -0,0 +1,97 @@
+use crate::{Error, Result};
+use mail_parser::{MimeHeaders, PartType};
+use serde::Serialize;
+
+/// One node of a message's MIME tree, as returned by the structure endpoint.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MimePart {
+    /// `type/subtype`, lowercased. Parts without a Content-Type are
+    /// `text/plain` as per RFC 2045.
+    pub content_type: String,
+    pub disposition: Option<String>,
+    pub filename: Option<String>,
+    /// Content-Transfer-Encoding, lowercased.
+    pub encoding: Option<String>,
+    /// Length of the encoded body in bytes.
+    pub size: usize,
+    /// Children of a multipart, or the root part of an attached message.
+    pub parts: Vec<MimePart>,
+}
+
+impl MimePart {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let message = mail_parser::Message::parse(data)
+            .ok_or_else(|| Error::Parse("no message headers found".to_owned()))?;
+
+        Ok(Self::walk(&message, 0))
+    }
+
+    fn walk(message: &mail_parser::Message, id: usize) -> Self {
+        let part = &message.parts[id];
+
+        let content_type = part
+            .content_type()
+            .map(|content_type| match content_type.subtype() {
+                Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+                None => content_type.ctype().to_owned(),
+            })
+            .unwrap_or_else(|| "text/plain".to_owned())
+            .to_ascii_lowercase();
+
+        let parts = match &part.body {
+            PartType::Multipart(ids) => ids.iter().map(|id| Self::walk(message, *id)).collect(),
+            PartType::Message(nested) => vec![Self::walk(nested, 0)],
+            _ => vec![],
+        };
+
+        Self {
+            content_type,
+            disposition: part
+                .content_disposition()
+                .map(|disposition| disposition.ctype().to_ascii_lowercase()),
+            filename: part.attachment_name().map(str::to_owned),
+            encoding: part
+                .content_transfer_encoding()
+                .map(|encoding| encoding.to_ascii_lowercase()),
+            size: part.offset_end.saturating_sub(part.offset_body),
+            parts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MimePart;
+    use crate::test_util::MessageBuilder;
+
+    #[test]
+    fn test_nested_structure() {
+        let data = MessageBuilder::new()
+            .subject("tree")
+            .plain("plain body")
+            .html("<p>html body</p>")
+            .attachment("report.pdf", "application/pdf", b"%PDF-1.4")
+            .build();
+
+        let root = MimePart::parse(&data).unwrap();
+        assert_eq!(root.content_type, "multipart/mixed");
+        assert_eq!(root.parts.len(), 2);
+
+        let alternative = &root.parts[0];
+        assert_eq!(alternative.content_type, "multipart/alternative");
+        let types: Vec<&str> = alternative
+            .parts
+            .iter()
+            .map(|part| part.content_type.as_str())
+            .collect();
+        assert_eq!(types, vec!["text/plain", "text/html"]);
+
+        let attachment = &root.parts[1];
+        assert_eq!(attachment.content_type, "application/pdf");
+        assert_eq!(attachment.disposition.as_deref(), Some("attachment"));
+        assert_eq!(attachment.filename.as_deref(), Some("report.pdf"));
+        assert_eq!(attachment.encoding.as_deref(), Some("base64"));
+        assert!(attachment.size > 0);
+        assert!(attachment.parts.is_empty());
+    }
+}
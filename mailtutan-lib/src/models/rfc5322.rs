@@ -0,0 +1,289 @@
+use super::message::{Attachment, Message, ParseOptions};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use uuid::Uuid;
+
+/// Longest line of a base64 body, as required by RFC 2045.
+const BASE64_LINE_LENGTH: usize = 76;
+
+impl Message {
+    /// Serializes the message into RFC 5322 bytes, e.g. to resend it after
+    /// changing some fields.
+    ///
+    /// `source` is returned as is while `subject`, `sender`, `recipients`,
+    /// the bodies and the attachments still match it. Otherwise the changed
+    /// headers, or the whole MIME body, are rebuilt from those fields and
+    /// the remaining headers of `source` are kept.
+    pub fn to_rfc5322(&self) -> Vec<u8> {
+        let parsed = mail_parser::Message::parse(&self.source);
+        let original = parsed
+            .as_ref()
+            .and_then(|_| Message::parse(&self.source, &ParseOptions::default()).ok())
+            .unwrap_or_default();
+
+        let subject_changed = parsed.is_none() || self.subject != original.subject;
+        let sender_changed = parsed.is_none() || self.sender != original.sender;
+        let recipients_changed = parsed.is_none() || self.recipients != original.recipients;
+        // Discarded or truncated bodies can't be told apart from changed
+        // ones, so those keep the body of `source`.
+        let body_changed = parsed.is_none()
+            || !(self.bodies_discarded
+                || self.body_truncated
+                || (self.plain == original.plain
+                    && self.html == original.html
+                    && same_attachments(&self.attachments, &original.attachments)));
+
+        if !(subject_changed || sender_changed || recipients_changed || body_changed) {
+            return self.source.clone();
+        }
+
+        let mut out = vec![];
+        let mut has_date = false;
+
+        if let Some(parsed) = &parsed {
+            for header in parsed.headers() {
+                let name = header.name().to_ascii_lowercase();
+                let replaced = match name.as_str() {
+                    "subject" => subject_changed,
+                    "from" => sender_changed,
+                    "to" => recipients_changed,
+                    "mime-version" => body_changed,
+                    name => body_changed && name.starts_with("content-"),
+                };
+                has_date |= name == "date";
+
+                if !replaced {
+                    if let Some(raw) = self.source.get(header.offset_field..header.offset_end) {
+                        out.extend_from_slice(raw);
+                    }
+                }
+            }
+        }
+
+        if !has_date {
+            push_header(&mut out, "Date", &Local::now().to_rfc2822());
+        }
+        if sender_changed && !self.sender.is_empty() {
+            push_header(&mut out, "From", &mailbox(&self.sender));
+        }
+        if recipients_changed && !self.recipients.is_empty() {
+            push_header(&mut out, "To", &self.recipients.join(", "));
+        }
+        if subject_changed {
+            push_header(&mut out, "Subject", &encode_word(&self.subject));
+        }
+
+        if body_changed {
+            push_header(&mut out, "MIME-Version", "1.0");
+            out.extend_from_slice(self.mime_body().as_bytes());
+        } else {
+            let body = parsed
+                .as_ref()
+                .and_then(|parsed| parsed.parts.first())
+                .map_or(self.source.len(), |part| part.offset_body);
+            out.extend_from_slice(b"\r\n");
+            out.extend_from_slice(self.source.get(body..).unwrap_or_default());
+        }
+
+        out
+    }
+
+    /// Content headers, blank line and body of the top-level entity.
+    fn mime_body(&self) -> String {
+        let plain = self
+            .plain
+            .as_ref()
+            .filter(|_| !self.plain_derived || self.html.is_none())
+            .map(|body| text_part("text/plain", body));
+        let html = self.html.as_ref().map(|body| text_part("text/html", body));
+
+        let text = match (plain, html) {
+            (Some(plain), Some(html)) => multipart("alternative", &[plain, html]),
+            (Some(part), None) | (None, Some(part)) => part,
+            (None, None) => text_part("text/plain", ""),
+        };
+
+        if self.attachments.is_empty() {
+            return text;
+        }
+
+        let mut parts = vec![text];
+        parts.extend(self.attachments.iter().map(attachment_part));
+
+        multipart("mixed", &parts)
+    }
+}
+
+fn same_attachments(left: &[Attachment], right: &[Attachment]) -> bool {
+    left.len() == right.len()
+        && left.iter().zip(right).all(|(left, right)| {
+            left.filename == right.filename
+                && left.mime_type == right.mime_type
+                && left.content_id == right.content_id
+                && left.body == right.body
+        })
+}
+
+fn push_header(out: &mut Vec<u8>, name: &str, value: &str) {
+    out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+}
+
+/// `sender` is `name address` or just the address.
+fn mailbox(sender: &str) -> String {
+    match sender.rsplit_once(' ') {
+        Some((name, address)) if address.contains('@') => {
+            format!("{} <{}>", encode_word(name), address)
+        }
+        _ => sender.to_owned(),
+    }
+}
+
+/// RFC 2047 encoded word for header text that isn't plain ASCII.
+fn encode_word(text: &str) -> String {
+    if text.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return text.to_owned();
+    }
+
+    format!("=?utf-8?B?{}?=", STANDARD.encode(text))
+}
+
+fn text_part(content_type: &str, body: &str) -> String {
+    format!(
+        "Content-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+        content_type,
+        base64_lines(body.as_bytes())
+    )
+}
+
+fn attachment_part(attachment: &Attachment) -> String {
+    let mut headers = format!(
+        "Content-Type: {}; name=\"{}\"\r\n",
+        attachment.mime_type, attachment.filename
+    );
+    match &attachment.content_id {
+        Some(content_id) => {
+            headers.push_str(&format!(
+                "Content-Disposition: inline; filename=\"{}\"\r\n",
+                attachment.filename
+            ));
+            headers.push_str(&format!("Content-ID: <{}>\r\n", content_id));
+        }
+        None => headers.push_str(&format!(
+            "Content-Disposition: attachment; filename=\"{}\"\r\n",
+            attachment.filename
+        )),
+    }
+
+    format!(
+        "{}Content-Transfer-Encoding: base64\r\n\r\n{}",
+        headers,
+        base64_lines(&attachment.body)
+    )
+}
+
+fn base64_lines(body: &[u8]) -> String {
+    let encoded = STANDARD.encode(body);
+
+    encoded
+        .as_bytes()
+        .chunks(BASE64_LINE_LENGTH)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+fn multipart(subtype: &str, parts: &[String]) -> String {
+    let boundary = format!("mailtutan-{}", Uuid::new_v4());
+    let mut out = format!(
+        "Content-Type: multipart/{}; boundary=\"{}\"\r\n\r\n",
+        subtype, boundary
+    );
+
+    for part in parts {
+        out.push_str(&format!("--{}\r\n{}\r\n", boundary, part));
+    }
+
+    out.push_str(&format!("--{}--\r\n", boundary));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MessageBuilder;
+
+    #[test]
+    fn test_unmodified_message_keeps_source() {
+        let data = MessageBuilder::new()
+            .from("sender@example.com")
+            .subject("Unchanged")
+            .plain("hello")
+            .build();
+        let message = Message::try_from(&data).unwrap();
+
+        assert_eq!(message.to_rfc5322(), data);
+    }
+
+    #[test]
+    fn test_modified_subject_round_trips() {
+        let data = MessageBuilder::new()
+            .from("Sender <sender@example.com>")
+            .to("rcpt@example.com")
+            .header("X-Trace", "kept")
+            .subject("Original")
+            .plain("hello")
+            .attachment("report.pdf", "application/pdf", b"%PDF-1.4 fake")
+            .build();
+        let mut message = Message::try_from(&data).unwrap();
+        message.subject = "Überarbeitet: Original".to_owned();
+
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let reparsed = Message::parse(&message.to_rfc5322(), &strict).unwrap();
+        assert_eq!(reparsed.subject, "Überarbeitet: Original");
+        assert_eq!(reparsed.sender, message.sender);
+        assert_eq!(reparsed.recipients, vec!["rcpt@example.com"]);
+        assert_eq!(reparsed.plain.as_deref(), Some("hello"));
+        assert_eq!(reparsed.attachments[0].body, b"%PDF-1.4 fake");
+
+        let source = String::from_utf8(message.to_rfc5322()).unwrap();
+        assert!(source.contains("X-Trace: kept\r\n"));
+        assert_eq!(source.matches("Subject:").count(), 1);
+    }
+
+    #[test]
+    fn test_modified_body_is_rebuilt() {
+        let data = MessageBuilder::new()
+            .from("sender@example.com")
+            .subject("Body")
+            .plain("before")
+            .build();
+        let mut message = Message::try_from(&data).unwrap();
+        message.plain = Some("after".to_owned());
+        message.html = Some("<p>after</p>".to_owned());
+
+        let reparsed = Message::try_from(&message.to_rfc5322()).unwrap();
+        assert_eq!(reparsed.subject, "Body");
+        assert_eq!(reparsed.plain.as_deref(), Some("after"));
+        assert_eq!(reparsed.html.as_deref(), Some("<p>after</p>"));
+    }
+
+    #[test]
+    fn test_message_without_source() {
+        let message = Message {
+            sender: "sender@example.com".to_owned(),
+            recipients: vec!["rcpt@example.com".to_owned()],
+            subject: "Built".to_owned(),
+            plain: Some("hello".to_owned()),
+            ..Default::default()
+        };
+
+        let reparsed = Message::try_from(&message.to_rfc5322()).unwrap();
+        assert_eq!(reparsed.subject, "Built");
+        assert_eq!(reparsed.sender, "sender@example.com");
+        assert_eq!(reparsed.plain.as_deref(), Some("hello"));
+    }
+}
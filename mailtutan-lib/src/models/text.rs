@@ -0,0 +1,256 @@
+/// Width `html_to_text` wraps paragraphs at, as in hand-written plain
+/// text mail.
+const TEXT_COLUMNS: usize = 80;
+
+/// Readable text of `html`, rendered by html2text: block elements start new
+/// lines, `script`/`style` contents are skipped, tables are drawn with box
+/// characters, `<pre>` keeps its whitespace and link targets are listed as
+/// numbered references after the text.
+pub fn html_to_text(html: &str) -> String {
+    let text = html2text::from_read(html.as_bytes(), TEXT_COLUMNS);
+
+    text.lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_owned()
+}
+
+/// Hosts that only serve open-tracking images, matched with their
+/// subdomains.
+const TRACKER_DOMAINS: &[&str] = &[
+    "doubleclick.net",
+    "google-analytics.com",
+    "hubspotlinks.com",
+    "list-manage.com",
+    "mailtrack.io",
+    "mandrillapp.com",
+    "mixpanel.com",
+    "sendgrid.net",
+];
+
+/// A transparent 1x1 GIF standing in for neutralized images.
+pub const PIXEL_PLACEHOLDER: &str =
+    "data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7";
+
+/// `html` with the `src` of likely tracking pixels, images sized 1x1 or
+/// served from a known tracker, replaced by `PIXEL_PLACEHOLDER`, so
+/// previewing a message doesn't report it as opened. `cid:` and `data:`
+/// images never leave the server and are kept.
+pub fn neutralize_tracking_pixels(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = find_img(rest) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+        let tag = &rest[..end];
+        rest = &rest[end..];
+
+        match attribute(tag, "src") {
+            Some((range, src)) if is_tracking_pixel(tag, src) => {
+                output.push_str(&tag[..range.start]);
+                output.push_str(PIXEL_PLACEHOLDER);
+                output.push_str(&tag[range.end..]);
+            }
+            _ => output.push_str(tag),
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+fn find_img(html: &str) -> Option<usize> {
+    let lower = html.to_ascii_lowercase();
+    let mut from = 0;
+
+    while let Some(position) = lower[from..].find("<img") {
+        let start = from + position;
+        match lower[start + 4..].chars().next() {
+            Some(c) if c.is_ascii_whitespace() || c == '/' || c == '>' => return Some(start),
+            _ => from = start + 4,
+        }
+    }
+
+    None
+}
+
+fn is_tracking_pixel(tag: &str, src: &str) -> bool {
+    let lower = src.to_ascii_lowercase();
+    if lower.starts_with("cid:") || lower.starts_with("data:") {
+        return false;
+    }
+
+    let dimension = |name: &str| {
+        attribute(tag, name)
+            .map(|(_, value)| value.trim().trim_end_matches("px").to_owned())
+            .or_else(|| style_dimension(tag, name))
+    };
+    let tiny = |value: Option<String>| matches!(value.as_deref(), Some("0" | "1"));
+
+    (tiny(dimension("width")) && tiny(dimension("height"))) || is_tracker(&lower)
+}
+
+/// `width`/`height` given in an inline `style`, in pixels.
+fn style_dimension(tag: &str, name: &str) -> Option<String> {
+    let (_, style) = attribute(tag, "style")?;
+
+    style.split(';').find_map(|declaration| {
+        let (property, value) = declaration.split_once(':')?;
+        (property.trim().eq_ignore_ascii_case(name))
+            .then(|| value.trim().trim_end_matches("px").to_owned())
+    })
+}
+
+fn is_tracker(src: &str) -> bool {
+    let Some(rest) = ["https://", "http://", "//"]
+        .iter()
+        .find_map(|scheme| src.strip_prefix(scheme))
+    else {
+        return false;
+    };
+    let host = rest.split(['/', ':', '?', '#']).next().unwrap_or_default();
+
+    TRACKER_DOMAINS.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// The byte range and value of attribute `name` in the start tag `tag`.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<(std::ops::Range<usize>, &'a str)> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+
+    while let Some(position) = lower[from..].find(name) {
+        let start = from + position;
+        from = start + name.len();
+
+        if !lower[..start].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let after = lower[from..].trim_start();
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value_start = tag.len() - value.trim_start().len();
+
+        let (start, end) = match tag[value_start..].chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let start = value_start + 1;
+                let end = tag[start..]
+                    .find(quote)
+                    .map_or(tag.len(), |end| start + end);
+                (start, end)
+            }
+            _ => {
+                let end = tag[value_start..]
+                    .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                    .map_or(tag.len(), |end| value_start + end);
+                (value_start, end)
+            }
+        };
+
+        return Some((start..end, &tag[start..end]));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_to_text() {
+        let html = concat!(
+            "<html><head><style>p { color: red; }</style></head><body>\n",
+            "<h1>Welcome</h1>\n",
+            "<p>Hello&nbsp;<b>World</b> &amp; friends</p>\n\n\n",
+            "<ul><li>one</li><li>two</li></ul>",
+            "<script>alert('<p>');</script>",
+            "<p>AT&T &#169; &#x263A;</p>",
+            "</body></html>"
+        );
+
+        assert_eq!(
+            html_to_text(html),
+            "# Welcome\n\nHello World & friends\n\n* one\n* two\n\nAT&T © ☺"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_table() {
+        let html = concat!(
+            "<table><tr><th>Item</th><th>Qty</th></tr>",
+            "<tr><td>Apples</td><td>3</td></tr></table>"
+        );
+
+        assert_eq!(
+            html_to_text(html),
+            "──────┬───\nItem  │Qty\n──────┼───\nApples│3\n──────┴───"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_links() {
+        let html = "<p>Read <a href=\"https://example.com/docs\">the docs</a> now.</p>";
+
+        assert_eq!(
+            html_to_text(html),
+            "Read [the docs][1] now.\n\n[1]: https://example.com/docs"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_pre() {
+        let html = "<p>Run:</p><pre>  fn main() {\n      println!(\"hi\");\n  }</pre>";
+
+        assert_eq!(
+            html_to_text(html),
+            "Run:\n\n  fn main() {\n      println!(\"hi\");\n  }"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_quoted_angle_bracket() {
+        let html = "<p title=\"a > b\" data-x='<b>'>kept whole</p>";
+
+        assert_eq!(html_to_text(html), "kept whole");
+    }
+
+    #[test]
+    fn test_neutralize_tracking_pixels() {
+        let html = concat!(
+            "<p>Hi</p>",
+            "<img src=\"https://news.example.com/open.gif?u=1\" width=\"1\" height=\"1\">",
+            "<IMG style=\"width: 1px; height: 1px\" SRC='https://cdn.example.com/o.png'>",
+            "<img src=\"https://t.sendgrid.net/wf/open?upn=x\">",
+            "<img src=\"https://cdn.example.com/logo.png\" width=\"120\" height=\"40\">",
+            "<img src=\"cid:spacer@example.com\" width=\"1\" height=\"1\">",
+            "<imgx src=\"https://news.example.com/open.gif\" width=\"1\" height=\"1\">"
+        );
+
+        assert_eq!(
+            neutralize_tracking_pixels(html),
+            format!(
+                concat!(
+                    "<p>Hi</p>",
+                    "<img src=\"{0}\" width=\"1\" height=\"1\">",
+                    "<IMG style=\"width: 1px; height: 1px\" SRC='{0}'>",
+                    "<img src=\"{0}\">",
+                    "<img src=\"https://cdn.example.com/logo.png\" width=\"120\" height=\"40\">",
+                    "<img src=\"cid:spacer@example.com\" width=\"1\" height=\"1\">",
+                    "<imgx src=\"https://news.example.com/open.gif\" width=\"1\" height=\"1\">"
+                ),
+                PIXEL_PLACEHOLDER
+            )
+        );
+    }
+}
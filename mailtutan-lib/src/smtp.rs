@@ -1,61 +1,747 @@
-use crate::models::Message;
-use crate::models::MessageEvent;
-use crate::APP;
-use mailin_embedded::{Handler, Server};
+use crate::{AppState, Mailtutan, Result};
 use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
 
-#[derive(Clone)]
-struct MyHandler {
-    pub data: Vec<u8>,
+mod auth;
+mod greylist;
+mod proxy;
+mod rate_limit;
+mod rcpt_delay;
+mod relay;
+mod response;
+mod session;
+mod tls;
+mod workers;
+pub use auth::Mechanism;
+pub use greylist::Greylist;
+pub use rate_limit::RateLimiter;
+pub use rcpt_delay::RcptDelay;
+pub use relay::Forward;
+pub use response::Response;
+pub use session::Session;
+pub use tls::{acceptor, acceptor_from_pem};
+pub use workers::{Job, ParsePool};
+
+/// Name the server announces in its greeting and EHLO replies.
+pub const HOSTNAME: &str = "mailtutan";
+
+const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
+
+/// Checks the domain of a MAIL FROM path against the configured allow and
+/// deny lists.
+pub fn sender_allowed(app: &Mailtutan, sender: &str) -> bool {
+    let domain = sender.rsplit_once('@').map(|(_, domain)| domain);
+    let listed = |list: &[String]| {
+        domain.is_some_and(|domain| list.iter().any(|item| item.eq_ignore_ascii_case(domain)))
+    };
+
+    if listed(&app.sender_denylist) {
+        return false;
+    }
+
+    app.sender_allowlist.is_empty() || listed(&app.sender_allowlist)
 }
 
-impl Handler for MyHandler {
-    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
-        // dbg!("data received");
-        self.data.append(&mut buf.to_owned());
+/// EHLO extensions advertised for the given configuration.
+pub fn capabilities(app: &Mailtutan) -> Vec<String> {
+    let mut capabilities = vec!["8BITMIME".to_owned(), "SMTPUTF8".to_owned()];
 
-        Ok(())
+    if app.smtp_auth {
+        let names: Vec<&str> = Mechanism::ALL.iter().map(|m| m.name()).collect();
+        capabilities.push(format!("AUTH {}", names.join(" ")));
     }
 
-    fn data_end(&mut self) -> mailin_embedded::Response {
-        // dbg!("data end");
-        let message = Message::from(&self.data);
+    if app.tls.is_some() || app.starttls_failure {
+        capabilities.push("STARTTLS".to_owned());
+    }
 
-        let msg = APP
-            .get()
-            .expect("get app")
-            .lock()
-            .expect("get lock")
-            .storage
-            .add(message);
+    if app.xforward {
+        capabilities.push("XFORWARD NAME ADDR PROTO HELO".to_owned());
+    }
+
+    capabilities
+}
+
+pub async fn serve(state: AppState) -> Result<()> {
+    let uris = state.lock().unwrap().get_smtp_uris();
+    let mut listeners = vec![];
+
+    for uri in uris {
+        listeners.push(TcpListener::bind(&uri).await?);
+
+        println!("listening on smtp://{}", &uri);
+    }
+
+    serve_listeners(listeners, state).await
+}
+
+/// Accepts connections on every listener, all feeding the same app.
+pub async fn serve_listeners(listeners: Vec<TcpListener>, state: AppState) -> Result<()> {
+    state.lock().unwrap().smtp_ready = true;
+
+    let accepting = listeners.into_iter().map(|listener| {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, remote)) => {
+                        tokio::spawn(handle_connection(stream, remote, state.clone()));
+                    }
+                    Err(err) => eprintln!("smtp accept failed: {}", err),
+                }
+            }
+        })
+    });
+
+    futures::future::join_all(accepting).await;
+
+    Ok(())
+}
+
+async fn handle_connection<S>(stream: S, remote: SocketAddr, state: AppState) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reverse_dns, acceptor, proxy_protocol) = {
+        let app = state.lock().unwrap();
+        (app.reverse_dns, app.tls.clone(), app.proxy_protocol)
+    };
+
+    let mut stream = stream;
+    let remote = if proxy_protocol {
+        timeout(FIVE_MINUTES, proxy::read_header(&mut stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??
+            .unwrap_or(remote)
+    } else {
+        remote
+    };
+
+    let mut session = Session::new(state, remote);
+
+    if reverse_dns {
+        let ip = remote.ip();
+        let host = tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip).ok())
+            .await
+            .ok()
+            .flatten();
+        session.set_client_host(host);
+    }
+
+    stream.write_all(&session.greeting().to_bytes()).await?;
 
-        let event = MessageEvent {
-            event_type: "add".to_owned(),
-            message: msg,
+    let Some(stream) = converse(stream, &mut session).await? else {
+        return Ok(());
+    };
+    let Some(acceptor) = acceptor else {
+        return Ok(());
+    };
+
+    let stream = acceptor.accept(stream).await?;
+    let (version, cipher) = tls::negotiated(stream.get_ref().1);
+    session.set_tls(version, cipher);
+
+    converse(stream, &mut session).await?;
+
+    Ok(())
+}
+
+/// Runs the command loop until the client is done, or until STARTTLS was
+/// accepted, in which case the plain stream is handed back for the
+/// handshake.
+async fn converse<S>(stream: S, session: &mut Session) -> io::Result<Option<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::with_capacity(80);
+
+    loop {
+        let read = read_line(&mut reader, &mut line, session.max_line_length());
+
+        let too_long = timeout(FIVE_MINUTES, read)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        let response = if too_long {
+            session.line_too_long()
+        } else if line.is_empty() {
+            break;
+        } else {
+            session.process(&line).await
         };
 
-        APP.get()
-            .expect("get app")
-            .lock()
-            .expect("get lock")
-            .ws_sender
-            .clone()
-            .send(serde_json::to_string(&event).unwrap())
-            .ok();
+        if session.chaos_drop() {
+            break;
+        }
+
+        let response = match session.take_forward() {
+            Some(forward) => Some(forward.send().await),
+            None => response,
+        };
+
+        if let Some(delay) = session.take_delay() {
+            tokio::time::sleep(delay).await;
+        }
+
+        if let Some(response) = response {
+            writer.write_all(&response.to_bytes()).await?;
+
+            if response.closes_connection() {
+                break;
+            }
+        }
+
+        if session.take_starttls() {
+            // Anything pipelined after STARTTLS was sent in the clear and
+            // must not be trusted, so it's dropped with the buffer.
+            return Ok(Some(reader.into_inner().unsplit(writer)));
+        }
+    }
+
+    // Lets TLS clients see a proper close_notify.
+    writer.shutdown().await.ok();
+
+    Ok(None)
+}
 
-        mailin_embedded::response::OK
+/// Reads one line into `line`, never buffering more than `max_length` bytes.
+///
+/// The remainder of an over-long line is consumed and discarded, in which
+/// case `line` is left empty and `true` is returned.
+async fn read_line<R>(reader: &mut R, line: &mut Vec<u8>, max_length: usize) -> io::Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut too_long = false;
+    line.clear();
+
+    loop {
+        let available = reader.fill_buf().await?;
+
+        if available.is_empty() {
+            break;
+        }
+
+        let (found, used) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(index) => (true, index + 1),
+            None => (false, available.len()),
+        };
+
+        if !too_long {
+            if line.len() + used > max_length {
+                too_long = true;
+                line.clear();
+            } else {
+                line.extend_from_slice(&available[..used]);
+            }
+        }
+
+        reader.consume(used);
+
+        if found {
+            break;
+        }
     }
+
+    Ok(too_long)
 }
 
-pub async fn serve() {
-    let handler = MyHandler { data: vec![] };
-    let mut server = Server::new(handler);
+#[cfg(test)]
+mod tests {
+    use super::{handle_connection, serve_listeners};
+    use crate::Mailtutan;
+    use tokio::io::{
+        duplex, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    };
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_long_header_line_is_rejected() {
+        let state = Mailtutan {
+            max_line_length: 100,
+            ..Default::default()
+        }
+        .init();
+
+        let (mut client, server) = duplex(64 * 1024);
+        let peer = "127.0.0.1:2525".parse().unwrap();
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+
+        let input = format!(
+            concat!(
+                "EHLO client.example\r\n",
+                "MAIL FROM:<sender@example.com>\r\n",
+                "RCPT TO:<rcpt@example.com>\r\n",
+                "DATA\r\n",
+                "Subject: {}\r\n",
+                "\r\n",
+                "body\r\n",
+                ".\r\n",
+                "NOOP\r\n",
+                "QUIT\r\n"
+            ),
+            "x".repeat(10_000)
+        );
+        client.write_all(input.as_bytes()).await.unwrap();
 
-    let uri = APP.get().unwrap().lock().unwrap().get_smtp_uri();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        let codes: Vec<&str> = output.lines().map(|line| &line[..4]).collect();
 
-    server.with_addr(&uri).unwrap();
+        assert_eq!(
+            codes,
+            vec!["220 ", "250-", "250-", "250 ", "250 ", "250 ", "354 ", "552 ", "250 ", "221 "]
+        );
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+    }
+
+    /// Sends `command` and reads the last line of its reply, with the time
+    /// it took to arrive.
+    async fn reply<R, W>(reader: &mut R, writer: &mut W, command: &str) -> (String, Duration)
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(command.as_bytes()).await.unwrap();
+        let started = Instant::now();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return (line, started.elapsed());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_delay() {
+        let state = Mailtutan {
+            rcpt_delays: vec!["*@slow.example.com=300".parse().unwrap()],
+            ..Default::default()
+        }
+        .init();
+
+        let (client, server) = duplex(64 * 1024);
+        let peer = "127.0.0.1:2525".parse().unwrap();
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = tokio::io::BufReader::new(reader);
+        reader.read_line(&mut String::new()).await.unwrap();
+        reply(&mut reader, &mut writer, "EHLO client.example\r\n").await;
+        reply(
+            &mut reader,
+            &mut writer,
+            "MAIL FROM:<sender@example.com>\r\n",
+        )
+        .await;
+
+        let (line, elapsed) =
+            reply(&mut reader, &mut writer, "RCPT TO:<rcpt@example.com>\r\n").await;
+        assert!(line.starts_with("250 "));
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+
+        // Other connections are served in the meantime.
+        let (mut other, server) = duplex(1024);
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+        let greeted = tokio::spawn(async move {
+            let mut greeting = [0; 4];
+            other.read_exact(&mut greeting).await.unwrap();
+            Instant::now()
+        });
+
+        let (line, elapsed) = reply(
+            &mut reader,
+            &mut writer,
+            "RCPT TO:<someone@slow.example.com>\r\n",
+        )
+        .await;
+        assert!(line.starts_with("250 "));
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(greeted.await.unwrap() < Instant::now() - Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_client_address() {
+        let state = Mailtutan {
+            proxy_protocol: true,
+            ..Default::default()
+        }
+        .init();
+
+        let (mut client, server) = duplex(64 * 1024);
+        let peer = "10.0.0.5:40000".parse().unwrap();
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+
+        let input = concat!(
+            "PROXY TCP4 203.0.113.7 10.0.0.1 51000 25\r\n",
+            "EHLO client.example\r\n",
+            "MAIL FROM:<sender@example.com>\r\n",
+            "RCPT TO:<rcpt@example.com>\r\n",
+            "DATA\r\n",
+            "Subject: proxied\r\n",
+            "\r\n",
+            "body\r\n",
+            ".\r\n",
+            "QUIT\r\n"
+        );
+        client.write_all(input.as_bytes()).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(!output.contains("500 "));
+        assert!(output.ends_with("221 Bye\r\n"));
+
+        let list = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(list[0].client_ip.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[tokio::test]
+    async fn test_transactions_on_one_connection_are_independent() {
+        let state = Mailtutan::default().init();
+
+        let (mut client, server) = duplex(64 * 1024);
+        let peer = "127.0.0.1:2525".parse().unwrap();
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+
+        let input = concat!(
+            "EHLO client.example\r\n",
+            "MAIL FROM:<first@example.com>\r\n",
+            "RCPT TO:<a@example.com>\r\n",
+            "RCPT TO:<b@example.com>\r\n",
+            "DATA\r\n",
+            "Subject: first\r\n",
+            "\r\n",
+            "one\r\n",
+            ".\r\n",
+            "MAIL FROM:<second@example.com>\r\n",
+            "RCPT TO:<c@example.com>\r\n",
+            "DATA\r\n",
+            "Subject: second\r\n",
+            "\r\n",
+            "two\r\n",
+            ".\r\n",
+            "QUIT\r\n"
+        );
+        client.write_all(input.as_bytes()).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(output.ends_with("221 Bye\r\n"));
+
+        let mut list = state.lock().unwrap().storage.list().unwrap();
+        list.sort_by_key(|message| message.id);
+
+        let stored: Vec<(&str, &str, Vec<&str>, &[u8])> = list
+            .iter()
+            .map(|message| {
+                (
+                    message.subject.as_str(),
+                    message.envelope_sender.as_str(),
+                    message
+                        .envelope_recipients
+                        .iter()
+                        .map(String::as_str)
+                        .collect(),
+                    message.source.as_slice(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            stored,
+            vec![
+                (
+                    "first",
+                    "first@example.com",
+                    vec!["a@example.com", "b@example.com"],
+                    &b"Subject: first\r\n\r\none\r\n"[..]
+                ),
+                (
+                    "second",
+                    "second@example.com",
+                    vec!["c@example.com"],
+                    &b"Subject: second\r\n\r\ntwo\r\n"[..]
+                ),
+            ]
+        );
+        assert_ne!(list[0].id, list[1].id);
+    }
+
+    #[tokio::test]
+    async fn test_relay_forwards_and_propagates_reply() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            stream.write_all(b"220 upstream ESMTP\r\n").await.unwrap();
+
+            let mut transcript = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    return transcript;
+                }
+                transcript.push_str(&line);
+
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 2.0.0 Ok: queued as UPSTREAM42\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).await.unwrap();
+            }
+        });
+
+        let state = Mailtutan {
+            relay: Some(upstream_addr.to_string()),
+            ..Default::default()
+        }
+        .init();
+
+        let (mut client, server) = duplex(64 * 1024);
+        let peer = "127.0.0.1:2525".parse().unwrap();
+        tokio::spawn(handle_connection(server, peer, state.clone()));
 
-    println!("listening on smtp://{}", &uri);
+        let input = concat!(
+            "EHLO client.example\r\n",
+            "MAIL FROM:<sender@example.com>\r\n",
+            "RCPT TO:<rcpt@example.com>\r\n",
+            "DATA\r\n",
+            "Subject: relayed\r\n",
+            "\r\n",
+            "..dot line\r\n",
+            ".\r\n",
+            "QUIT\r\n"
+        );
+        client.write_all(input.as_bytes()).await.unwrap();
 
-    server.serve().unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(output.contains("\r\n250 2.0.0 Ok: queued as UPSTREAM42\r\n"));
+        assert_eq!(state.lock().unwrap().storage.size(), 1);
+
+        let transcript = received.await.unwrap();
+        assert!(transcript.contains("MAIL FROM:<sender@example.com>\r\n"));
+        assert!(transcript.contains("RCPT TO:<rcpt@example.com>\r\n"));
+        assert!(transcript.contains("Subject: relayed\r\n\r\n..dot line\r\n.\r\n"));
+    }
+
+    /// Reads one full, possibly multiline, reply.
+    async fn read_reply<R: AsyncReadExt + Unpin>(reader: &mut R) -> String {
+        let mut reply = vec![];
+
+        loop {
+            let mut byte = [0; 1];
+            reader.read_exact(&mut byte).await.unwrap();
+            reply.push(byte[0]);
+
+            let text = String::from_utf8_lossy(&reply);
+            let last_line = text.trim_end_matches("\r\n").rsplit("\r\n").next().unwrap();
+            if text.ends_with("\r\n") && last_line.as_bytes().get(3) == Some(&b' ') {
+                return text.into_owned();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_starttls_records_negotiated_parameters() {
+        use std::sync::Arc;
+        use tokio_rustls::rustls::{self, Certificate, RootCertStore, ServerName};
+        use tokio_rustls::TlsConnector;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let acceptor = super::acceptor_from_pem(
+            cert.serialize_pem().unwrap().as_bytes(),
+            cert.serialize_private_key_pem().as_bytes(),
+        )
+        .unwrap();
+
+        let state = Mailtutan {
+            tls: Some(acceptor),
+            ..Default::default()
+        }
+        .init();
+
+        let (mut client, server) = duplex(64 * 1024);
+        let peer = "127.0.0.1:2525".parse().unwrap();
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+
+        read_reply(&mut client).await;
+        client.write_all(b"EHLO client.example\r\n").await.unwrap();
+        assert!(read_reply(&mut client).await.contains("250 STARTTLS\r\n"));
+        client.write_all(b"STARTTLS\r\n").await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with("220 "));
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut client = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+
+        client.write_all(b"EHLO client.example\r\n").await.unwrap();
+        let ehlo = read_reply(&mut client).await;
+        assert!(!ehlo.contains("STARTTLS"));
+
+        client
+            .write_all(
+                concat!(
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<rcpt@example.com>\r\n",
+                    "DATA\r\n",
+                    "Subject: encrypted\r\n",
+                    "\r\n",
+                    "body\r\n",
+                    ".\r\n",
+                    "QUIT\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(output.ends_with("221 Bye\r\n"));
+
+        let message = state.lock().unwrap().storage.get(1).unwrap();
+        assert_eq!(message.tls_version.as_deref(), Some("TLSv1.3"));
+        assert!(message.tls_cipher.unwrap().starts_with("TLS13_"));
+
+        let state = Mailtutan::default().init();
+        let (mut client, server) = duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, peer, state.clone()));
+        client
+            .write_all(
+                concat!(
+                    "EHLO client.example\r\n",
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<rcpt@example.com>\r\n",
+                    "DATA\r\n",
+                    "\r\n",
+                    ".\r\n",
+                    "QUIT\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(!output.contains("STARTTLS"));
+        assert_eq!(
+            state.lock().unwrap().storage.get(1).unwrap().tls_version,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chaos_drops_connection_mid_data() {
+        let state = Mailtutan {
+            chaos_drop_after: Some(64),
+            ..Default::default()
+        }
+        .init();
+
+        let (mut client, server) = duplex(64 * 1024);
+        let peer = "127.0.0.1:2525".parse().unwrap();
+        let connection = tokio::spawn(handle_connection(server, peer, state.clone()));
+
+        let input = format!(
+            concat!(
+                "EHLO client.example\r\n",
+                "MAIL FROM:<sender@example.com>\r\n",
+                "RCPT TO:<rcpt@example.com>\r\n",
+                "DATA\r\n",
+                "Subject: partial\r\n",
+                "\r\n",
+                "{}\r\n",
+                ".\r\n",
+                "QUIT\r\n"
+            ),
+            "x".repeat(100)
+        );
+        client.write_all(input.as_bytes()).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        let codes: Vec<&str> = output.lines().map(|line| &line[..4]).collect();
+
+        assert_eq!(
+            codes,
+            vec!["220 ", "250-", "250-", "250 ", "250 ", "250 ", "354 "]
+        );
+        assert!(connection.await.unwrap().is_ok());
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_several_ports_share_storage() {
+        let state = Mailtutan::default().init();
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        tokio::spawn(serve_listeners(vec![first, second], state.clone()));
+
+        for (i, addr) in addrs.iter().enumerate() {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let input = format!(
+                concat!(
+                    "EHLO client.example\r\n",
+                    "MAIL FROM:<sender@example.com>\r\n",
+                    "RCPT TO:<rcpt@example.com>\r\n",
+                    "DATA\r\n",
+                    "Subject: port {}\r\n",
+                    "\r\n",
+                    ".\r\n",
+                    "QUIT\r\n"
+                ),
+                i
+            );
+            client.write_all(input.as_bytes()).await.unwrap();
+
+            let mut output = String::new();
+            client.read_to_string(&mut output).await.unwrap();
+            assert!(output.ends_with("221 Bye\r\n"));
+        }
+
+        let mut subjects: Vec<String> = state
+            .lock()
+            .unwrap()
+            .storage
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|message| message.subject)
+            .collect();
+        subjects.sort();
+        assert_eq!(subjects, vec!["port 0", "port 1"]);
+    }
 }
@@ -0,0 +1,97 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+
+/// SASL mechanisms accepted by the `AUTH` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    Plain,
+    Login,
+    CramMd5,
+}
+
+impl Mechanism {
+    pub const ALL: &'static [Mechanism] = &[Mechanism::Plain, Mechanism::Login, Mechanism::CramMd5];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::Login => "LOGIN",
+            Mechanism::CramMd5 => "CRAM-MD5",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|mechanism| mechanism.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Step of an `AUTH` exchange that is waiting for a client response.
+pub(super) enum Exchange {
+    Plain,
+    LoginUsername,
+    LoginPassword { username: String },
+    CramMd5 { challenge: String },
+}
+
+pub(super) fn encode(text: &str) -> String {
+    STANDARD.encode(text)
+}
+
+pub(super) fn decode(text: &str) -> Option<String> {
+    STANDARD
+        .decode(text.trim())
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+/// Decodes a PLAIN response (`authzid NUL authcid NUL passwd`) into the
+/// username and password.
+pub(super) fn decode_plain(text: &str) -> Option<(String, String)> {
+    let decoded = decode(text)?;
+    let mut fields = decoded.split('\0').skip(1);
+
+    Some((fields.next()?.to_owned(), fields.next()?.to_owned()))
+}
+
+/// Decodes a CRAM-MD5 response (`username SP hex-digest`, RFC 2195).
+pub(super) fn decode_cram_md5(text: &str) -> Option<(String, String)> {
+    let decoded = decode(text)?;
+    let (username, digest) = decoded.rsplit_once(' ')?;
+
+    Some((username.to_owned(), digest.to_ascii_lowercase()))
+}
+
+/// The digest a client knowing `password` answers `challenge` with.
+pub(super) fn cram_md5_digest(password: &str, challenge: &str) -> String {
+    let mut mac =
+        Hmac::<Md5>::new_from_slice(password.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(challenge.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cram_md5_digest() {
+        // The example exchange of RFC 2195.
+        assert_eq!(
+            cram_md5_digest(
+                "tanstaaftanstaaf",
+                "<1896.697170952@postoffice.reston.mci.net>"
+            ),
+            "b913a602c7eda7a495b4e6e7334d3890"
+        );
+    }
+}
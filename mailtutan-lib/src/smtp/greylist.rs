@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Greylisting simulation keyed on the (client ip, sender, recipient)
+/// triplet.
+///
+/// The first delivery attempt for a triplet is deferred. Retries are deferred
+/// until `delay` has passed since that first attempt and accepted afterwards.
+#[derive(Debug, Clone)]
+pub struct Greylist {
+    delay: Duration,
+    first_seen: HashMap<(IpAddr, String, String), Instant>,
+}
+
+impl Greylist {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            first_seen: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` when the delivery must be deferred.
+    pub fn defer(&mut self, ip: IpAddr, sender: &str, recipients: &[String]) -> bool {
+        let now = Instant::now();
+        let mut defer = false;
+
+        for recipient in recipients {
+            let key = (ip, sender.to_lowercase(), recipient.to_lowercase());
+            match self.first_seen.get(&key) {
+                Some(first_seen) if now.duration_since(*first_seen) >= self.delay => {}
+                Some(_) => defer = true,
+                None => {
+                    self.first_seen.insert(key, now);
+                    defer = true;
+                }
+            }
+        }
+
+        defer
+    }
+}
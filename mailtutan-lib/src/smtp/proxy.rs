@@ -0,0 +1,135 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature opening a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads the PROXY protocol header (version 1 or 2) a load balancer sends
+/// ahead of the proxied connection and returns the client address it
+/// names. `None` for `UNKNOWN`/`LOCAL` headers, which don't carry one.
+///
+/// Exactly the header is consumed, so SMTP can continue on `stream`.
+pub async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LENGTH {
+                return Err(invalid("PROXY header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+
+        return parse_v1(&String::from_utf8_lossy(&line));
+    }
+
+    if start != V2_SIGNATURE[..5] {
+        return Err(invalid("missing PROXY header"));
+    }
+
+    let mut header = [0; 11];
+    stream.read_exact(&mut header).await?;
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("bad PROXY v2 signature"));
+    }
+
+    let (version_command, family) = (header[7], header[8]);
+    let length = u16::from_be_bytes([header[9], header[10]]) as usize;
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY version"));
+    }
+
+    parse_v2(version_command & 0x0f, family, &addresses)
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad PROXY address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("bad PROXY port"))?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    // LOCAL: a health check by the proxy itself.
+    if command == 0 {
+        return Ok(None);
+    }
+
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+    match family {
+        // TCP or UDP over IPv4: source, destination, source port, destination port.
+        0x11 | 0x12 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        0x21 | 0x22 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        0x00 => Ok(None),
+        _ => Err(invalid("unsupported PROXY address family")),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut v1: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 25\r\nEHLO";
+        assert_eq!(
+            read_header(&mut v1).await.unwrap(),
+            Some("[2001:db8::1]:51000".parse().unwrap())
+        );
+        assert_eq!(v1, b"EHLO");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        v2.extend_from_slice(&51000u16.to_be_bytes());
+        v2.extend_from_slice(&25u16.to_be_bytes());
+        v2.extend_from_slice(b"EHLO");
+        let mut stream = v2.as_slice();
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("203.0.113.7:51000".parse().unwrap())
+        );
+        assert_eq!(stream, b"EHLO");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut unknown).await.unwrap(), None);
+
+        let mut smtp: &[u8] = b"EHLO client.example\r\n";
+        assert!(read_header(&mut smtp).await.is_err());
+    }
+}
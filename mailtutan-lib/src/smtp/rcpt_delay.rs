@@ -0,0 +1,92 @@
+use crate::{Error, Result};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Holds back the reply to RCPT TO, written as `500` (milliseconds, for
+/// every recipient) or `pattern=500`. In the pattern `*` matches any run of
+/// characters, e.g. `*@slow.example.com=2000`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RcptDelay {
+    /// Recipients the delay applies to, all of them when `None`.
+    pub pattern: Option<String>,
+    pub delay: Duration,
+}
+
+impl RcptDelay {
+    /// Delay of the first entry matching `recipient`.
+    pub fn find(delays: &[Self], recipient: &str) -> Option<Duration> {
+        delays
+            .iter()
+            .find(|delay| delay.matches(recipient))
+            .map(|delay| delay.delay)
+    }
+
+    pub fn matches(&self, recipient: &str) -> bool {
+        self.pattern.as_deref().is_none_or(|pattern| {
+            glob(
+                &pattern.to_ascii_lowercase(),
+                &recipient.to_ascii_lowercase(),
+            )
+        })
+    }
+}
+
+impl FromStr for RcptDelay {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid RCPT delay {}", value));
+
+        let (pattern, millis) = match value.rsplit_once('=') {
+            Some(("", _)) => return Err(invalid()),
+            Some((pattern, millis)) => (Some(pattern.to_owned()), millis),
+            None => (None, value),
+        };
+        let millis: u64 = millis.trim().parse().map_err(|_| invalid())?;
+
+        Ok(Self {
+            pattern,
+            delay: Duration::from_millis(millis),
+        })
+    }
+}
+
+fn glob(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+
+            (0..=text.len())
+                .filter(|start| text.is_char_boundary(*start))
+                .any(|start| glob(rest, &text[start..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match() {
+        let delays: Vec<RcptDelay> = ["*@slow.example.com=2000", "stuck@example.com=500", "10"]
+            .iter()
+            .map(|value| value.parse().unwrap())
+            .collect();
+
+        let find = |recipient| RcptDelay::find(&delays, recipient);
+        assert_eq!(
+            find("Someone@Slow.example.com"),
+            Some(Duration::from_millis(2000))
+        );
+        assert_eq!(find("stuck@example.com"), Some(Duration::from_millis(500)));
+        assert_eq!(find("other@example.com"), Some(Duration::from_millis(10)));
+        assert_eq!(RcptDelay::find(&delays[..2], "other@example.com"), None);
+
+        assert!("=10".parse::<RcptDelay>().is_err());
+        assert!("stuck@example.com=soon".parse::<RcptDelay>().is_err());
+    }
+}
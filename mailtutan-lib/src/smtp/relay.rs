@@ -0,0 +1,137 @@
+use super::{Response, HOSTNAME};
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// A captured transaction to hand on to the upstream server.
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub upstream: String,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+impl Forward {
+    /// Delivers the message upstream and returns the reply to pass back to
+    /// the client: the reply to DATA, or the first one refusing the
+    /// transaction. Connection problems are reported as 451.
+    pub async fn send(&self) -> Response {
+        let result = match TcpStream::connect(&self.upstream).await {
+            Ok(stream) => self.deliver(stream).await,
+            Err(err) => Err(err),
+        };
+
+        result.unwrap_or_else(|err| {
+            eprintln!("relaying to {} failed: {}", self.upstream, err);
+            Response::new(451, "Upstream relay failed, try again later")
+        })
+    }
+
+    async fn deliver<S>(&self, stream: S) -> io::Result<Response>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+
+        let greeting = read_response(&mut stream).await?;
+        if greeting.is_error() {
+            return Ok(greeting);
+        }
+
+        let smtputf8 = !self.sender.is_ascii()
+            || self
+                .recipients
+                .iter()
+                .any(|recipient| !recipient.is_ascii());
+        let mut commands = vec![
+            format!("EHLO {}", HOSTNAME),
+            format!(
+                "MAIL FROM:<{}>{}",
+                self.sender,
+                if smtputf8 { " SMTPUTF8" } else { "" }
+            ),
+        ];
+        commands.extend(
+            self.recipients
+                .iter()
+                .map(|recipient| format!("RCPT TO:<{}>", recipient)),
+        );
+        commands.push("DATA".to_owned());
+
+        for command in commands {
+            stream
+                .write_all(format!("{}\r\n", command).as_bytes())
+                .await?;
+
+            let response = read_response(&mut stream).await?;
+            if response.is_error() {
+                return Ok(response);
+            }
+        }
+
+        stream.write_all(&dot_stuff(&self.data)).await?;
+        let response = read_response(&mut stream).await?;
+
+        stream.write_all(b"QUIT\r\n").await.ok();
+
+        Ok(response)
+    }
+}
+
+/// Applies the transparency procedure of RFC 5321 section 4.5.2 and adds
+/// the terminating dot line.
+fn dot_stuff(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5);
+
+    for line in data.split_inclusive(|byte| *byte == b'\n') {
+        if line.starts_with(b".") {
+            out.push(b'.');
+        }
+        out.extend_from_slice(line);
+    }
+
+    if !out.is_empty() && !out.ends_with(b"\r\n") {
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b".\r\n");
+
+    out
+}
+
+async fn read_response<R>(reader: &mut R) -> io::Result<Response>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut lines = vec![];
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.to_owned()))?;
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line.get(4..).unwrap_or_default().to_owned());
+
+        if last {
+            return Ok(Response::multiline(code, lines));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dot_stuff;
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(dot_stuff(b"a\r\n.b\r\n"), b"a\r\n..b\r\n.\r\n");
+        assert_eq!(dot_stuff(b"no newline"), b"no newline\r\n.\r\n");
+    }
+}
@@ -0,0 +1,56 @@
+/// A reply sent back to the SMTP client, possibly spanning several lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Response {
+    pub fn new<T: Into<String>>(code: u16, text: T) -> Self {
+        Self {
+            code,
+            lines: vec![text.into()],
+        }
+    }
+
+    pub fn multiline(code: u16, lines: Vec<String>) -> Self {
+        Self { code, lines }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(250, "OK")
+    }
+
+    pub fn bad_sequence() -> Self {
+        Self::new(503, "Bad sequence of commands")
+    }
+
+    pub fn syntax_error() -> Self {
+        Self::new(501, "Syntax error in parameters or arguments")
+    }
+
+    /// RFC 6531: a non-ASCII address in a transaction without SMTPUTF8.
+    pub fn smtputf8_required() -> Self {
+        Self::new(553, "Non-ASCII address requires SMTPUTF8")
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.code >= 400
+    }
+
+    pub fn closes_connection(&self) -> bool {
+        self.code == 221 || self.code == 421
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::new();
+        let last = self.lines.len().saturating_sub(1);
+
+        for (index, line) in self.lines.iter().enumerate() {
+            let separator = if index == last { ' ' } else { '-' };
+            out.push_str(&format!("{}{}{}\r\n", self.code, separator, line));
+        }
+
+        out.into_bytes()
+    }
+}
@@ -0,0 +1,1854 @@
+use super::auth::{self, Exchange, Mechanism};
+use super::{capabilities, Forward, Job, RcptDelay, Response, HOSTNAME};
+use crate::models::{self, Message, ParseFailure};
+use crate::{AppState, Error};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Client details passed on by a proxy with XFORWARD, valid for the current
+/// mail transaction.
+#[derive(Debug, Default, Clone)]
+struct XForward {
+    addr: Option<IpAddr>,
+    name: Option<String>,
+    helo: Option<String>,
+    proto: Option<String>,
+}
+
+/// Lines kept in a transcript, so a client that never completes DATA can't
+/// grow it without bound.
+const MAX_TRANSCRIPT_LINES: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Connected,
+    Greeted,
+    Mail,
+    Data,
+}
+
+/// State of a single SMTP connection.
+///
+/// Lines read from the client are fed to [`Session::process`], which returns
+/// the reply to write back (if any). Envelope state is only reset by RSET,
+/// a new HELO/EHLO or a completed DATA transaction.
+pub struct Session {
+    state: AppState,
+    remote: SocketAddr,
+    client_host: Option<String>,
+    /// Domain from HELO/EHLO, and whether it was EHLO.
+    helo: Option<(String, bool)>,
+    xforward: XForward,
+    stage: Stage,
+    sender: Option<String>,
+    /// When MAIL FROM of the current transaction was accepted.
+    mail_started: Option<Instant>,
+    /// MAIL FROM carried the SMTPUTF8 parameter (RFC 6531), allowing
+    /// non-ASCII addresses in the transaction.
+    smtputf8: bool,
+    recipients: Vec<String>,
+    data: Vec<u8>,
+    /// Untouched DATA lines, only collected when `raw_source` is set.
+    wire: Option<Vec<u8>>,
+    /// Bytes received in the current DATA, counted for `chaos_drop_after`.
+    data_received: usize,
+    chaos_drop_after: Option<usize>,
+    /// Completed transaction still to be relayed upstream.
+    forward: Option<Forward>,
+    in_body: bool,
+    rejection: Option<Response>,
+    max_line_length: usize,
+    max_header_size: usize,
+    authenticated: bool,
+    /// Set by STARTTLS until the connection performed the handshake.
+    starting_tls: bool,
+    /// How long to hold back the last reply, for `rcpt_delays`.
+    delay: Option<Duration>,
+    /// Negotiated version and cipher once the connection is encrypted.
+    tls: Option<(Option<String>, Option<String>)>,
+    exchange: Option<Exchange>,
+    /// Commands and replies since the last DATA, stored on the message it
+    /// completes.
+    transcript: Vec<String>,
+}
+
+impl Session {
+    pub fn new(state: AppState, remote: SocketAddr) -> Self {
+        let (max_line_length, max_header_size, chaos_drop_after) = {
+            let app = state.lock().expect("get lock");
+            (
+                app.max_line_length,
+                app.max_header_size,
+                app.chaos_drop_after,
+            )
+        };
+
+        Self {
+            state,
+            remote,
+            client_host: None,
+            helo: None,
+            xforward: XForward::default(),
+            stage: Stage::Connected,
+            sender: None,
+            mail_started: None,
+            smtputf8: false,
+            recipients: vec![],
+            data: vec![],
+            wire: None,
+            data_received: 0,
+            chaos_drop_after,
+            forward: None,
+            in_body: false,
+            rejection: None,
+            max_line_length,
+            max_header_size,
+            authenticated: false,
+            starting_tls: false,
+            delay: None,
+            tls: None,
+            exchange: None,
+            transcript: vec![],
+        }
+    }
+
+    /// Longest line, including its terminator, the connection will buffer.
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+
+    /// Called instead of [`Session::process`] when the client sent a line
+    /// longer than [`Session::max_line_length`].
+    ///
+    /// Outside of DATA the command is refused straight away. Inside DATA the
+    /// transaction is marked as failed and the rest of the message is
+    /// discarded until the terminating dot.
+    pub fn line_too_long(&mut self) -> Option<Response> {
+        if self.stage == Stage::Data {
+            self.reject(Response::new(552, "Line length exceeds limit"));
+            None
+        } else {
+            Some(Response::new(500, "Line too long"))
+        }
+    }
+
+    /// Whether chaos testing wants the connection dropped right now,
+    /// without replying.
+    pub fn chaos_drop(&self) -> bool {
+        self.stage == Stage::Data
+            && self
+                .chaos_drop_after
+                .is_some_and(|limit| self.data_received >= limit)
+    }
+
+    /// In relay mode, the transaction just captured. Its upstream reply is
+    /// what the client should get instead of the one from
+    /// [`Session::process`].
+    pub fn take_forward(&mut self) -> Option<Forward> {
+        self.forward.take()
+    }
+
+    /// Whether the last reply accepted STARTTLS, so the connection has to
+    /// perform the TLS handshake before reading further commands.
+    pub fn take_starttls(&mut self) -> bool {
+        std::mem::take(&mut self.starting_tls)
+    }
+
+    /// How long the connection should wait before writing the last reply.
+    pub fn take_delay(&mut self) -> Option<Duration> {
+        self.delay.take()
+    }
+
+    /// Records the negotiated TLS parameters. As required by RFC 3207 the
+    /// session forgets everything learned before the handshake.
+    pub fn set_tls(&mut self, version: Option<String>, cipher: Option<String>) {
+        self.reset();
+        self.stage = Stage::Connected;
+        self.helo = None;
+        self.authenticated = false;
+        self.tls = Some((version, cipher));
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Reverse DNS name of the client, recorded on every captured message.
+    pub fn set_client_host(&mut self, client_host: Option<String>) {
+        self.client_host = client_host;
+    }
+
+    pub fn greeting(&self) -> Response {
+        Response::new(220, format!("{} ESMTP", HOSTNAME))
+    }
+
+    pub async fn process(&mut self, line: &[u8]) -> Option<Response> {
+        if self.stage == Stage::Data {
+            return self.data_line(line).await;
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        self.log(format!("C: {}", self.redact(line)));
+        let response = self.command(line);
+        for line in transcript_lines(&response) {
+            self.log(line);
+        }
+
+        Some(response)
+    }
+
+    fn log(&mut self, line: String) {
+        if self.transcript.len() < MAX_TRANSCRIPT_LINES {
+            self.transcript.push(line);
+        }
+    }
+
+    /// `line` with AUTH credentials left out.
+    fn redact(&self, line: &str) -> String {
+        if self.exchange.is_some() {
+            return "<redacted>".to_owned();
+        }
+
+        let mut words = line.splitn(3, ' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some(verb), Some(mechanism), Some(_)) if verb.eq_ignore_ascii_case("AUTH") => {
+                format!("{} {} <redacted>", verb, mechanism)
+            }
+            _ => line.to_owned(),
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Response {
+        if let Some(exchange) = self.exchange.take() {
+            return self.auth_response(exchange, line);
+        }
+
+        let (verb, args) = match line.split_once(' ') {
+            Some((verb, args)) => (verb, args.trim()),
+            None => (line, ""),
+        };
+
+        match verb.to_ascii_uppercase().as_str() {
+            "HELO" => self.helo(args, false),
+            "EHLO" => self.helo(args, true),
+            "AUTH" => self.auth(args),
+            "STARTTLS" => self.starttls(verb, args),
+            "XFORWARD" => self.xforward(verb, args),
+            "MAIL" => self.mail(args),
+            "RCPT" => self.rcpt(args),
+            "DATA" => self.data_start(),
+            "RSET" => self.rset(),
+            "NOOP" => Response::ok(),
+            "VRFY" => Response::new(252, "Cannot VRFY user"),
+            "QUIT" => Response::new(221, "Bye"),
+            _ => self.unknown(verb),
+        }
+    }
+
+    fn helo(&mut self, domain: &str, extended: bool) -> Response {
+        if domain.is_empty() {
+            return Response::syntax_error();
+        }
+
+        self.reset();
+        self.stage = Stage::Greeted;
+        self.helo = Some((domain.to_owned(), extended));
+
+        if extended {
+            let mut lines = vec![format!("{} Hello {}", HOSTNAME, domain)];
+            lines.extend(
+                capabilities(&self.state.lock().expect("get lock"))
+                    .into_iter()
+                    .filter(|capability| self.tls.is_none() || capability != "STARTTLS"),
+            );
+
+            Response::multiline(250, lines)
+        } else {
+            Response::new(250, format!("{} Hello {}", HOSTNAME, domain))
+        }
+    }
+
+    fn starttls(&mut self, verb: &str, args: &str) -> Response {
+        let (tls, failure) = {
+            let app = self.state.lock().expect("get lock");
+            (app.tls.is_some(), app.starttls_failure)
+        };
+
+        if !tls && !failure {
+            return self.unknown(verb);
+        }
+
+        if !args.is_empty() {
+            return Response::syntax_error();
+        }
+
+        if failure {
+            return Response::new(454, "TLS not available due to temporary reason");
+        }
+
+        if self.stage != Stage::Greeted || self.tls.is_some() {
+            return Response::bad_sequence();
+        }
+
+        self.starting_tls = true;
+
+        Response::new(220, "Ready to start TLS")
+    }
+
+    fn xforward(&mut self, verb: &str, args: &str) -> Response {
+        if !self.state.lock().expect("get lock").xforward {
+            return self.unknown(verb);
+        }
+
+        if self.stage != Stage::Greeted {
+            return Response::bad_sequence();
+        }
+
+        let mut xforward = self.xforward.clone();
+
+        for attribute in args.split_whitespace() {
+            let Some((name, value)) = attribute.split_once('=') else {
+                return Response::syntax_error();
+            };
+            let value = match value {
+                "[UNAVAILABLE]" | "[TEMPUNAVAIL]" => None,
+                value => Some(xtext_decode(value)),
+            };
+
+            match name.to_ascii_uppercase().as_str() {
+                "ADDR" => {
+                    let addr = value.map(|value| {
+                        let value = value.strip_prefix("IPV6:").unwrap_or(&value).to_owned();
+                        value.parse::<IpAddr>()
+                    });
+
+                    match addr.transpose() {
+                        Ok(addr) => xforward.addr = addr,
+                        Err(_) => return Response::syntax_error(),
+                    }
+                }
+                "NAME" => xforward.name = value,
+                "HELO" => xforward.helo = value,
+                "PROTO" => xforward.proto = value,
+                "PORT" | "SOURCE" | "IDENT" => {}
+                _ => return Response::new(501, "Bad XFORWARD attribute name"),
+            }
+        }
+
+        self.xforward = xforward;
+
+        Response::ok()
+    }
+
+    /// Address of the SMTP client, as forwarded by a proxy if it did.
+    fn client_ip(&self) -> IpAddr {
+        self.xforward.addr.unwrap_or(self.remote.ip())
+    }
+
+    fn client_host(&self) -> Option<String> {
+        self.xforward
+            .name
+            .clone()
+            .or_else(|| self.client_host.clone())
+    }
+
+    /// HELO/EHLO name of the client, as forwarded by a proxy if it did.
+    fn announced_helo(&self) -> Option<String> {
+        self.xforward
+            .helo
+            .clone()
+            .or_else(|| self.helo.as_ref().map(|(helo, _)| helo.clone()))
+    }
+
+    fn auth(&mut self, args: &str) -> Response {
+        if !self.state.lock().expect("get lock").smtp_auth {
+            return Response::new(502, "Command not implemented");
+        }
+
+        if self.stage != Stage::Greeted || self.authenticated {
+            return Response::bad_sequence();
+        }
+
+        let (name, initial) = match args.split_once(' ') {
+            Some((name, initial)) => (name, Some(initial)),
+            None => (args, None),
+        };
+
+        match (Mechanism::from_name(name), initial) {
+            (Some(Mechanism::Plain), Some(initial)) => self.auth_response(Exchange::Plain, initial),
+            (Some(Mechanism::Plain), None) => self.challenge(Exchange::Plain, ""),
+            (Some(Mechanism::Login), None) => self.challenge(Exchange::LoginUsername, "Username:"),
+            (Some(Mechanism::Login), Some(username)) => {
+                self.auth_response(Exchange::LoginUsername, username)
+            }
+            (Some(Mechanism::CramMd5), None) => {
+                let challenge = format!("<{}@{}>", Uuid::new_v4().simple(), HOSTNAME);
+                self.challenge(
+                    Exchange::CramMd5 {
+                        challenge: challenge.clone(),
+                    },
+                    &challenge,
+                )
+            }
+            // The client can't know the challenge beforehand.
+            (Some(Mechanism::CramMd5), Some(_)) => Response::syntax_error(),
+            (None, _) => Response::new(504, "Unrecognized authentication type"),
+        }
+    }
+
+    fn challenge(&mut self, exchange: Exchange, prompt: &str) -> Response {
+        self.exchange = Some(exchange);
+
+        Response::new(334, auth::encode(prompt))
+    }
+
+    fn auth_response(&mut self, exchange: Exchange, line: &str) -> Response {
+        if line == "*" {
+            return Response::new(501, "Authentication cancelled");
+        }
+
+        match exchange {
+            Exchange::Plain => match auth::decode_plain(line) {
+                Some((username, password)) => self.authenticate(&username, &password),
+                None => Response::syntax_error(),
+            },
+            Exchange::LoginUsername => match auth::decode(line) {
+                Some(username) => self.challenge(Exchange::LoginPassword { username }, "Password:"),
+                None => Response::syntax_error(),
+            },
+            Exchange::LoginPassword { username } => match auth::decode(line) {
+                Some(password) => self.authenticate(&username, &password),
+                None => Response::syntax_error(),
+            },
+            Exchange::CramMd5 { challenge } => match auth::decode_cram_md5(line) {
+                Some((username, digest)) => {
+                    let password = self.state.lock().expect("get lock").smtp_password.clone();
+
+                    if digest == auth::cram_md5_digest(&password, &challenge) {
+                        self.authenticate(&username, &password)
+                    } else {
+                        Response::new(535, "Authentication credentials invalid")
+                    }
+                }
+                None => Response::syntax_error(),
+            },
+        }
+    }
+
+    fn authenticate(&mut self, username: &str, password: &str) -> Response {
+        let valid = {
+            let app = self.state.lock().expect("get lock");
+            app.smtp_username == username && app.smtp_password == password
+        };
+
+        if valid {
+            self.authenticated = true;
+            Response::new(235, "Authentication succeeded")
+        } else {
+            Response::new(535, "Authentication credentials invalid")
+        }
+    }
+
+    /// The trace header a real MTA would prepend, RFC 5321 section 4.4.
+    fn received_header(&self) -> String {
+        let (helo, protocol) = match &self.helo {
+            Some((helo, true)) => (helo.as_str(), "ESMTP"),
+            Some((helo, false)) => (helo.as_str(), "SMTP"),
+            None => ("unknown", "SMTP"),
+        };
+        let helo = self.xforward.helo.as_deref().unwrap_or(helo);
+        let protocol = self.xforward.proto.as_deref().unwrap_or(protocol);
+        let from = match self.client_host() {
+            Some(host) => format!("{} ({} [{}])", helo, host, self.client_ip()),
+            None => format!("{} ([{}])", helo, self.client_ip()),
+        };
+
+        format!(
+            "Received: from {}\r\n\tby {} with {}; {}\r\n",
+            from,
+            HOSTNAME,
+            protocol,
+            chrono::Local::now().to_rfc2822()
+        )
+    }
+
+    fn unknown(&self, verb: &str) -> Response {
+        let app = self.state.lock().expect("get lock");
+
+        if app.log_unknown_commands {
+            eprintln!("smtp {}: unknown command {:?}", self.remote, verb);
+        }
+
+        match app.unknown_command_code {
+            502 => Response::new(502, "Command not implemented"),
+            code => Response::new(code, "Command not recognized"),
+        }
+    }
+
+    fn mail(&mut self, args: &str) -> Response {
+        if self.stage != Stage::Greeted {
+            return Response::bad_sequence();
+        }
+
+        if !self.authenticated && self.state.lock().expect("get lock").smtp_auth {
+            return Response::new(530, "Authentication required");
+        }
+
+        match parse_path(args, "FROM:") {
+            Some((path, params)) => {
+                let smtputf8 = params
+                    .split_whitespace()
+                    .any(|param| param.eq_ignore_ascii_case("SMTPUTF8"));
+                if !smtputf8 && !path.is_ascii() {
+                    return Response::smtputf8_required();
+                }
+
+                if !super::sender_allowed(&self.state.lock().expect("get lock"), path) {
+                    return Response::new(550, "Sender domain not accepted");
+                }
+
+                self.sender = Some(path.to_owned());
+                self.mail_started = Some(Instant::now());
+                self.smtputf8 = smtputf8;
+                self.stage = Stage::Mail;
+                Response::ok()
+            }
+            None => Response::syntax_error(),
+        }
+    }
+
+    fn rcpt(&mut self, args: &str) -> Response {
+        if self.stage != Stage::Mail {
+            return Response::bad_sequence();
+        }
+
+        match parse_path(args, "TO:") {
+            Some((path, _)) if !self.smtputf8 && !path.is_ascii() => Response::smtputf8_required(),
+            Some((path, _params)) if !path.is_empty() => {
+                self.delay =
+                    RcptDelay::find(&self.state.lock().expect("get lock").rcpt_delays, path);
+                self.recipients.push(path.to_owned());
+                Response::ok()
+            }
+            _ => Response::syntax_error(),
+        }
+    }
+
+    fn data_start(&mut self) -> Response {
+        if self.stage != Stage::Mail || self.recipients.is_empty() {
+            return Response::bad_sequence();
+        }
+
+        self.stage = Stage::Data;
+
+        if self.state.lock().expect("get lock").raw_source {
+            self.wire = Some(vec![]);
+        }
+
+        Response::new(354, "Start mail input; end with <CRLF>.<CRLF>")
+    }
+
+    async fn data_line(&mut self, line: &[u8]) -> Option<Response> {
+        self.data_received += line.len();
+
+        if self.chaos_drop() {
+            return None;
+        }
+
+        if self.rejection.is_none() {
+            if let Some(wire) = self.wire.as_mut() {
+                wire.extend_from_slice(line);
+            }
+        }
+
+        // Bare LF clients end DATA with a bare LF dot line as well.
+        if line == b".\r\n" || line == b".\n" {
+            return Some(self.data_end().await);
+        }
+
+        if self.rejection.is_some() {
+            return None;
+        }
+
+        // Undo the transparency procedure of RFC 5321 section 4.5.2.
+        let line = line.strip_prefix(b".").unwrap_or(line);
+        self.data.extend_from_slice(line);
+
+        if !self.in_body {
+            if line == b"\r\n" || line == b"\n" {
+                self.in_body = true;
+            } else if self.data.len() > self.max_header_size {
+                self.reject(Response::new(552, "Message header size exceeds limit"));
+            }
+        }
+
+        None
+    }
+
+    fn reject(&mut self, response: Response) {
+        self.data.clear();
+        if let Some(wire) = self.wire.as_mut() {
+            wire.clear();
+        }
+        self.rejection = Some(response);
+    }
+
+    async fn data_end(&mut self) -> Response {
+        if let Some(rejection) = self.rejection.take() {
+            self.reset();
+            self.stage = Stage::Greeted;
+            return rejection;
+        }
+
+        let client_ip = self.client_ip();
+        let client_host = self.client_host();
+        let helo = self.announced_helo();
+        let (tls_version, tls_cipher) = self.tls.clone().unwrap_or_default();
+        let transaction_ms = self
+            .mail_started
+            .map(|started| started.elapsed().as_millis() as u64);
+        let mut transcript = std::mem::take(&mut self.transcript);
+
+        let (parse_options, content_id) = {
+            let mut app = self.state.lock().expect("get lock");
+
+            if let Some(greylist) = app.greylist.as_mut() {
+                let sender = self.sender.as_deref().unwrap_or_default();
+
+                if greylist.defer(client_ip, sender, &self.recipients) {
+                    drop(app);
+                    self.reset();
+                    self.stage = Stage::Greeted;
+
+                    return Response::new(450, "Greylisted, please try again later");
+                }
+            }
+
+            if app
+                .rate_limit
+                .as_mut()
+                .is_some_and(|limit| !limit.acquire())
+            {
+                drop(app);
+                self.reset();
+                self.stage = Stage::Greeted;
+
+                return Response::new(451, "Rate limit exceeded, try again later");
+            }
+
+            let missing = missing_headers(&self.data, &app.required_headers);
+            if !missing.is_empty() {
+                drop(app);
+                self.reset();
+                self.stage = Stage::Greeted;
+
+                return Response::new(
+                    554,
+                    format!("Missing required headers: {}", missing.join(", ")),
+                );
+            }
+
+            // The trace header carries the delivery time, so `content_id` is
+            // taken from the data as received to stay stable across
+            // deliveries.
+            let content_id = app.received_header.then(|| models::content_id(&self.data));
+            if app.received_header {
+                let mut data = self.received_header().into_bytes();
+                data.append(&mut self.data);
+                self.data = data;
+            }
+
+            self.forward = app.relay.as_ref().map(|upstream| Forward {
+                upstream: upstream.clone(),
+                sender: self.sender.clone().unwrap_or_default(),
+                recipients: self.recipients.clone(),
+                data: self.data.clone(),
+            });
+
+            if app.parse_pool.is_some() {
+                let id = match app.storage.reserve_id() {
+                    Ok(id) => id,
+                    Err(err) => {
+                        eprintln!("reserving a message id failed: {}", err);
+                        drop(app);
+                        self.reset();
+                        self.stage = Stage::Greeted;
+
+                        return Response::new(
+                            451,
+                            "Requested action aborted: local error in processing",
+                        );
+                    }
+                };
+                let response = match &app.data_response {
+                    Some(template) => Response::new(250, template.replace("{id}", &id.to_string())),
+                    None => Response::new(250, "Queued"),
+                };
+                transcript.extend(transcript_lines(&response));
+
+                let job = Job {
+                    id,
+                    data: std::mem::take(&mut self.data),
+                    wire: self.wire.take(),
+                    content_id,
+                    envelope_sender: self.sender.take().unwrap_or_default(),
+                    envelope_recipients: std::mem::take(&mut self.recipients),
+                    client_ip: Some(client_ip.to_string()),
+                    client_host: client_host.clone(),
+                    helo: helo.clone(),
+                    tls_version: tls_version.clone(),
+                    tls_cipher: tls_cipher.clone(),
+                    transaction_ms,
+                    transcript,
+                    parse_options: app.parse_options.clone(),
+                };
+                if let Some(pool) = &app.parse_pool {
+                    pool.enqueue(job);
+                }
+                drop(app);
+                self.reset();
+                self.stage = Stage::Greeted;
+
+                return response;
+            }
+
+            (app.parse_options.clone(), content_id)
+        };
+
+        // Parsing is the slow part, so it runs on the blocking pool and
+        // other sessions can use the state meanwhile.
+        let data = std::mem::take(&mut self.data);
+        let parsed = tokio::task::spawn_blocking(move || {
+            let parsed = Message::parse(&data, &parse_options);
+            (data, parsed)
+        })
+        .await;
+
+        let parsed = match parsed {
+            Ok((data, parsed)) => {
+                self.data = data;
+                parsed
+            }
+            Err(err) => {
+                eprintln!("parsing a message failed: {}", err);
+                self.reset();
+                self.stage = Stage::Greeted;
+                self.forward = None;
+
+                return Response::new(451, "Requested action aborted: local error in processing");
+            }
+        };
+
+        let result = parsed.and_then(|mut message| {
+            message.envelope_sender = self.sender.take().unwrap_or_default();
+            message.envelope_recipients = std::mem::take(&mut self.recipients);
+            message.client_ip = Some(client_ip.to_string());
+            message.client_host = client_host;
+            message.helo = helo;
+            message.tls_version = tls_version;
+            message.tls_cipher = tls_cipher;
+            message.transaction_ms = transaction_ms;
+
+            if let Some(content_id) = content_id {
+                message.content_id = content_id;
+            }
+            if let Some(wire) = self.wire.take() {
+                message.set_source(wire);
+            }
+
+            self.state.lock().expect("get lock").capture(message)
+        });
+
+        if let Err(Error::Parse(reason)) = &result {
+            let mut app = self.state.lock().expect("get lock");
+
+            // Strict mode rejects such messages on purpose, they aren't
+            // failures to inspect.
+            if !app.parse_options.strict {
+                let mut failure = ParseFailure::new(&self.data, reason.clone());
+                failure.envelope_sender = self.sender.clone().unwrap_or_default();
+                failure.envelope_recipients = self.recipients.clone();
+
+                app.record_parse_failure(failure);
+            }
+        }
+
+        self.reset();
+        self.stage = Stage::Greeted;
+
+        if result.is_err() {
+            self.forward = None;
+        }
+
+        let response = match &result {
+            Ok(message) => match &self.state.lock().expect("get lock").data_response {
+                Some(template) => {
+                    let id = message.id.map(|id| id.to_string()).unwrap_or_default();
+                    Response::new(250, template.replace("{id}", &id))
+                }
+                None => Response::ok(),
+            },
+            Err(Error::Parse(_)) => Response::new(554, "Transaction failed"),
+            Err(Error::TooLarge(_)) => Response::new(552, "Message exceeds size limit"),
+            Err(Error::TooManyAttachments(_)) => {
+                Response::new(554, "Message exceeds attachment limits")
+            }
+            Err(_) => Response::new(451, "Requested action aborted: local error in processing"),
+        };
+
+        // The final reply depends on the stored message, so the transcript
+        // is attached afterwards. It's gone if the message already was.
+        if let Ok(mut message) = result {
+            transcript.extend(transcript_lines(&response));
+            message.transcript = transcript;
+            let _ = self.state.lock().expect("get lock").storage.update(message);
+        }
+
+        response
+    }
+
+    fn rset(&mut self) -> Response {
+        self.reset();
+
+        if self.stage != Stage::Connected {
+            self.stage = Stage::Greeted;
+        }
+
+        Response::ok()
+    }
+
+    fn reset(&mut self) {
+        self.sender = None;
+        self.mail_started = None;
+        self.smtputf8 = false;
+        self.recipients.clear();
+        self.data.clear();
+        self.wire = None;
+        self.xforward = XForward::default();
+        self.data_received = 0;
+        self.in_body = false;
+        self.rejection = None;
+    }
+}
+
+/// Names in `required` without a header field in the header block of
+/// `data`, compared case-insensitively.
+fn missing_headers(data: &[u8], required: &[String]) -> Vec<String> {
+    let present: Vec<String> = String::from_utf8_lossy(data)
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter(|line| !line.starts_with([' ', '\t']))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, _)| name.trim().to_ascii_lowercase())
+        .collect();
+
+    required
+        .iter()
+        .filter(|name| !present.contains(&name.to_ascii_lowercase()))
+        .cloned()
+        .collect()
+}
+
+fn transcript_lines(response: &Response) -> impl Iterator<Item = String> + '_ {
+    response
+        .lines
+        .iter()
+        .map(move |line| format!("S: {} {}", response.code, line))
+}
+
+/// Decodes `+XX` escapes of RFC 3461 xtext.
+fn xtext_decode(value: &str) -> String {
+    let mut out = vec![];
+    let mut bytes = value.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'+' {
+            out.push(byte);
+            continue;
+        }
+
+        let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+        match std::str::from_utf8(&hex)
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            Some(decoded) => out.push(decoded),
+            None => {
+                out.push(b'+');
+                out.extend(hex);
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Splits `FROM:<path> params` into the path and the trailing parameters.
+fn parse_path<'a>(args: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    let head = args.get(..prefix.len())?;
+
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+
+    let rest = args[prefix.len()..].trim_start().strip_prefix('<')?;
+    let (path, params) = rest.split_once('>')?;
+
+    Some((path, params.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Session;
+    use crate::models::ParseOptions;
+    use crate::smtp::auth;
+    use crate::smtp::{Greylist, RateLimiter};
+    use crate::storage::Memdir;
+    use crate::test_util::MessageBuilder;
+    use crate::Mailtutan;
+    use std::time::Duration;
+
+    async fn send(session: &mut Session, lines: &[&str]) -> Vec<u16> {
+        let mut codes = vec![];
+
+        for line in lines {
+            if let Some(response) = session.process(format!("{}\r\n", line).as_bytes()).await {
+                codes.push(response.code);
+            }
+        }
+
+        codes
+    }
+
+    #[tokio::test]
+    async fn test_noop_keeps_transaction_state() {
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<first@example.com>",
+                "NOOP",
+                "RCPT TO:<second@example.com>",
+                "NOOP",
+                "DATA",
+                "Subject: keepalive",
+                "",
+                "body",
+                ".",
+            ],
+        )
+        .await;
+
+        assert_eq!(codes, vec![250, 250, 250, 250, 250, 250, 354, 250]);
+
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].envelope_sender, "sender@example.com");
+        assert_eq!(
+            messages[0].envelope_recipients,
+            vec!["first@example.com", "second@example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_plain_is_required() {
+        let state = Mailtutan {
+            smtp_auth: true,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state, "127.0.0.1:2525".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<sender@example.com>",
+                "AUTH PLAIN AGFkbWluAHdyb25n",
+                "AUTH PLAIN AGFkbWluAGFkbWlu",
+                "MAIL FROM:<sender@example.com>",
+            ],
+        )
+        .await;
+
+        assert_eq!(codes, vec![250, 530, 535, 235, 250]);
+    }
+
+    #[tokio::test]
+    async fn test_auth_cram_md5() {
+        let state = Mailtutan {
+            smtp_auth: true,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state, "127.0.0.1:2525".parse().unwrap());
+        send(&mut session, &["EHLO client.example"]).await;
+
+        for (password, code) in [("wrong", 535), ("admin", 235)] {
+            let reply = session.process(b"AUTH CRAM-MD5\r\n").await.unwrap();
+            assert_eq!(reply.code, 334);
+
+            let challenge = auth::decode(&reply.lines[0]).unwrap();
+            assert!(challenge.starts_with('<') && challenge.ends_with('>'));
+
+            let response = format!("admin {}", auth::cram_md5_digest(password, &challenge));
+            let line = format!("{}\r\n", auth::encode(&response));
+            assert_eq!(session.process(line.as_bytes()).await.unwrap().code, code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_greylisting() {
+        let state = Mailtutan {
+            greylist: Some(Greylist::new(Duration::from_millis(50))),
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+        let delivery = [
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+            "Subject: greylisted",
+            "",
+            "body",
+            ".",
+        ];
+
+        send(&mut session, &["EHLO client.example"]).await;
+        assert_eq!(send(&mut session, &delivery).await.last(), Some(&450));
+        assert_eq!(send(&mut session, &delivery).await.last(), Some(&450));
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        assert_eq!(send(&mut session, &delivery).await.last(), Some(&250));
+        assert_eq!(state.lock().unwrap().storage.size(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sender_domain_lists() {
+        let state = Mailtutan {
+            sender_allowlist: vec!["example.com".to_owned()],
+            sender_denylist: vec!["spam.example.com".to_owned()],
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<root@spam.example.com>",
+                "MAIL FROM:<root@other.org>",
+                "RCPT TO:<rcpt@example.com>",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 550, 550, 503]);
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+
+        let codes = send(
+            &mut session,
+            &[
+                "MAIL FROM:<sender@Example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: allowed",
+                "",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 250, 354, 250]);
+        assert_eq!(state.lock().unwrap().storage.size(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parse_pool_keeps_arrival_order() {
+        let state = Mailtutan {
+            parse_workers: 4,
+            ..Default::default()
+        }
+        .init();
+
+        let clients: Vec<_> = (0..8)
+            .map(|client| {
+                let state = state.clone();
+
+                tokio::spawn(async move {
+                    let mut session = Session::new(state, "127.0.0.1:2525".parse().unwrap());
+                    send(&mut session, &["EHLO client.example"]).await;
+
+                    for i in 0..10 {
+                        let subject = format!("Subject: {}-{}", client, i);
+                        let codes = send(
+                            &mut session,
+                            &[
+                                "MAIL FROM:<sender@example.com>",
+                                "RCPT TO:<rcpt@example.com>",
+                                "DATA",
+                                &subject,
+                                "",
+                                ".",
+                            ],
+                        )
+                        .await;
+                        assert_eq!(codes.last(), Some(&250));
+                    }
+                })
+            })
+            .collect();
+
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        for _ in 0..500 {
+            if state.lock().unwrap().storage.size() == 80 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut messages = state.lock().unwrap().storage.list().unwrap();
+        messages.sort_by_key(|message| message.id);
+        assert_eq!(messages.len(), 80);
+
+        let ids: Vec<usize> = messages.iter().filter_map(|message| message.id).collect();
+        assert_eq!(ids, (1..=80).collect::<Vec<_>>());
+
+        for client in 0..8 {
+            let sequence: Vec<String> = messages
+                .iter()
+                .map(|message| message.subject.clone())
+                .filter(|subject| subject.starts_with(&format!("{}-", client)))
+                .collect();
+            let expected: Vec<String> = (0..10).map(|i| format!("{}-{}", client, i)).collect();
+            assert_eq!(sequence, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_code() {
+        let state = Mailtutan {
+            unknown_command_code: 502,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state, "127.0.0.1:2525".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<sender@example.com>",
+                "XYZZY \u{1}\u{ff} plugh",
+                "RCPT TO:<rcpt@example.com>",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 250, 502, 250]);
+    }
+
+    #[tokio::test]
+    async fn test_client_ip_is_captured() {
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state.clone(), "192.0.2.10:40000".parse().unwrap());
+
+        send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: from a known address",
+                "",
+                ".",
+            ],
+        )
+        .await;
+
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(messages[0].client_ip.as_deref(), Some("192.0.2.10"));
+        assert_eq!(messages[0].client_host, None);
+    }
+
+    #[tokio::test]
+    async fn test_parse_failures_are_kept() {
+        let state = Mailtutan {
+            max_parse_failures: 1,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let mut lines = vec!["EHLO client.example"];
+        for _ in 0..2 {
+            lines.extend([
+                "MAIL FROM:<app@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                ".",
+            ]);
+        }
+        let codes = send(&mut session, &lines).await;
+        assert_eq!(codes, vec![250, 250, 250, 354, 554, 250, 250, 354, 554]);
+
+        let app = state.lock().unwrap();
+        assert_eq!(app.parse_failure_count, 2);
+        assert_eq!(app.parse_failures.len(), 1);
+
+        let failure = &app.parse_failures[0];
+        assert_eq!(failure.error, "no message headers found");
+        assert_eq!(failure.envelope_sender, "app@example.com");
+        assert_eq!(failure.envelope_recipients, vec!["rcpt@example.com"]);
+        assert!(app.storage.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_strict_rejections_are_not_failures() {
+        let state = Mailtutan {
+            max_parse_failures: 1,
+            parse_options: ParseOptions {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<app@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: strict",
+                "not a header",
+                "",
+                "body",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 250, 250, 354, 554]);
+
+        let app = state.lock().unwrap();
+        assert_eq!(app.parse_failure_count, 0);
+        assert!(app.parse_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_starttls_failure_simulation() {
+        let state = Mailtutan {
+            starttls_failure: true,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state, "10.0.0.1:40000".parse().unwrap());
+
+        let ehlo = session.process(b"EHLO client.example\r\n").await.unwrap();
+        assert!(ehlo.lines.contains(&"STARTTLS".to_owned()));
+
+        let codes = send(
+            &mut session,
+            &["STARTTLS", "MAIL FROM:<sender@example.com>"],
+        )
+        .await;
+        assert_eq!(codes, vec![454, 250]);
+        assert!(!session.take_starttls());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_duration_is_recorded() {
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        send(
+            &mut session,
+            &["EHLO client.example", "MAIL FROM:<sender@example.com>"],
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let codes = send(
+            &mut session,
+            &[
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: timed",
+                "",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 354, 250]);
+
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert!(messages[0].transaction_ms.is_some_and(|ms| ms >= 20));
+    }
+
+    #[tokio::test]
+    async fn test_transcript_is_recorded() {
+        let state = Mailtutan {
+            smtp_auth: true,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "AUTH PLAIN AGFkbWluAGFkbWlu",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: logged",
+                "",
+                "secret body",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 235, 250, 250, 354, 250]);
+
+        let transcript = state.lock().unwrap().storage.list().unwrap()[0]
+            .transcript
+            .clone();
+        assert_eq!(transcript[0], "C: EHLO client.example");
+        assert!(transcript.contains(&"C: AUTH PLAIN <redacted>".to_owned()));
+        assert!(transcript.contains(&"C: MAIL FROM:<sender@example.com>".to_owned()));
+        assert!(transcript.contains(&"C: RCPT TO:<rcpt@example.com>".to_owned()));
+        assert!(transcript.contains(&"C: DATA".to_owned()));
+        assert_eq!(transcript.last().unwrap(), "S: 250 OK");
+        assert!(!transcript
+            .iter()
+            .any(|line| line.contains("AGFkbWlu") || line.contains("secret body")));
+    }
+
+    #[tokio::test]
+    async fn test_bare_lf_message() {
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let mut codes = vec![];
+        for line in [
+            "EHLO client.example\n",
+            "MAIL FROM:<sender@example.com>\n",
+            "RCPT TO:<rcpt@example.com>\n",
+            "DATA\n",
+            "Subject: bare line feeds\n",
+            "From: sender@example.com\n",
+            "\n",
+            "first line\n",
+            "..stuffed\n",
+            "last line\n",
+            ".\n",
+            "NOOP\n",
+        ] {
+            codes.extend(
+                session
+                    .process(line.as_bytes())
+                    .await
+                    .map(|response| response.code),
+            );
+        }
+        assert_eq!(codes, vec![250, 250, 250, 354, 250, 250]);
+
+        let message = state.lock().unwrap().storage.list().unwrap().remove(0);
+        assert_eq!(message.subject, "bare line feeds");
+        assert_eq!(message.sender, "sender@example.com");
+        assert_eq!(
+            message.plain.as_deref(),
+            Some("first line\n.stuffed\nlast line\n")
+        );
+        assert_eq!(
+            message.source,
+            b"Subject: bare line feeds\nFrom: sender@example.com\n\nfirst line\n.stuffed\nlast line\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smtputf8_addresses() {
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let ehlo = session.process(b"EHLO client.example\r\n").await.unwrap();
+        assert!(ehlo.lines.contains(&"SMTPUTF8".to_owned()));
+
+        let codes = send(
+            &mut session,
+            &[
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<用户@例え.jp>",
+                "RSET",
+                "MAIL FROM:<送信者@例え.jp>",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 553, 250, 553]);
+
+        let codes = send(
+            &mut session,
+            &[
+                "MAIL FROM:<送信者@例え.jp> SMTPUTF8",
+                "RCPT TO:<用户@例え.jp>",
+                "DATA",
+                "From: 送信者@例え.jp",
+                "To: 用户@例え.jp",
+                "Subject: こんにちは",
+                "",
+                "body",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 250, 354, 250]);
+
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(messages[0].envelope_sender, "送信者@例え.jp");
+        assert_eq!(messages[0].envelope_recipients, vec!["用户@例え.jp"]);
+        assert_eq!(messages[0].recipients, vec!["用户@例え.jp"]);
+        assert_eq!(messages[0].subject, "こんにちは");
+    }
+
+    #[tokio::test]
+    async fn test_required_headers() {
+        let state = Mailtutan {
+            required_headers: vec!["From".to_owned(), "Subject".to_owned()],
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let mut replies = vec![];
+        for line in [
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+            "from: sender@example.com",
+            "To: rcpt@example.com",
+            "",
+            "Subject: only in the body",
+            ".",
+        ] {
+            replies.extend(session.process(format!("{}\r\n", line).as_bytes()).await);
+        }
+
+        let reply = replies.last().unwrap();
+        assert_eq!(reply.code, 554);
+        assert_eq!(reply.lines, vec!["Missing required headers: Subject"]);
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+
+        let codes = send(
+            &mut session,
+            &[
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "From: sender@example.com",
+                "SUBJECT: present",
+                "",
+                "body",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 250, 354, 250]);
+        assert_eq!(state.lock().unwrap().storage.size(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_data_response_template() {
+        let state = Mailtutan {
+            data_response: Some("OK: queued as {id}".to_owned()),
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let mut replies = vec![];
+        for line in [
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+            "Subject: queued",
+            "",
+            ".",
+        ] {
+            replies.extend(session.process(format!("{}\r\n", line).as_bytes()).await);
+        }
+
+        let id = state.lock().unwrap().storage.list().unwrap()[0].id.unwrap();
+        let reply = replies.last().unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(reply.lines, vec![format!("OK: queued as {}", id)]);
+    }
+
+    #[tokio::test]
+    async fn test_parse_pool_data_response_names_reserved_id() {
+        let state = Mailtutan {
+            parse_workers: 2,
+            data_response: Some("OK: queued as {id}".to_owned()),
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+        session.process(b"EHLO client.example\r\n").await;
+
+        let mut replies = vec![];
+        for subject in ["Subject: first", "Subject: second"] {
+            for line in [
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                subject,
+                "",
+                ".",
+            ] {
+                replies.extend(session.process(format!("{}\r\n", line).as_bytes()).await);
+            }
+        }
+        let replies: Vec<String> = replies
+            .into_iter()
+            .filter(|reply| reply.lines[0].starts_with("OK: "))
+            .map(|reply| reply.lines[0].clone())
+            .collect();
+        assert_eq!(replies, vec!["OK: queued as 1", "OK: queued as 2"]);
+
+        for _ in 0..500 {
+            if state.lock().unwrap().storage.size() == 2 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let app = state.lock().unwrap();
+        assert_eq!(app.storage.get(1).unwrap().subject, "first");
+        assert_eq!(app.storage.get(2).unwrap().subject, "second");
+    }
+
+    #[tokio::test]
+    async fn test_helo_name_is_recorded() {
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO Relay-7.example.net",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: helo",
+                "",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 250, 250, 354, 250]);
+
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(messages[0].helo.as_deref(), Some("Relay-7.example.net"));
+    }
+
+    #[tokio::test]
+    async fn test_xforward_client_is_recorded() {
+        let state = Mailtutan {
+            xforward: true,
+            received_header: true,
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "10.0.0.1:40000".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO proxy.internal",
+                "XFORWARD NAME=client.example ADDR=192.0.2.44 PROTO=ESMTP",
+                "XFORWARD HELO=laptop+2Eexample PORT=51234",
+                "XFORWARD BOGUS=1",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: forwarded",
+                "",
+                ".",
+                "MAIL FROM:<sender@example.com>",
+                "XFORWARD ADDR=192.0.2.45",
+            ],
+        )
+        .await;
+        assert_eq!(
+            codes,
+            vec![250, 250, 250, 501, 250, 250, 354, 250, 250, 503]
+        );
+
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(messages[0].client_ip.as_deref(), Some("192.0.2.44"));
+        assert_eq!(messages[0].client_host.as_deref(), Some("client.example"));
+        assert_eq!(messages[0].helo.as_deref(), Some("laptop.example"));
+        assert!(String::from_utf8_lossy(&messages[0].source).starts_with(
+            "Received: from laptop.example (client.example [192.0.2.44])\r\n\tby mailtutan with ESMTP; "
+        ));
+
+        let state = Mailtutan::default().init();
+        let mut session = Session::new(state, "10.0.0.1:40000".parse().unwrap());
+        let codes = send(
+            &mut session,
+            &["EHLO proxy.internal", "XFORWARD ADDR=192.0.2.44"],
+        )
+        .await;
+        assert_eq!(codes, vec![250, 500]);
+    }
+
+    #[tokio::test]
+    async fn test_strict_parse_rejects_malformed_message() {
+        let mut app = Mailtutan::default();
+        app.parse_options.strict = true;
+        let state = app.init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+
+        let codes = send(
+            &mut session,
+            &[
+                "EHLO client.example",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<rcpt@example.com>",
+                "DATA",
+                "Subject: malformed",
+                "not a header line",
+                "",
+                "body",
+                ".",
+            ],
+        )
+        .await;
+        assert_eq!(codes.last(), Some(&554));
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_attachment_limits_are_rejected_with_554() {
+        let mut app = Mailtutan::default();
+        app.parse_options.max_attachments = Some(1);
+        let state = app.init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+
+        let data = MessageBuilder::new()
+            .subject("many parts")
+            .attachment("1.txt", "text/plain", b"tiny")
+            .attachment("2.txt", "text/plain", b"tiny")
+            .build();
+        let data = String::from_utf8(data).unwrap();
+        let mut lines = vec![
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+        ];
+        lines.extend(data.lines());
+        lines.push(".");
+
+        let codes = send(&mut session, &lines).await;
+        assert_eq!(codes.last(), Some(&554));
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_attachment_is_rejected_with_552() {
+        let mut app = Mailtutan::default();
+        app.parse_options.max_attachment_size = Some(4);
+        let state = app.init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+
+        let data = MessageBuilder::new()
+            .subject("big")
+            .attachment("big.bin", "application/octet-stream", b"too many bytes")
+            .build();
+        let data = String::from_utf8(data).unwrap();
+        let mut lines = vec![
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+        ];
+        lines.extend(data.lines());
+        lines.push(".");
+
+        let codes = send(&mut session, &lines).await;
+        assert_eq!(codes.last(), Some(&552));
+        assert_eq!(state.lock().unwrap().storage.size(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let state = Mailtutan {
+            rate_limit: Some(RateLimiter::new(1.0, 3)),
+            ..Default::default()
+        }
+        .init();
+        let mut session = Session::new(state.clone(), "127.0.0.1:2525".parse().unwrap());
+        send(&mut session, &["EHLO client.example"]).await;
+
+        let mut codes: Vec<u16> = vec![];
+        for _ in 0..6 {
+            let replies = send(
+                &mut session,
+                &[
+                    "MAIL FROM:<sender@example.com>",
+                    "RCPT TO:<rcpt@example.com>",
+                    "DATA",
+                    "Subject: flood",
+                    "",
+                    ".",
+                ],
+            )
+            .await;
+            codes.extend(replies.last().copied());
+        }
+
+        assert_eq!(codes, vec![250, 250, 250, 451, 451, 451]);
+        assert_eq!(state.lock().unwrap().storage.size(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_received_header() {
+        let delivery = [
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+            "Subject: traced",
+            "",
+            "body",
+            ".",
+        ];
+
+        for received_header in [true, false] {
+            let state = Mailtutan {
+                received_header,
+                ..Default::default()
+            }
+            .init();
+            let mut session = Session::new(state.clone(), "192.0.2.7:40000".parse().unwrap());
+            send(&mut session, &delivery).await;
+
+            let message = state.lock().unwrap().storage.list().unwrap().remove(0);
+            let source = String::from_utf8(message.source).unwrap();
+            assert_eq!(
+                message.content_id,
+                crate::models::content_id(b"Subject: traced\r\n\r\nbody\r\n")
+            );
+
+            if received_header {
+                assert!(source.starts_with(
+                    "Received: from client.example ([192.0.2.7])\r\n\tby mailtutan with ESMTP; "
+                ));
+                assert_eq!(message.received.len(), 1);
+                assert_eq!(message.subject, "traced");
+            } else {
+                assert_eq!(source, "Subject: traced\r\n\r\nbody\r\n");
+                assert!(message.received.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_received_header_in_maildir() {
+        let path = std::env::temp_dir().join(format!("mailtutan-received-{}", std::process::id()));
+        let state = Mailtutan {
+            received_header: true,
+            storage: Box::new(Memdir::new(10, path.to_str().unwrap()).unwrap()),
+            ..Default::default()
+        }
+        .init();
+
+        for client in ["192.0.2.7:40000", "192.0.2.8:40000"] {
+            let mut session = Session::new(state.clone(), client.parse().unwrap());
+            send(
+                &mut session,
+                &[
+                    "EHLO client.example",
+                    "MAIL FROM:<sender@example.com>",
+                    "RCPT TO:<rcpt@example.com>",
+                    "DATA",
+                    "Subject: traced",
+                    "",
+                    "body",
+                    ".",
+                ],
+            )
+            .await;
+        }
+
+        // Read back from disk, where the source starts with the trace
+        // header.
+        let messages = state.lock().unwrap().storage.list().unwrap();
+        assert_eq!(messages.len(), 2);
+        for message in messages {
+            assert_eq!(message.received.len(), 1);
+            assert_eq!(
+                message.content_id,
+                crate::models::content_id(b"Subject: traced\r\n\r\nbody\r\n")
+            );
+        }
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_source_framing() {
+        let delivery = [
+            "EHLO client.example",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<rcpt@example.com>",
+            "DATA",
+            "Subject: dots",
+            "",
+            "..leading dot",
+            ".",
+        ];
+
+        for raw_source in [false, true] {
+            let state = Mailtutan {
+                raw_source,
+                ..Default::default()
+            }
+            .init();
+            let mut session = Session::new(state.clone(), "192.0.2.7:40000".parse().unwrap());
+            send(&mut session, &delivery).await;
+
+            let message = state.lock().unwrap().storage.list().unwrap().remove(0);
+            assert_eq!(message.plain.as_deref(), Some(".leading dot\r\n"));
+
+            if raw_source {
+                assert_eq!(
+                    message.source,
+                    b"Subject: dots\r\n\r\n..leading dot\r\n.\r\n"
+                );
+            } else {
+                assert!(!message.source.ends_with(b"\r\n.\r\n"));
+                assert_eq!(message.source, b"Subject: dots\r\n\r\n.leading dot\r\n");
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{Error, Result};
+use std::sync::Arc;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, ProtocolVersion, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Builds the STARTTLS acceptor from PEM certificate chain and key files.
+pub fn acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let read = |path: &str| {
+        std::fs::read(path).map_err(|err| Error::Config(format!("reading {}: {}", path, err)))
+    };
+
+    acceptor_from_pem(&read(cert_path)?, &read(key_path)?)
+}
+
+pub fn acceptor_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<TlsAcceptor> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut &cert_pem[..])
+        .map_err(|err| Error::Config(format!("invalid certificate: {}", err)))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certs.is_empty() {
+        return Err(Error::Config("no certificate found".to_owned()));
+    }
+
+    let key = rustls_pemfile::read_all(&mut &key_pem[..])
+        .map_err(|err| Error::Config(format!("invalid private key: {}", err)))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::Config("no private key found".to_owned()))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| Error::Config(format!("invalid certificate or key: {}", err)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Negotiated protocol version and cipher suite, e.g. `TLSv1.3` and
+/// `TLS13_AES_256_GCM_SHA384`.
+pub fn negotiated(connection: &rustls::ServerConnection) -> (Option<String>, Option<String>) {
+    let version = connection.protocol_version().map(|version| match version {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_owned(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_owned(),
+        other => format!("{:?}", other),
+    });
+    let cipher = connection
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()));
+
+    (version, cipher)
+}
@@ -0,0 +1,192 @@
+use crate::models::{Message, ParseFailure, ParseOptions};
+use crate::{Error, Mailtutan};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+
+/// Raw DATA of a transaction waiting to be parsed.
+pub struct Job {
+    /// Reserved from the storage at enqueue, so the reply can name it.
+    pub id: usize,
+    pub data: Vec<u8>,
+    /// Wire bytes to store as `source` instead of `data`, in raw mode.
+    pub wire: Option<Vec<u8>>,
+    /// `content_id` to keep instead of the hash of `data`, which may start
+    /// with an added Received header.
+    pub content_id: Option<String>,
+    pub envelope_sender: String,
+    pub envelope_recipients: Vec<String>,
+    pub client_ip: Option<String>,
+    pub client_host: Option<String>,
+    pub helo: Option<String>,
+    pub tls_version: Option<String>,
+    pub tls_cipher: Option<String>,
+    pub transaction_ms: Option<u64>,
+    pub transcript: Vec<String>,
+    pub parse_options: ParseOptions,
+}
+
+/// Parses messages on a fixed set of threads so SMTP sessions don't wait on
+/// it. Parsing runs concurrently, but messages are stored in the order they
+/// were enqueued, under the ids reserved for them then.
+pub struct ParsePool {
+    sender: Mutex<Sender<(u64, Job)>>,
+    next_ticket: AtomicU64,
+}
+
+/// Ticket of the next job allowed to be stored.
+type Turn = (Mutex<u64>, Condvar);
+
+impl ParsePool {
+    pub fn start(state: Weak<Mutex<Mailtutan>>, workers: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let turn: Arc<Turn> = Arc::new((Mutex::new(0), Condvar::new()));
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let turn = turn.clone();
+            let state = state.clone();
+
+            thread::spawn(move || work(&receiver, &turn, &state));
+        }
+
+        Self {
+            sender: Mutex::new(sender),
+            next_ticket: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enqueue(&self, job: Job) {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+
+        self.sender.lock().unwrap().send((ticket, job)).ok();
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<(u64, Job)>>, turn: &Turn, state: &Weak<Mutex<Mailtutan>>) {
+    loop {
+        let next = receiver.lock().unwrap().recv();
+        let Ok((ticket, job)) = next else {
+            return;
+        };
+
+        // The ticket is handed on while unwinding too, so a panicking parse
+        // neither stalls the jobs after it nor takes the worker down.
+        let ticket = Ticket {
+            turn,
+            number: ticket,
+        };
+        let processed = panic::catch_unwind(AssertUnwindSafe(|| process(ticket, job, state)));
+
+        if processed.is_err() {
+            eprintln!("parsing queued message panicked");
+        }
+    }
+}
+
+/// Place of a job in the storing order.
+struct Ticket<'a> {
+    turn: &'a Turn,
+    number: u64,
+}
+
+impl Ticket<'_> {
+    /// Blocks until the jobs before this one are done.
+    fn wait(&self) {
+        let (current, condvar) = self.turn;
+        let _current = condvar
+            .wait_while(lock(current), |current| *current != self.number)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        self.wait();
+
+        let (current, condvar) = self.turn;
+        *lock(current) += 1;
+        condvar.notify_all();
+    }
+}
+
+fn lock(current: &Mutex<u64>) -> MutexGuard<'_, u64> {
+    current.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn process(ticket: Ticket, job: Job, state: &Weak<Mutex<Mailtutan>>) {
+    let parsed = Message::parse(&job.data, &job.parse_options);
+
+    ticket.wait();
+
+    match (parsed, state.upgrade()) {
+        (Ok(mut message), Some(state)) => {
+            message.id = Some(job.id);
+            message.envelope_sender = job.envelope_sender;
+            message.envelope_recipients = job.envelope_recipients;
+            message.client_ip = job.client_ip;
+            message.client_host = job.client_host;
+            message.helo = job.helo;
+            message.tls_version = job.tls_version;
+            message.tls_cipher = job.tls_cipher;
+            message.transaction_ms = job.transaction_ms;
+            message.transcript = job.transcript;
+
+            if let Some(content_id) = job.content_id {
+                message.content_id = content_id;
+            }
+            if let Some(wire) = job.wire {
+                message.set_source(wire);
+            }
+
+            if let Err(err) = state.lock().unwrap().capture(message) {
+                eprintln!("storing queued message failed: {}", err);
+            }
+        }
+        (Err(err), state) => {
+            eprintln!("parsing queued message failed: {}", err);
+
+            // Strict mode rejects such messages on purpose, they aren't
+            // failures to inspect.
+            if let (Error::Parse(reason), Some(state)) = (err, state) {
+                if !job.parse_options.strict {
+                    let mut failure = ParseFailure::new(&job.data, reason);
+                    failure.envelope_sender = job.envelope_sender;
+                    failure.envelope_recipients = job.envelope_recipients;
+
+                    state.lock().unwrap().record_parse_failure(failure);
+                }
+            }
+        }
+        (_, None) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panicking_job_hands_on_its_ticket() {
+        let turn: Turn = (Mutex::new(0), Condvar::new());
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ticket = Ticket {
+                turn: &turn,
+                number: 0,
+            };
+            panic!("parser bug");
+        }));
+        assert!(panicked.is_err());
+
+        // Would wait forever if the first ticket hadn't been handed on.
+        drop(Ticket {
+            turn: &turn,
+            number: 1,
+        });
+        assert_eq!(*lock(&turn.0), 2);
+    }
+}
@@ -1,38 +1,258 @@
 use crate::models::Message;
+use crate::{Error, Result};
+use bytes::Bytes;
 
 mod memdir;
 mod memory;
+mod sequence;
+mod tee;
 pub use memdir::Memdir;
 pub use memory::Memory;
+pub use sequence::Sequence;
+pub use tee::Tee;
 
 pub trait Storage: Sync + Send {
-    fn list(&self) -> Vec<Message>;
-    fn add(&mut self, message: Message) -> Message;
-    fn get(&self, item: usize) -> Message;
-    fn remove(&mut self, item: usize);
+    fn list(&self) -> Result<Vec<Message>>;
+    /// Ids of the stored messages, in no particular order, without loading
+    /// the messages where the backend can avoid it.
+    fn ids(&self) -> Result<Vec<usize>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter_map(|message| message.id)
+            .collect())
+    }
+    /// Messages with an id greater than `id`, newest first.
+    fn list_since(&self, id: usize) -> Result<Vec<Message>> {
+        let mut list: Vec<Message> = self
+            .list()?
+            .into_iter()
+            .filter(|message| message.id.is_some_and(|item| item > id))
+            .collect();
+        list.sort_by_key(|message| std::cmp::Reverse(message.id));
+
+        Ok(list)
+    }
+    fn add(&mut self, message: Message) -> Result<Message>;
+    /// Stores a message under the id it already carries, e.g. one assigned
+    /// by another storage. Later [`Storage::add`] calls continue after it.
+    fn insert(&mut self, message: Message) -> Result<Message>;
+    /// Takes the next id for a message that is stored later through
+    /// [`Storage::insert`], e.g. once it's parsed. An id that ends up
+    /// unused leaves a gap.
+    fn reserve_id(&mut self) -> Result<usize>;
+    fn get(&self, item: usize) -> Result<Message>;
+    /// Filename and body of the attachment of message `item` whose part id
+    /// or Content-ID is `cid`, without handing out the rest of the message.
+    fn attachment(&self, item: usize, cid: &str) -> Result<Option<(String, Bytes)>> {
+        find_attachment(&self.get(item)?, cid)
+    }
+    /// Replaces the stored metadata of an existing message, keyed by its id.
+    fn update(&mut self, message: Message) -> Result<Message>;
+    fn remove(&mut self, item: usize) -> Result<()>;
     fn size(&self) -> usize;
-    fn delete_all(&mut self);
+    /// Changes the capacity, removing the oldest messages beyond it.
+    fn set_messages_limit(&mut self, limit: usize) -> Result<()>;
+    /// Messages the storage removed on its own to stay within the limit
+    /// since the last call.
+    fn take_evicted(&mut self) -> Vec<Message> {
+        vec![]
+    }
+    /// Id of the newest stored message.
+    fn last_id(&self) -> Option<usize> {
+        self.list()
+            .ok()?
+            .into_iter()
+            .filter_map(|message| message.id)
+            .max()
+    }
+    fn delete_all(&mut self) -> Result<()>;
+    /// Counts the changes to the stored messages: every add, update,
+    /// removal and clear bumps it, edits in place included.
+    fn revision(&self) -> u64;
+    /// Whether the backend can currently serve requests.
+    fn healthy(&self) -> bool {
+        true
+    }
+}
+
+/// Looks up an attachment for [`Storage::attachment`].
+fn find_attachment(message: &Message, cid: &str) -> Result<Option<(String, Bytes)>> {
+    if message.bodies_discarded {
+        return Err(Error::Gone(format!(
+            "bodies of message {} were discarded in headers-only mode",
+            message.id.unwrap_or_default()
+        )));
+    }
+
+    Ok(message
+        .attachments
+        .iter()
+        .find(|attachment| attachment.cid == cid || attachment.content_id.as_deref() == Some(cid))
+        .map(|attachment| {
+            (
+                attachment.filename.clone(),
+                Bytes::copy_from_slice(&attachment.body),
+            )
+        }))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::Memdir;
     use super::Memory;
     use super::Storage;
     use crate::models::Message;
+    use crate::Error;
     use std::assert_eq;
 
     #[test]
     fn test_store() {
         let mut store = Memory::new(1000);
 
-        store.add(Message {
-            ..Default::default()
-        });
+        store
+            .add(Message {
+                ..Default::default()
+            })
+            .unwrap();
 
         assert_eq!(store.size(), 1);
 
-        store.delete_all();
+        store.delete_all().unwrap();
 
         assert_eq!(store.size(), 0);
     }
+
+    #[test]
+    fn test_content_id_is_stable() {
+        let mut store = Memory::new(1000);
+
+        let data = concat!(
+            "From: Private Person <me@fromdomain.com>\n",
+            "To: A Test User <test@todomain.com>\n",
+            "Subject: SMTP e-mail test\n",
+            "\n",
+            "This is a test e-mail message.\n"
+        )
+        .as_bytes()
+        .to_vec();
+
+        let first = store.add(Message::try_from(&data).unwrap()).unwrap();
+        let second = store.add(Message::try_from(&data).unwrap()).unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.content_id, second.content_id);
+        assert_eq!(first.content_id.len(), 64);
+    }
+
+    #[test]
+    fn test_missing_message_is_storage_error() {
+        let mut store = Memory::new(1000);
+
+        assert!(matches!(store.get(42), Err(Error::Storage(_))));
+        assert!(matches!(store.remove(42), Err(Error::Storage(_))));
+    }
+
+    #[test]
+    fn test_maildir_path_errors() {
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+
+        assert!(matches!(Memdir::new(10, manifest), Err(Error::Config(_))));
+        assert!(matches!(
+            Memdir::new(10, format!("{}/maildir", manifest)),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_maildir_keeps_labels() {
+        let path = std::env::temp_dir().join(format!("mailtutan-labels-{}", std::process::id()));
+        let mut store = Memdir::new(10, path.to_str().unwrap()).unwrap();
+
+        let data = b"Subject: labels\r\n\r\nbody\r\n".to_vec();
+        let mut message = store.add(Message::try_from(&data).unwrap()).unwrap();
+        message.labels.push("password-reset".to_owned());
+        store.update(message.clone()).unwrap();
+
+        let stored = store.get(message.id.unwrap()).unwrap();
+        assert_eq!(stored.labels, vec!["password-reset"]);
+        assert_eq!(store.size(), 1);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_maildir_ids_survive_restart() {
+        let path = std::env::temp_dir().join(format!("mailtutan-ids-{}", std::process::id()));
+        let data = b"Subject: ids\r\n\r\nbody\r\n".to_vec();
+
+        let mut store = Memdir::new(10, path.to_str().unwrap()).unwrap();
+        let first = store.add(Message::try_from(&data).unwrap()).unwrap();
+        let second = store.add(Message::try_from(&data).unwrap()).unwrap();
+        store.delete_all().unwrap();
+        drop(store);
+
+        let mut store = Memdir::new(10, path.to_str().unwrap()).unwrap();
+        let third = store.add(Message::try_from(&data).unwrap()).unwrap();
+
+        assert!(first.id < second.id);
+        assert!(third.id > second.id);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_eviction_skips_deleted_ids() {
+        let path = std::env::temp_dir().join(format!("mailtutan-evict-{}", std::process::id()));
+        let stores: Vec<Box<dyn Storage>> = vec![
+            Box::new(Memory::new(2)),
+            Box::new(Memdir::new(2, path.to_str().unwrap()).unwrap()),
+        ];
+        let data = b"Subject: evict\r\n\r\nbody\r\n".to_vec();
+
+        for mut store in stores {
+            let mut ids = vec![];
+            for _ in 0..2 {
+                ids.push(store.add(Message::try_from(&data).unwrap()).unwrap().id);
+            }
+            store.remove(ids[1].unwrap()).unwrap();
+
+            // `id - messages_limit` of the fourth message is the removed one.
+            for _ in 0..2 {
+                ids.push(store.add(Message::try_from(&data).unwrap()).unwrap().id);
+            }
+
+            let mut stored: Vec<_> = store.list().unwrap().iter().map(|m| m.id).collect();
+            stored.sort();
+            assert_eq!(stored, vec![ids[2], ids[3]]);
+
+            let evicted: Vec<_> = store.take_evicted().iter().map(|m| m.id).collect();
+            assert_eq!(evicted, vec![ids[0]]);
+        }
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_attachment() {
+        let mut store = Memory::new(10).with_compression(true);
+        let data = crate::test_util::MessageBuilder::new()
+            .subject("Attachment")
+            .plain("see attached")
+            .attachment("report.pdf", "application/pdf", b"%PDF-1.4 fake")
+            .build();
+        let message = store.add(Message::try_from(&data).unwrap()).unwrap();
+        let id = message.id.unwrap();
+        let cid = &message.attachments[0].cid;
+
+        let (filename, body) = store.attachment(id, cid).unwrap().unwrap();
+        assert_eq!(filename, "report.pdf");
+        assert_eq!(&body[..], b"%PDF-1.4 fake");
+
+        assert!(store.attachment(id, "missing").unwrap().is_none());
+        assert!(matches!(
+            store.attachment(id + 1, cid),
+            Err(Error::Storage(_))
+        ));
+    }
 }
@@ -1,110 +1,368 @@
-use crate::models::Message;
-use crate::Storage;
+use super::Sequence;
+use crate::models::{Message, ParseOptions};
+use crate::{Error, Result, Storage};
+use serde::{Deserialize, Serialize};
 use std::fs;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::path::Path;
 use std::str::FromStr;
 
+/// Fields that can't be recovered by reparsing the `.eml` file, kept in a
+/// `<id>.json` file next to it.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Metadata {
+    created_at: Option<String>,
+    envelope_sender: String,
+    envelope_recipients: Vec<String>,
+    client_ip: Option<String>,
+    client_host: Option<String>,
+    helo: Option<String>,
+    tls_version: Option<String>,
+    tls_cipher: Option<String>,
+    transaction_ms: Option<u64>,
+    parse_ms: Option<u64>,
+    transcript: Vec<String>,
+    labels: Vec<String>,
+    bodies_discarded: bool,
+    spam: bool,
+    mailbox: String,
+    quarantined: bool,
+    sequence: Option<String>,
+    size: Option<usize>,
+    /// Hash of the data as received, which the `.eml` no longer is once a
+    /// Received header was added.
+    content_id: Option<String>,
+}
+
+impl Metadata {
+    fn from_message(message: &Message) -> Self {
+        Self {
+            created_at: message.created_at.clone(),
+            envelope_sender: message.envelope_sender.clone(),
+            envelope_recipients: message.envelope_recipients.clone(),
+            client_ip: message.client_ip.clone(),
+            client_host: message.client_host.clone(),
+            helo: message.helo.clone(),
+            tls_version: message.tls_version.clone(),
+            tls_cipher: message.tls_cipher.clone(),
+            transaction_ms: message.transaction_ms,
+            parse_ms: Some(message.parse_ms),
+            transcript: message.transcript.clone(),
+            labels: message.labels.clone(),
+            bodies_discarded: message.bodies_discarded,
+            spam: message.spam,
+            mailbox: message.mailbox.clone(),
+            quarantined: message.quarantined,
+            sequence: message.sequence.clone(),
+            size: Some(message.size),
+            content_id: Some(message.content_id.clone()),
+        }
+    }
+
+    fn apply(self, message: &mut Message) {
+        message.created_at = self.created_at;
+        message.envelope_sender = self.envelope_sender;
+        message.envelope_recipients = self.envelope_recipients;
+        message.client_ip = self.client_ip;
+        message.client_host = self.client_host;
+        message.helo = self.helo;
+        message.tls_version = self.tls_version;
+        message.tls_cipher = self.tls_cipher;
+        message.transaction_ms = self.transaction_ms;
+        message.parse_ms = self.parse_ms.unwrap_or(message.parse_ms);
+        message.transcript = self.transcript;
+        message.labels = self.labels;
+        message.spam = self.spam;
+        message.mailbox = self.mailbox;
+        message.quarantined = self.quarantined;
+        message.sequence = self.sequence;
+        message.size = self.size.unwrap_or(message.size);
+        message.content_id = self.content_id.unwrap_or(message.content_id.clone());
+
+        if self.bodies_discarded {
+            message.discard_bodies();
+        }
+    }
+}
+
+/// Holds the highest id ever assigned, so ids aren't reused after the
+/// newest messages were deleted and the process restarted.
+const SEQUENCE_FILE: &str = "sequence";
+
 pub struct Memdir {
-    sequence_id: usize,
+    sequence: Sequence,
     messages_limit: usize,
     path: Box<Path>,
+    parse_options: ParseOptions,
+    /// Removed to stay within `messages_limit`, not yet taken.
+    evicted: Vec<Message>,
+    /// See [`Storage::revision`].
+    revision: u64,
 }
 
 impl Memdir {
-    pub fn new<T: AsRef<str>>(capacity: usize, path: T) -> Self {
+    pub fn new<T: AsRef<str>>(capacity: usize, path: T) -> Result<Self> {
         let path = Path::new(path.as_ref());
 
         if path.exists() {
             if !path.is_dir() {
-                panic!("path exists, but it is not a dir");
+                return Err(Error::Config(format!(
+                    "{} exists, but it is not a dir",
+                    path.display()
+                )));
             }
         } else {
-            fs::create_dir(path).unwrap();
+            fs::create_dir(path)?;
         }
 
-        Self {
-            sequence_id: Self::find_last_sequence_id(path) + 1,
+        Ok(Self {
+            sequence: Sequence::after(Self::find_last_sequence_id(path)?),
             messages_limit: capacity,
             path: path.into(),
-        }
+            parse_options: ParseOptions::default(),
+            evicted: vec![],
+            revision: 0,
+        })
+    }
+
+    /// Options used when reparsing the stored `.eml` files.
+    pub fn with_parse_options(mut self, parse_options: ParseOptions) -> Self {
+        self.parse_options = parse_options;
+        self
     }
 
-    fn find_last_sequence_id(path: &Path) -> usize {
-        let mut last_sequence_id = 0;
+    fn evict(&mut self, item: usize) -> Result<()> {
+        let message = self.get(item)?;
+        self.remove(item)?;
+        self.evicted.push(message);
+
+        Ok(())
+    }
+
+    fn evict_oldest(&mut self) -> Result<()> {
+        match Self::read_ids(&self.path)?.into_iter().min() {
+            Some(oldest) => self.evict(oldest),
+            None => Ok(()),
+        }
+    }
 
-        for path in fs::read_dir(path).unwrap() {
-            let path = path.unwrap().path();
-            let id = usize::from_str(path.file_stem().unwrap().to_str().unwrap()).unwrap();
+    fn find_last_sequence_id(path: &Path) -> Result<usize> {
+        let mut last_sequence_id = match fs::read_to_string(path.join(SEQUENCE_FILE)) {
+            Ok(text) => usize::from_str(text.trim())
+                .map_err(|err| Error::Storage(format!("invalid {}: {}", SEQUENCE_FILE, err)))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(Error::Io(err)),
+        };
 
+        for id in Self::read_ids(path)? {
             if id > last_sequence_id {
                 last_sequence_id = id;
             }
         }
 
-        last_sequence_id
+        Ok(last_sequence_id)
+    }
+
+    fn read_ids(path: &Path) -> Result<Vec<usize>> {
+        let mut ids = vec![];
+
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+
+            if path.extension().is_some_and(|ext| ext == "eml") {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| usize::from_str(stem).ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn write_metadata(&self, message: &Message) -> Result<()> {
+        let filename = format!("{}.json", message.id.unwrap_or_default());
+        let metadata = serde_json::to_vec(&Metadata::from_message(message))
+            .map_err(|err| Error::Storage(err.to_string()))?;
+
+        fs::write(self.path.join(filename), metadata)?;
+
+        Ok(())
+    }
+
+    fn read_metadata(&self, item: usize) -> Result<Option<Metadata>> {
+        let filename = format!("{}.json", item);
+
+        match fs::read(self.path.join(filename)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|err| Error::Storage(err.to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
+    fn not_found(item: usize, err: std::io::Error) -> Error {
+        if err.kind() == ErrorKind::NotFound {
+            Error::Storage(format!("message {} not found", item))
+        } else {
+            Error::Io(err)
+        }
     }
 }
 
 impl Storage for Memdir {
-    fn list(&self) -> Vec<Message> {
+    fn list(&self) -> Result<Vec<Message>> {
         let mut list: Vec<Message> = vec![];
 
-        for path in fs::read_dir(&self.path).unwrap() {
-            let path = path.unwrap().path();
-            let id = usize::from_str(path.file_stem().unwrap().to_str().unwrap()).unwrap();
-
-            list.push(self.get(id));
+        for id in Self::read_ids(&self.path)? {
+            list.push(self.get(id)?);
         }
 
-        list
+        Ok(list)
+    }
+
+    fn ids(&self) -> Result<Vec<usize>> {
+        Self::read_ids(&self.path)
+    }
+
+    fn list_since(&self, id: usize) -> Result<Vec<Message>> {
+        let mut ids: Vec<usize> = Self::read_ids(&self.path)?
+            .into_iter()
+            .filter(|item| *item > id)
+            .collect();
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+
+        ids.into_iter().map(|item| self.get(item)).collect()
+    }
+
+    fn add(&mut self, mut message: Message) -> Result<Message> {
+        message.id = Some(self.sequence.next());
+
+        self.insert(message)
+    }
+
+    fn reserve_id(&mut self) -> Result<usize> {
+        let id = self.sequence.next();
+        fs::write(
+            self.path.join(SEQUENCE_FILE),
+            self.sequence.last().to_string(),
+        )?;
+
+        Ok(id)
     }
 
-    fn add(&mut self, mut message: Message) -> Message {
-        message.id = Some(self.sequence_id);
+    fn insert(&mut self, message: Message) -> Result<Message> {
+        let id = message
+            .id
+            .ok_or_else(|| Error::Storage("message has no id".to_owned()))?;
+        self.sequence.skip_past(id);
+        fs::write(
+            self.path.join(SEQUENCE_FILE),
+            self.sequence.last().to_string(),
+        )?;
 
-        let filename = format!("{}.eml", self.sequence_id);
-        let mut file = fs::File::create(self.path.join(filename)).unwrap();
-        file.write_all(&message.source).unwrap();
+        let filename = format!("{}.eml", id);
+        let mut file = fs::File::create(self.path.join(filename))?;
+        file.write_all(&message.source)?;
+        self.write_metadata(&message)?;
+        self.revision += 1;
 
+        // Eviction only makes room, so failing at it doesn't undo the insert.
         if self.size() > self.messages_limit {
-            let record_to_delete = self.sequence_id - self.messages_limit;
-            self.remove(record_to_delete);
+            if let Err(err) = self.evict_oldest() {
+                eprintln!("evicting the oldest message failed: {}", err);
+            }
         }
 
-        self.sequence_id += 1;
-
-        message
+        Ok(message)
     }
 
-    fn get(&self, item: usize) -> Message {
+    fn get(&self, item: usize) -> Result<Message> {
         let filename = format!("{}.eml", item);
 
-        let bytes = fs::read(self.path.join(filename)).unwrap();
+        let bytes = fs::read(self.path.join(filename)).map_err(|err| Self::not_found(item, err))?;
 
-        let mut message = Message::from(&bytes);
+        let mut message = Message::parse(&bytes, &self.parse_options)?;
         message.id = Some(item);
 
-        message
+        if let Some(metadata) = self.read_metadata(item)? {
+            metadata.apply(&mut message);
+        }
+
+        Ok(message)
+    }
+
+    fn update(&mut self, message: Message) -> Result<Message> {
+        let id = message.id.unwrap_or_default();
+        let filename = format!("{}.eml", id);
+
+        if !self.path.join(filename).exists() {
+            return Err(Error::Storage(format!("message {} not found", id)));
+        }
+
+        self.write_metadata(&message)?;
+        self.revision += 1;
+
+        Ok(message)
     }
 
-    fn remove(&mut self, item: usize) {
+    fn remove(&mut self, item: usize) -> Result<()> {
         let filename = format!("{}.eml", item);
 
         let path = self.path.join(filename);
-        fs::remove_file(path).unwrap();
+        fs::remove_file(path).map_err(|err| Self::not_found(item, err))?;
+        self.revision += 1;
+
+        match fs::remove_file(self.path.join(format!("{}.json", item))) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(Error::Io(err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_messages_limit(&mut self, limit: usize) -> Result<()> {
+        self.messages_limit = limit;
+
+        let mut ids = Self::read_ids(&self.path)?;
+        ids.sort_unstable();
+
+        for id in ids.iter().take(ids.len().saturating_sub(limit)) {
+            self.evict(*id)?;
+        }
+
+        Ok(())
+    }
+
+    fn take_evicted(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    fn last_id(&self) -> Option<usize> {
+        Self::read_ids(&self.path).ok()?.into_iter().max()
     }
 
     fn size(&self) -> usize {
-        fs::read_dir(&self.path).unwrap().count()
+        Self::read_ids(&self.path).map_or(0, |ids| ids.len())
     }
 
-    fn delete_all(&mut self) {
-        for path in fs::read_dir(&self.path).unwrap() {
-            let path = path.unwrap().path();
-            if path.extension().unwrap() == "eml" {
-                fs::remove_file(path).unwrap();
-            }
+    fn delete_all(&mut self) -> Result<()> {
+        for id in Self::read_ids(&self.path)? {
+            self.remove(id)?;
         }
+        self.revision += 1;
+
+        Ok(())
+    }
+
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn healthy(&self) -> bool {
+        self.path.is_dir()
     }
 }
@@ -1,63 +1,263 @@
+use super::{find_attachment, Sequence};
 use crate::models::Message;
-use crate::Storage;
+use crate::{Error, Result, Storage};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::collections::HashMap;
+use std::io::{Read, Write};
 
 #[derive(Default)]
 pub struct Memory {
-    sequence_id: usize,
+    sequence: Sequence,
     messages_limit: usize,
     records: HashMap<usize, Message>,
+    compress: bool,
+    /// Removed to stay within `messages_limit`, not yet taken.
+    evicted: Vec<Message>,
+    /// See [`Storage::revision`].
+    revision: u64,
 }
 
 impl Memory {
     pub fn new(capacity: usize) -> Self {
         Self {
             records: HashMap::new(),
-            sequence_id: 1,
+            sequence: Sequence::default(),
             messages_limit: capacity,
+            compress: false,
+            evicted: vec![],
+            revision: 0,
         }
     }
+
+    /// Keep `source` gzip-compressed while stored. Messages handed out by
+    /// the storage always carry the original bytes.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    fn evict(&mut self, item: usize) -> Result<()> {
+        let message = self.get(item)?;
+        self.remove(item)?;
+        self.evicted.push(message);
+
+        Ok(())
+    }
+
+    fn evict_oldest(&mut self) -> Result<()> {
+        match self.records.keys().min().copied() {
+            Some(oldest) => self.evict(oldest),
+            None => Ok(()),
+        }
+    }
+
+    fn pack(&self, message: &Message) -> Result<Message> {
+        let mut record = message.clone();
+
+        if self.compress {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(&message.source)?;
+            record.source = encoder.finish()?;
+        }
+
+        Ok(record)
+    }
+
+    fn unpack(&self, record: &Message) -> Result<Message> {
+        let mut message = record.clone();
+
+        if self.compress {
+            message.source = vec![];
+            GzDecoder::new(record.source.as_slice()).read_to_end(&mut message.source)?;
+        }
+
+        Ok(message)
+    }
 }
 
 impl Storage for Memory {
-    fn list(&self) -> Vec<Message> {
+    fn list(&self) -> Result<Vec<Message>> {
         let mut list: Vec<Message> = vec![];
 
         for record in self.records.values() {
-            list.push(record.clone());
+            list.push(self.unpack(record)?);
         }
 
-        list
+        Ok(list)
+    }
+
+    fn ids(&self) -> Result<Vec<usize>> {
+        Ok(self.records.keys().copied().collect())
     }
 
-    fn add(&mut self, mut message: Message) -> Message {
-        message.id = Some(self.sequence_id);
+    fn list_since(&self, id: usize) -> Result<Vec<Message>> {
+        // Only the records after `id` are unpacked.
+        let mut records: Vec<(&usize, &Message)> = self
+            .records
+            .iter()
+            .filter(|(item, _)| **item > id)
+            .collect();
+        records.sort_by_key(|(item, _)| std::cmp::Reverse(**item));
+
+        records
+            .into_iter()
+            .map(|(_, record)| self.unpack(record))
+            .collect()
+    }
 
-        self.records.insert(self.sequence_id, message.clone());
+    fn add(&mut self, mut message: Message) -> Result<Message> {
+        message.id = Some(self.sequence.next());
 
+        self.insert(message)
+    }
+
+    fn reserve_id(&mut self) -> Result<usize> {
+        Ok(self.sequence.next())
+    }
+
+    fn insert(&mut self, message: Message) -> Result<Message> {
+        let id = message
+            .id
+            .ok_or_else(|| Error::Storage("message has no id".to_owned()))?;
+        self.sequence.skip_past(id);
+
+        let record = self.pack(&message)?;
+        self.records.insert(id, record);
+        self.revision += 1;
+
+        // Eviction only makes room, so failing at it doesn't undo the insert.
         if self.size() > self.messages_limit {
-            let record_to_delete = self.sequence_id - self.messages_limit;
-            self.remove(record_to_delete);
+            if let Err(err) = self.evict_oldest() {
+                eprintln!("evicting the oldest message failed: {}", err);
+            }
+        }
+
+        Ok(message)
+    }
+
+    fn get(&self, item: usize) -> Result<Message> {
+        let record = self
+            .records
+            .get(&item)
+            .ok_or_else(|| Error::Storage(format!("message {} not found", item)))?;
+
+        self.unpack(record)
+    }
+
+    fn attachment(&self, item: usize, cid: &str) -> Result<Option<(String, Bytes)>> {
+        // Only `source` is compressed, the attachments can be read as is.
+        let record = self
+            .records
+            .get(&item)
+            .ok_or_else(|| Error::Storage(format!("message {} not found", item)))?;
+
+        find_attachment(record, cid)
+    }
+
+    fn update(&mut self, message: Message) -> Result<Message> {
+        let id = message.id.unwrap_or_default();
+        let packed = self.pack(&message)?;
+
+        match self.records.get_mut(&id) {
+            Some(record) => {
+                *record = packed;
+                self.revision += 1;
+                Ok(message)
+            }
+            None => Err(Error::Storage(format!("message {} not found", id))),
         }
+    }
+
+    fn remove(&mut self, item: usize) -> Result<()> {
+        self.records
+            .remove(&item)
+            .ok_or_else(|| Error::Storage(format!("message {} not found", item)))?;
+        self.revision += 1;
+
+        Ok(())
+    }
+
+    fn set_messages_limit(&mut self, limit: usize) -> Result<()> {
+        self.messages_limit = limit;
+
+        let mut ids: Vec<usize> = self.records.keys().copied().collect();
+        ids.sort_unstable();
 
-        self.sequence_id += 1;
+        for id in ids.iter().take(ids.len().saturating_sub(limit)) {
+            self.evict(*id)?;
+        }
 
-        message
+        Ok(())
     }
 
-    fn get(&self, item: usize) -> Message {
-        self.records.get(&item).unwrap().clone()
+    fn take_evicted(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.evicted)
     }
 
-    fn remove(&mut self, item: usize) {
-        self.records.remove(&item).unwrap();
+    fn last_id(&self) -> Option<usize> {
+        self.records.keys().max().copied()
     }
 
     fn size(&self) -> usize {
         self.records.len()
     }
 
-    fn delete_all(&mut self) {
-        self.records.clear()
+    fn delete_all(&mut self) -> Result<()> {
+        self.records.clear();
+        self.revision += 1;
+
+        Ok(())
+    }
+
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_source_round_trips() {
+        let mut store = Memory::new(10).with_compression(true);
+        let data = format!(
+            "Subject: text heavy\r\n\r\n{}\r\n",
+            "lorem ipsum ".repeat(500)
+        )
+        .into_bytes();
+
+        let message = store.add(Message::try_from(&data).unwrap()).unwrap();
+        let id = message.id.unwrap();
+
+        let record = &store.records[&id];
+        assert_ne!(record.source, data);
+        assert!(record.source.len() < data.len());
+        assert_eq!(&record.source[..2], &[0x1f, 0x8b]);
+
+        assert_eq!(store.get(id).unwrap().source, data);
+        assert_eq!(store.list().unwrap()[0].source, data);
+    }
+
+    #[test]
+    fn test_list_since() {
+        let mut store = Memory::new(10);
+        let data = b"Subject: since\r\n\r\nbody\r\n".to_vec();
+
+        let ids: Vec<_> = (0..3)
+            .map(|_| store.add(Message::try_from(&data).unwrap()).unwrap().id)
+            .collect();
+
+        let since: Vec<_> = store
+            .list_since(ids[0].unwrap())
+            .unwrap()
+            .iter()
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(since, vec![ids[2], ids[1]]);
+        assert!(store.list_since(usize::MAX).unwrap().is_empty());
     }
 }
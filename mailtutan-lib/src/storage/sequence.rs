@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Hands out message ids. Ids are unique and increasing for the lifetime of
+/// the sequence; persistent backends seed it from the highest id they have
+/// ever assigned so a restart continues where the last run stopped.
+#[derive(Debug)]
+pub struct Sequence {
+    next: AtomicUsize,
+}
+
+impl Sequence {
+    /// A sequence whose first id is `last + 1`.
+    pub fn after(last: usize) -> Self {
+        Self {
+            next: AtomicUsize::new(last + 1),
+        }
+    }
+
+    /// Reserves the next id.
+    pub fn next(&self) -> usize {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Makes sure `id`, assigned elsewhere, is never handed out.
+    pub fn skip_past(&self, id: usize) {
+        self.next.fetch_max(id + 1, Ordering::SeqCst);
+    }
+
+    /// Highest id handed out so far, or 0 when none was.
+    pub fn last(&self) -> usize {
+        self.next.load(Ordering::SeqCst) - 1
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::after(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sequence;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_ids_are_unique_across_threads() {
+        let sequence = Arc::new(Sequence::after(41));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let sequence = sequence.clone();
+                thread::spawn(move || (0..100).map(|_| sequence.next()).collect::<Vec<_>>())
+            })
+            .collect();
+
+        let mut ids: Vec<usize> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        assert_eq!(ids.len(), 400);
+        assert_eq!(ids[0], 42);
+        assert_eq!(sequence.last(), 441);
+    }
+}
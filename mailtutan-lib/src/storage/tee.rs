@@ -0,0 +1,335 @@
+use crate::models::Message;
+use crate::{Result, Storage};
+use bytes::Bytes;
+
+/// Forwards every write to several storages and serves reads from the
+/// primary one.
+///
+/// Ids are assigned by the primary and the secondaries store messages
+/// under them, so removals and updates reach the same message everywhere.
+pub struct Tee {
+    primary: Box<dyn Storage + 'static>,
+    secondaries: Vec<Box<dyn Storage + 'static>>,
+    strict: bool,
+}
+
+impl Tee {
+    pub fn new(primary: Box<dyn Storage + 'static>) -> Self {
+        Self {
+            primary,
+            secondaries: vec![],
+            strict: false,
+        }
+    }
+
+    pub fn with(mut self, secondary: Box<dyn Storage + 'static>) -> Self {
+        self.secondaries.push(secondary);
+        self
+    }
+
+    /// When set, a failing secondary fails the whole operation. Otherwise the
+    /// error is logged and the primary result is returned.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    fn forward<F>(&mut self, mut operation: F) -> Result<()>
+    where
+        F: FnMut(&mut dyn Storage) -> Result<()>,
+    {
+        for secondary in self.secondaries.iter_mut() {
+            if let Err(err) = operation(secondary.as_mut()) {
+                if self.strict {
+                    return Err(err);
+                }
+
+                eprintln!("tee storage: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for Tee {
+    fn list(&self) -> Result<Vec<Message>> {
+        self.primary.list()
+    }
+
+    fn ids(&self) -> Result<Vec<usize>> {
+        self.primary.ids()
+    }
+
+    fn list_since(&self, id: usize) -> Result<Vec<Message>> {
+        self.primary.list_since(id)
+    }
+
+    fn add(&mut self, message: Message) -> Result<Message> {
+        let stored = self.primary.add(message)?;
+        self.forward(|storage| storage.insert(stored.clone()).map(|_| ()))?;
+
+        Ok(stored)
+    }
+
+    fn reserve_id(&mut self) -> Result<usize> {
+        self.primary.reserve_id()
+    }
+
+    fn insert(&mut self, message: Message) -> Result<Message> {
+        let stored = self.primary.insert(message)?;
+        self.forward(|storage| storage.insert(stored.clone()).map(|_| ()))?;
+
+        Ok(stored)
+    }
+
+    fn get(&self, item: usize) -> Result<Message> {
+        self.primary.get(item)
+    }
+
+    fn attachment(&self, item: usize, cid: &str) -> Result<Option<(String, Bytes)>> {
+        self.primary.attachment(item, cid)
+    }
+
+    fn update(&mut self, message: Message) -> Result<Message> {
+        let stored = self.primary.update(message.clone())?;
+        self.forward(|storage| storage.update(message.clone()).map(|_| ()))?;
+
+        Ok(stored)
+    }
+
+    fn remove(&mut self, item: usize) -> Result<()> {
+        self.primary.remove(item)?;
+        self.forward(|storage| storage.remove(item))
+    }
+
+    fn size(&self) -> usize {
+        self.primary.size()
+    }
+
+    fn set_messages_limit(&mut self, limit: usize) -> Result<()> {
+        self.primary.set_messages_limit(limit)?;
+        self.forward(|storage| storage.set_messages_limit(limit))
+    }
+
+    fn take_evicted(&mut self) -> Vec<Message> {
+        for secondary in self.secondaries.iter_mut() {
+            secondary.take_evicted();
+        }
+
+        self.primary.take_evicted()
+    }
+
+    fn last_id(&self) -> Option<usize> {
+        self.primary.last_id()
+    }
+
+    fn delete_all(&mut self) -> Result<()> {
+        self.primary.delete_all()?;
+        self.forward(|storage| storage.delete_all())
+    }
+
+    fn revision(&self) -> u64 {
+        self.primary.revision()
+    }
+
+    fn healthy(&self) -> bool {
+        self.primary.healthy()
+            && (!self.strict || self.secondaries.iter().all(|storage| storage.healthy()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tee;
+    use crate::models::Message;
+    use crate::storage::{Memory, Storage};
+    use crate::{Error, Result};
+    use std::sync::{Arc, Mutex};
+
+    /// Records the subjects it was asked to store, optionally failing.
+    struct Stub {
+        added: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl Storage for Stub {
+        fn list(&self) -> Result<Vec<Message>> {
+            Ok(vec![])
+        }
+
+        fn add(&mut self, message: Message) -> Result<Message> {
+            if self.fail {
+                return Err(Error::Storage("stub is down".to_owned()));
+            }
+
+            self.added.lock().unwrap().push(message.subject.clone());
+            Ok(message)
+        }
+
+        fn insert(&mut self, message: Message) -> Result<Message> {
+            self.add(message)
+        }
+
+        fn reserve_id(&mut self) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn get(&self, item: usize) -> Result<Message> {
+            Err(Error::Storage(format!("message {} not found", item)))
+        }
+
+        fn update(&mut self, message: Message) -> Result<Message> {
+            Ok(message)
+        }
+
+        fn remove(&mut self, _item: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> usize {
+            self.added.lock().unwrap().len()
+        }
+
+        fn set_messages_limit(&mut self, _limit: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn delete_all(&mut self) -> Result<()> {
+            self.added.lock().unwrap().clear();
+            Ok(())
+        }
+
+        fn revision(&self) -> u64 {
+            0
+        }
+    }
+
+    /// A memory storage the test can look into, failing its first
+    /// `failures` inserts.
+    struct Flaky {
+        memory: Arc<Mutex<Memory>>,
+        failures: usize,
+    }
+
+    impl Storage for Flaky {
+        fn list(&self) -> Result<Vec<Message>> {
+            self.memory.lock().unwrap().list()
+        }
+
+        fn add(&mut self, message: Message) -> Result<Message> {
+            self.memory.lock().unwrap().add(message)
+        }
+
+        fn insert(&mut self, message: Message) -> Result<Message> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Error::Storage("flaky is down".to_owned()));
+            }
+
+            self.memory.lock().unwrap().insert(message)
+        }
+
+        fn reserve_id(&mut self) -> Result<usize> {
+            self.memory.lock().unwrap().reserve_id()
+        }
+
+        fn get(&self, item: usize) -> Result<Message> {
+            self.memory.lock().unwrap().get(item)
+        }
+
+        fn update(&mut self, message: Message) -> Result<Message> {
+            self.memory.lock().unwrap().update(message)
+        }
+
+        fn remove(&mut self, item: usize) -> Result<()> {
+            self.memory.lock().unwrap().remove(item)
+        }
+
+        fn size(&self) -> usize {
+            self.memory.lock().unwrap().size()
+        }
+
+        fn set_messages_limit(&mut self, limit: usize) -> Result<()> {
+            self.memory.lock().unwrap().set_messages_limit(limit)
+        }
+
+        fn delete_all(&mut self) -> Result<()> {
+            self.memory.lock().unwrap().delete_all()
+        }
+
+        fn revision(&self) -> u64 {
+            self.memory.lock().unwrap().revision()
+        }
+    }
+
+    fn message() -> Message {
+        Message {
+            subject: "tee".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tee_writes_to_all_and_reads_primary() {
+        let added = Arc::new(Mutex::new(vec![]));
+        let stub = Stub {
+            added: added.clone(),
+            fail: false,
+        };
+        let mut tee = Tee::new(Box::new(Memory::new(10))).with(Box::new(stub));
+
+        let stored = tee.add(message()).unwrap();
+
+        assert_eq!(*added.lock().unwrap(), vec!["tee"]);
+        assert_eq!(tee.get(stored.id.unwrap()).unwrap().subject, "tee");
+        assert_eq!(tee.size(), 1);
+    }
+
+    #[test]
+    fn test_tee_failing_secondary() {
+        let failing = || {
+            Box::new(Stub {
+                added: Arc::new(Mutex::new(vec![])),
+                fail: true,
+            })
+        };
+
+        let mut tee = Tee::new(Box::new(Memory::new(10))).with(failing());
+        assert!(tee.add(message()).is_ok());
+
+        let mut tee = Tee::new(Box::new(Memory::new(10)))
+            .with(failing())
+            .strict(true);
+        assert!(matches!(tee.add(message()), Err(Error::Storage(_))));
+    }
+
+    #[test]
+    fn test_tee_secondaries_keep_primary_ids() {
+        let memory = Arc::new(Mutex::new(Memory::new(10)));
+        let flaky = Flaky {
+            memory: memory.clone(),
+            failures: 1,
+        };
+        let mut tee = Tee::new(Box::new(Memory::new(10))).with(Box::new(flaky));
+
+        let subjects = ["lost", "first", "second"];
+        let ids: Vec<usize> = subjects
+            .iter()
+            .map(|subject| {
+                let message = Message {
+                    subject: subject.to_string(),
+                    ..Default::default()
+                };
+                tee.add(message).unwrap().id.unwrap()
+            })
+            .collect();
+
+        tee.remove(ids[1]).unwrap();
+
+        let secondary = memory.lock().unwrap();
+        assert!(secondary.get(ids[1]).is_err());
+        assert_eq!(secondary.get(ids[2]).unwrap().subject, "second");
+        assert_eq!(secondary.size(), 1);
+    }
+}
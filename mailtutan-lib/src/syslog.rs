@@ -0,0 +1,187 @@
+use crate::smtp::HOSTNAME;
+use crate::{AppState, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use std::net::SocketAddr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+
+/// facility mail (2) * 8 + severity informational (6)
+const PRIORITY: u8 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    /// Octet-counted framing, RFC 6587.
+    Tcp,
+}
+
+/// Remote collector receiving an RFC 5424 record per captured message.
+#[derive(Debug, Clone)]
+pub struct Syslog {
+    pub target: SocketAddr,
+    pub transport: Transport,
+    /// Add the base64 encoded source to the record.
+    pub include_source: bool,
+}
+
+impl Syslog {
+    /// Formats one record around a serialized [`MessageEvent`](crate::models::MessageEvent).
+    pub fn record(msg_id: &str, payload: &str) -> String {
+        format!(
+            "<{}>1 {} {} mailtutan {} {} - {}",
+            PRIORITY,
+            Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            HOSTNAME,
+            std::process::id(),
+            msg_id,
+            payload
+        )
+    }
+}
+
+/// Forwards every captured message to the configured collector. Returns right
+/// away when none is configured. Delivery failures are logged and dropped.
+pub async fn serve(state: AppState) -> Result<()> {
+    let (syslog, mut events) = {
+        let app = state.lock().unwrap();
+
+        match &app.syslog {
+            Some(syslog) => (syslog.clone(), app.ws_sender.subscribe()),
+            None => return Ok(()),
+        }
+    };
+
+    let udp = match syslog.transport {
+        Transport::Udp => Some(UdpSocket::bind(unspecified(&syslog.target)).await?),
+        Transport::Tcp => None,
+    };
+    let mut tcp: Option<TcpStream> = None;
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                eprintln!("syslog fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let Some(payload) = payload(&state, &syslog, &event) else {
+            continue;
+        };
+        let record = Syslog::record("add", &payload);
+
+        let sent = match &udp {
+            Some(socket) => socket
+                .send_to(record.as_bytes(), syslog.target)
+                .await
+                .map(|_| ()),
+            None => send_tcp(&mut tcp, syslog.target, &record).await,
+        };
+
+        if let Err(err) = sent {
+            eprintln!("syslog forwarding to {} failed: {}", syslog.target, err);
+        }
+    }
+}
+
+/// The event JSON for `add` events, with the source attached if asked for.
+fn payload(state: &AppState, syslog: &Syslog, event: &str) -> Option<String> {
+    let mut value: serde_json::Value = serde_json::from_str(event).ok()?;
+
+    if value["type"] != "add" {
+        return None;
+    }
+
+    if syslog.include_source {
+        let id = value["message"]["id"].as_u64()? as usize;
+        let source = state.lock().unwrap().storage.get(id).ok()?.source;
+        value["message"]["source"] = STANDARD.encode(source).into();
+    }
+
+    Some(value.to_string())
+}
+
+async fn send_tcp(
+    stream: &mut Option<TcpStream>,
+    target: SocketAddr,
+    record: &str,
+) -> std::io::Result<()> {
+    if stream.is_none() {
+        *stream = Some(TcpStream::connect(target).await?);
+    }
+
+    let framed = format!("{} {}", record.len(), record);
+    let result = match stream.as_mut() {
+        Some(connection) => connection.write_all(framed.as_bytes()).await,
+        None => Ok(()),
+    };
+
+    if result.is_err() {
+        // Reconnect on the next record.
+        *stream = None;
+    }
+
+    result
+}
+
+fn unspecified(target: &SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Message;
+    use crate::Mailtutan;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_forwards_captured_message() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let state = Mailtutan {
+            syslog: Some(Syslog {
+                target: receiver.local_addr().unwrap(),
+                transport: Transport::Udp,
+                include_source: true,
+            }),
+            ..Default::default()
+        }
+        .init();
+
+        tokio::spawn(serve(state.clone()));
+        while state.lock().unwrap().ws_sender.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let data = b"Subject: audited\r\n\r\nbody\r\n".to_vec();
+        state
+            .lock()
+            .unwrap()
+            .capture(Message::try_from(&data).unwrap())
+            .unwrap();
+
+        let mut buffer = vec![0; 65536];
+        let (len, _) =
+            tokio::time::timeout(Duration::from_secs(5), receiver.recv_from(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+        let record = String::from_utf8_lossy(&buffer[..len]).to_string();
+
+        assert!(record.starts_with("<22>1 "));
+        assert!(record.contains(" mailtutan mailtutan "));
+        let (_, json) = record.split_once(" - ").unwrap();
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(value["type"], "add");
+        assert_eq!(value["message"]["subject"], "audited");
+        assert_eq!(value["message"]["source"], STANDARD.encode(&data));
+    }
+}
@@ -0,0 +1,243 @@
+//! Helpers for building raw messages and talking to the API in tests.
+
+use crate::api::router;
+use crate::AppState;
+use axum::response::Response;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http_body::Body;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+struct Part {
+    filename: String,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+/// Builds a well-formed MIME message, picking `multipart/alternative` and
+/// `multipart/mixed` wrappers as needed. The output feeds straight into
+/// [`Message::parse`](crate::models::Message::parse) or an SMTP DATA command.
+#[derive(Default)]
+pub struct MessageBuilder {
+    headers: Vec<(String, String)>,
+    plain: Option<String>,
+    html: Option<String>,
+    attachments: Vec<Part>,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from(self, from: &str) -> Self {
+        self.header("From", from)
+    }
+
+    pub fn to(self, to: &str) -> Self {
+        self.header("To", to)
+    }
+
+    pub fn subject(self, subject: &str) -> Self {
+        self.header("Subject", subject)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn plain(mut self, body: &str) -> Self {
+        self.plain = Some(body.to_owned());
+        self
+    }
+
+    pub fn html(mut self, body: &str) -> Self {
+        self.html = Some(body.to_owned());
+        self
+    }
+
+    pub fn attachment(mut self, filename: &str, content_type: &str, body: &[u8]) -> Self {
+        self.attachments.push(Part {
+            filename: filename.to_owned(),
+            content_type: content_type.to_owned(),
+            body: body.to_vec(),
+        });
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut out = String::new();
+
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        out.push_str("MIME-Version: 1.0\r\n");
+        out.push_str(&self.body());
+        out.into_bytes()
+    }
+
+    /// Content headers, blank line and body of the top-level entity.
+    fn body(&self) -> String {
+        let text = self.text();
+
+        if self.attachments.is_empty() {
+            return text;
+        }
+
+        let mut parts = vec![text];
+
+        for attachment in &self.attachments {
+            let encoded = STANDARD.encode(&attachment.body);
+            let lines: Vec<&str> = encoded
+                .as_bytes()
+                .chunks(76)
+                .map(|line| std::str::from_utf8(line).unwrap())
+                .collect();
+
+            parts.push(format!(
+                concat!(
+                    "Content-Type: {}; name=\"{}\"\r\n",
+                    "Content-Disposition: attachment; filename=\"{}\"\r\n",
+                    "Content-Transfer-Encoding: base64\r\n",
+                    "\r\n",
+                    "{}"
+                ),
+                attachment.content_type,
+                attachment.filename,
+                attachment.filename,
+                lines.join("\r\n")
+            ));
+        }
+
+        multipart("mixed", &parts)
+    }
+
+    fn text(&self) -> String {
+        let plain = self.plain.as_ref().map(|body| single("text/plain", body));
+        let html = self.html.as_ref().map(|body| single("text/html", body));
+
+        match (plain, html) {
+            (Some(plain), Some(html)) => multipart("alternative", &[plain, html]),
+            (Some(part), None) | (None, Some(part)) => part,
+            (None, None) => single("text/plain", ""),
+        }
+    }
+}
+
+fn single(content_type: &str, body: &str) -> String {
+    format!(
+        "Content-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}",
+        content_type, body
+    )
+}
+
+fn multipart(subtype: &str, parts: &[String]) -> String {
+    let boundary = format!("mailtutan-{}", subtype);
+    let mut out = format!(
+        "Content-Type: multipart/{}; boundary=\"{}\"\r\n\r\n",
+        subtype, boundary
+    );
+
+    for part in parts {
+        out.push_str(&format!("--{}\r\n{}\r\n", boundary, part));
+    }
+
+    out.push_str(&format!("--{}--\r\n", boundary));
+    out
+}
+
+/// Serves the API for `state` on a free local port.
+pub fn spawn_router(state: AppState) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(router(state).into_make_service());
+    tokio::spawn(server);
+
+    addr
+}
+
+/// Sends a bodyless request on its own connection and returns the raw
+/// response, headers included.
+pub async fn request(addr: SocketAddr, method: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                method, path
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    response
+}
+
+/// The whole body of a handler's response.
+pub async fn body_bytes(response: Response) -> Vec<u8> {
+    let mut body = response.into_body();
+    let mut bytes = vec![];
+
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.unwrap());
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Message, ParseOptions};
+
+    #[test]
+    fn test_multipart_message() {
+        let data = MessageBuilder::new()
+            .from("sender@example.com")
+            .to("rcpt@example.com")
+            .subject("Built")
+            .plain("plain body")
+            .html("<p>html body</p>")
+            .attachment("report.pdf", "application/pdf", b"%PDF-1.4 fake")
+            .build();
+
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let message = Message::parse(&data, &strict).unwrap();
+
+        assert_eq!(message.subject, "Built");
+        assert_eq!(message.sender, "sender@example.com");
+        assert_eq!(message.recipients, vec!["rcpt@example.com"]);
+        assert_eq!(message.plain.as_deref(), Some("plain body"));
+        assert_eq!(message.html.as_deref(), Some("<p>html body</p>"));
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(message.attachments[0].filename, "report.pdf");
+        assert_eq!(message.attachments[0].body, b"%PDF-1.4 fake");
+    }
+
+    #[test]
+    fn test_single_part_message() {
+        let data = MessageBuilder::new()
+            .subject("Plain only")
+            .plain("hello")
+            .build();
+
+        let message = Message::try_from(&data).unwrap();
+
+        assert_eq!(message.subject, "Plain only");
+        assert_eq!(message.plain.as_deref(), Some("hello"));
+        assert!(message.attachments.is_empty());
+    }
+}
@@ -111,8 +111,17 @@ pub fn Root() -> Html {
                 while let Some(msg) = read.next().await {
                     if let Ok(websocket::Message::Text(msg)) = msg {
                         if let Ok(m) = serde_json::from_str::<MessageEvent>(&msg) {
-                            dispatch.reduce_mut(|state| {
-                                state.messages.insert(m.message.id.unwrap(), m.message)
+                            dispatch.reduce_mut(|state| match m.event_type.as_str() {
+                                "delete" | "evict" => {
+                                    state.messages.remove(&m.message.id.unwrap());
+                                }
+                                "clear" => {
+                                    state.messages.clear();
+                                    state.selected_message = None;
+                                }
+                                _ => {
+                                    state.messages.insert(m.message.id.unwrap(), m.message);
+                                }
                             });
                         }
                     }
@@ -1,9 +1,17 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::Parser;
 use clap::ValueEnum;
+use mailtutan_lib::models::{AttachmentOverflow, LineEnding, ParseOptions};
+use mailtutan_lib::smtp::{self, Greylist, RateLimiter};
 use mailtutan_lib::storage::Memdir;
 use mailtutan_lib::storage::Storage;
-use mailtutan_lib::{storage::Memory, Mailtutan};
-use std::net::Ipv4Addr;
+use mailtutan_lib::syslog::{Syslog, Transport};
+use mailtutan_lib::{storage::Memory, Error, Mailtutan, Reloader, Result, Settings};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::sync::broadcast;
 
 #[derive(Clone, Debug, ValueEnum)]
@@ -14,21 +22,70 @@ pub enum StorageType {
     Maildir,
 }
 
+#[derive(Clone, Debug, ValueEnum)]
+pub enum LineEndingType {
+    #[clap(value_enum)]
+    Lf,
+    #[clap(value_enum)]
+    Crlf,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+pub enum AttachmentOverflowType {
+    #[clap(value_enum)]
+    Reject,
+    #[clap(value_enum)]
+    Truncate,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+pub enum SyslogTransportType {
+    #[clap(value_enum)]
+    Udp,
+    #[clap(value_enum)]
+    Tcp,
+}
+
 /// Mailtutan
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Config {
+    /// TOML file with the settings applied again on reload, overriding the options below
+    #[arg(long = "config", env("MAILTUTAN_CONFIG"))]
+    pub config: Option<PathBuf>,
+
     /// IPv4 address
     #[arg(long = "ip", env("MAILTUTAN_IPADDR"), default_value = "0.0.0.0")]
     pub ip: Ipv4Addr,
 
+    /// IPv4 address of the HTTP listener, overrides --ip
+    #[arg(long = "http-ip", env("MAILTUTAN_HTTP_IPADDR"))]
+    pub http_ip: Option<Ipv4Addr>,
+
+    /// IPv4 address of the SMTP listener, overrides --ip
+    #[arg(long = "smtp-ip", env("MAILTUTAN_SMTP_IPADDR"))]
+    pub smtp_ip: Option<Ipv4Addr>,
+
     /// HTTP Port number
     #[arg(long = "http-port", env("MAILTUTAN_HTTP_PORT"), default_value_t = 1080)]
     pub http_port: u16,
 
-    /// SMTP Port number
-    #[arg(long = "smtp-port", env("MAILTUTAN_SMTP_PORT"), default_value_t = 1025)]
-    pub smtp_port: u16,
+    /// SMTP Port numbers, one listener each
+    #[arg(
+        long = "smtp-port",
+        env("MAILTUTAN_SMTP_PORT"),
+        value_delimiter = ',',
+        default_value = "1025"
+    )]
+    pub smtp_port: Vec<u16>,
+
+    /// Public base URL used in generated links, e.g. behind a proxy
+    #[arg(long = "public-url", env("MAILTUTAN_PUBLIC_URL"))]
+    pub public_url: Option<String>,
+
+    /// Replace likely tracking pixels in served html with a placeholder
+    #[arg(long = "block-tracking-pixels", env("MAILTUTAN_BLOCK_TRACKING_PIXELS"))]
+    pub block_tracking_pixels: bool,
 
     /// HTTP Auth
     #[arg(
@@ -54,6 +111,38 @@ pub struct Config {
     )]
     pub http_password: String,
 
+    /// Serve the API read-only, refusing deletes and other changes
+    #[arg(long = "read-only", env("MAILTUTAN_READ_ONLY"))]
+    pub read_only: bool,
+
+    /// Add Server-Timing headers to the message list and JSON endpoints
+    #[arg(long = "server-timing", env("MAILTUTAN_SERVER_TIMING"))]
+    pub server_timing: bool,
+
+    /// SMTP Auth
+    #[arg(
+        long = "smtp-auth",
+        env("MAILTUTAN_SMTP_AUTH"),
+        default_value_t = false
+    )]
+    pub smtp_auth: bool,
+
+    /// SMTP Username
+    #[arg(
+        long = "smtp-username",
+        env("MAILTUTAN_SMTP_USERNAME"),
+        default_value = "admin"
+    )]
+    pub smtp_username: String,
+
+    /// SMTP Password
+    #[arg(
+        long = "smtp-password",
+        env("MAILTUTAN_SMTP_PASSWORD"),
+        default_value = "admin"
+    )]
+    pub smtp_password: String,
+
     /// Messages Limit
     #[arg(
         long = "messages-limit",
@@ -62,6 +151,325 @@ pub struct Config {
     )]
     pub messages_limit: usize,
 
+    /// Return at most this many messages from an unpaginated list request
+    #[arg(long = "max-list-size", env("MAILTUTAN_MAX_LIST_SIZE"))]
+    pub max_list_size: Option<usize>,
+
+    /// Include the bodies in the list entries of messages smaller than this many bytes
+    #[arg(long = "inline-body-size", env("MAILTUTAN_INLINE_BODY_SIZE"))]
+    pub inline_body_size: Option<usize>,
+
+    /// Maximum SMTP line length in bytes
+    #[arg(
+        long = "max-line-length",
+        env("MAILTUTAN_MAX_LINE_LENGTH"),
+        default_value_t = 65536
+    )]
+    pub max_line_length: usize,
+
+    /// Maximum message header size in bytes
+    #[arg(
+        long = "max-header-size",
+        env("MAILTUTAN_MAX_HEADER_SIZE"),
+        default_value_t = 1048576
+    )]
+    pub max_header_size: usize,
+
+    /// Normalize line endings of plain/html bodies
+    #[arg(
+        long = "normalize-line-endings",
+        env("MAILTUTAN_NORMALIZE_LINE_ENDINGS")
+    )]
+    pub normalize_line_endings: Option<LineEndingType>,
+
+    /// Reject messages that don't parse cleanly with 554
+    #[arg(
+        long = "strict-parse",
+        env("MAILTUTAN_STRICT_PARSE"),
+        default_value_t = false
+    )]
+    pub strict_parse: bool,
+
+    /// Maximum number of attachments per message
+    #[arg(long = "max-attachments", env("MAILTUTAN_MAX_ATTACHMENTS"))]
+    pub max_attachments: Option<usize>,
+
+    /// Maximum total attachment size per message in bytes
+    #[arg(long = "max-attachments-size", env("MAILTUTAN_MAX_ATTACHMENTS_SIZE"))]
+    pub max_attachments_size: Option<usize>,
+
+    /// Maximum size of any single attachment in bytes
+    #[arg(long = "max-attachment-size", env("MAILTUTAN_MAX_ATTACHMENT_SIZE"))]
+    pub max_attachment_size: Option<usize>,
+
+    /// What to do with messages over the attachment limits. Rejections reply
+    /// 554, or 552 for a single attachment over its limit
+    #[arg(
+        long = "attachment-overflow",
+        env("MAILTUTAN_ATTACHMENT_OVERFLOW"),
+        default_value = "reject"
+    )]
+    pub attachment_overflow: AttachmentOverflowType,
+
+    /// Charset for text parts that declare none and aren't valid UTF-8
+    #[arg(long = "default-charset", env("MAILTUTAN_DEFAULT_CHARSET"))]
+    pub default_charset: Option<String>,
+
+    /// Warn about html cid: references without a matching part
+    #[arg(
+        long = "verify-cids",
+        env("MAILTUTAN_VERIFY_CIDS"),
+        default_value_t = false
+    )]
+    pub verify_cids: bool,
+
+    /// Also expose sender and recipient addresses with lowercased domains
+    #[arg(
+        long = "normalize-addresses",
+        env("MAILTUTAN_NORMALIZE_ADDRESSES"),
+        default_value_t = false
+    )]
+    pub normalize_addresses: bool,
+
+    /// Convert html-only messages to plain text keeping paragraphs and list items apart
+    #[arg(
+        long = "html-to-text",
+        env("MAILTUTAN_HTML_TO_TEXT"),
+        default_value_t = false
+    )]
+    pub html_to_text: bool,
+
+    /// Pretty-print plain text bodies that are JSON for display
+    #[arg(
+        long = "pretty-json",
+        env("MAILTUTAN_PRETTY_JSON"),
+        default_value_t = false
+    )]
+    pub pretty_json: bool,
+
+    /// Truncate decoded bodies kept for preview to this many characters
+    #[arg(long = "max-body-length", env("MAILTUTAN_MAX_BODY_LENGTH"))]
+    pub max_body_length: Option<usize>,
+
+    /// Defer first delivery attempts with 450 for this many seconds
+    #[arg(long = "greylist-delay", env("MAILTUTAN_GREYLIST_DELAY"))]
+    pub greylist_delay: Option<u64>,
+
+    /// Accepted messages per second, over it DATA gets 451
+    #[arg(long = "rate-limit", env("MAILTUTAN_RATE_LIMIT"))]
+    pub rate_limit: Option<f64>,
+
+    /// Messages accepted at once before --rate-limit applies
+    #[arg(
+        long = "rate-limit-burst",
+        env("MAILTUTAN_RATE_LIMIT_BURST"),
+        default_value_t = 1
+    )]
+    pub rate_limit_burst: u32,
+
+    /// Only accept MAIL FROM addresses in these domains
+    #[arg(
+        long = "sender-allow",
+        env("MAILTUTAN_SENDER_ALLOW"),
+        value_delimiter = ','
+    )]
+    pub sender_allow: Vec<String>,
+
+    /// Reject MAIL FROM addresses in these domains
+    #[arg(
+        long = "sender-deny",
+        env("MAILTUTAN_SENDER_DENY"),
+        value_delimiter = ','
+    )]
+    pub sender_deny: Vec<String>,
+
+    /// Purge messages older than this many seconds
+    #[arg(long = "message-ttl", env("MAILTUTAN_MESSAGE_TTL"))]
+    pub message_ttl: Option<u64>,
+
+    /// Threads parsing received messages, 0 parses inline. DATA is answered
+    /// before parsing, so this excludes --strict-parse and attachment limits
+    /// that reject
+    #[arg(
+        long = "parse-workers",
+        env("MAILTUTAN_PARSE_WORKERS"),
+        default_value_t = 0
+    )]
+    pub parse_workers: usize,
+
+    /// Delay the RCPT TO reply by milliseconds, for all recipients or as pattern=ms with * wildcards
+    #[arg(
+        long = "rcpt-delay",
+        env("MAILTUTAN_RCPT_DELAYS"),
+        value_delimiter = ','
+    )]
+    pub rcpt_delays: Vec<String>,
+
+    /// Number messages per UTC day, e.g. 2024-06-01/007
+    #[arg(long = "daily-sequence", env("MAILTUTAN_DAILY_SEQUENCE"))]
+    pub daily_sequence: bool,
+
+    /// Warn about messages taking longer than this many milliseconds to parse
+    #[arg(long = "slow-parse-ms", env("MAILTUTAN_SLOW_PARSE_MS"))]
+    pub slow_parse_ms: Option<u64>,
+
+    /// Expect a PROXY protocol (v1 or v2) header on SMTP connections
+    #[arg(long = "proxy-protocol", env("MAILTUTAN_PROXY_PROTOCOL"))]
+    pub proxy_protocol: bool,
+
+    /// Text of the 250 reply after DATA; {id} is replaced with the message id
+    #[arg(long = "data-response", env("MAILTUTAN_DATA_RESPONSE"))]
+    pub data_response: Option<String>,
+
+    /// Reply code for unrecognized SMTP commands
+    #[arg(
+        long = "unknown-command-code",
+        env("MAILTUTAN_UNKNOWN_COMMAND_CODE"),
+        default_value_t = 500,
+        value_parser = PossibleValuesParser::new(["500", "502"]).map(|code| code.parse::<u16>().unwrap())
+    )]
+    pub unknown_command_code: u16,
+
+    /// Log unrecognized SMTP commands
+    #[arg(
+        long = "log-unknown-commands",
+        env("MAILTUTAN_LOG_UNKNOWN_COMMANDS"),
+        default_value_t = false
+    )]
+    pub log_unknown_commands: bool,
+
+    /// Resolve the reverse DNS name of SMTP clients
+    #[arg(
+        long = "reverse-dns",
+        env("MAILTUTAN_REVERSE_DNS"),
+        default_value_t = false
+    )]
+    pub reverse_dns: bool,
+
+    /// Syslog collector receiving a record per captured message
+    #[arg(long = "syslog", env("MAILTUTAN_SYSLOG"))]
+    pub syslog: Option<SocketAddr>,
+
+    /// Syslog transport
+    #[arg(
+        long = "syslog-transport",
+        env("MAILTUTAN_SYSLOG_TRANSPORT"),
+        default_value = "udp"
+    )]
+    pub syslog_transport: SyslogTransportType,
+
+    /// Include the message source in syslog records
+    #[arg(
+        long = "syslog-include-source",
+        env("MAILTUTAN_SYSLOG_INCLUDE_SOURCE"),
+        default_value_t = false
+    )]
+    pub syslog_include_source: bool,
+
+    /// Prepend a Received header for the SMTP hop to stored sources
+    #[arg(
+        long = "received-header",
+        env("MAILTUTAN_RECEIVED_HEADER"),
+        default_value_t = false
+    )]
+    pub received_header: bool,
+
+    /// Store message sources as sent on the wire, dot-stuffing and terminator included
+    #[arg(
+        long = "raw-source",
+        env("MAILTUTAN_RAW_SOURCE"),
+        default_value_t = false
+    )]
+    pub raw_source: bool,
+
+    /// Chaos testing: drop the connection after this many DATA bytes
+    #[arg(long = "chaos-drop-after", env("MAILTUTAN_CHAOS_DROP_AFTER"))]
+    pub chaos_drop_after: Option<usize>,
+
+    /// Flag messages containing any of these comma-separated words as spam
+    #[arg(
+        long = "spam-keyword",
+        env("MAILTUTAN_SPAM_KEYWORDS"),
+        value_delimiter = ','
+    )]
+    pub spam_keywords: Vec<String>,
+
+    /// Reject messages lacking any of these comma-separated headers with 554
+    #[arg(
+        long = "require-header",
+        env("MAILTUTAN_REQUIRED_HEADERS"),
+        value_delimiter = ','
+    )]
+    pub required_headers: Vec<String>,
+
+    /// Refuse websocket subscribers beyond this many with 503
+    #[arg(long = "max-ws-subscribers", env("MAILTUTAN_MAX_WS_SUBSCRIBERS"))]
+    pub max_ws_subscribers: Option<usize>,
+
+    /// Also relay captured messages to this host:port
+    #[arg(long = "relay", env("MAILTUTAN_RELAY"))]
+    pub relay: Option<String>,
+
+    /// Accept XFORWARD and record the forwarded client details
+    #[arg(long = "xforward", env("MAILTUTAN_XFORWARD"), default_value_t = false)]
+    pub xforward: bool,
+
+    /// Use camelCase keys in API and websocket JSON
+    #[arg(
+        long = "camel-case-json",
+        env("MAILTUTAN_CAMEL_CASE_JSON"),
+        default_value_t = false
+    )]
+    pub camel_case_json: bool,
+
+    /// PEM certificate chain offered through STARTTLS
+    #[arg(long = "tls-cert", env("MAILTUTAN_TLS_CERT"), requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key of the STARTTLS certificate
+    #[arg(long = "tls-key", env("MAILTUTAN_TLS_KEY"), requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// Advertise STARTTLS but reject the command with 454
+    #[arg(
+        long = "starttls-failure",
+        env("MAILTUTAN_STARTTLS_FAILURE"),
+        default_value_t = false
+    )]
+    pub starttls_failure: bool,
+
+    /// Route messages into a mailbox, as name=domain:example.com or name=subject:text
+    #[arg(
+        long = "mailbox-rule",
+        env("MAILTUTAN_MAILBOX_RULES"),
+        value_delimiter = ','
+    )]
+    pub mailbox_rules: Vec<String>,
+
+    /// Keep this many unparseable deliveries for GET /api/failures
+    #[arg(
+        long = "max-parse-failures",
+        env("MAILTUTAN_MAX_PARSE_FAILURES"),
+        default_value_t = 10
+    )]
+    pub max_parse_failures: usize,
+
+    /// Keep only the headers and metadata of captured messages
+    #[arg(
+        long = "headers-only",
+        env("MAILTUTAN_HEADERS_ONLY"),
+        default_value_t = false
+    )]
+    pub headers_only: bool,
+
+    /// Keep message sources gzip-compressed in memory storage
+    #[arg(
+        long = "compress-source",
+        env("MAILTUTAN_COMPRESS_SOURCE"),
+        default_value_t = false
+    )]
+    pub compress_source: bool,
+
     /// Storage
     #[arg(long = "storage", env("MAILTUTAN_STORAGE"), default_value = "memory")]
     pub storage: StorageType,
@@ -75,27 +483,356 @@ pub struct Config {
     pub maildir_path: String,
 }
 
+/// Contents of the `--config` file. Keys are named after the options they
+/// override, e.g. `messages-limit = 500`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileSettings {
+    messages_limit: Option<usize>,
+    max_list_size: Option<usize>,
+    max_line_length: Option<usize>,
+    max_header_size: Option<usize>,
+    sender_allow: Option<Vec<String>>,
+    sender_deny: Option<Vec<String>>,
+    spam_keyword: Option<Vec<String>>,
+    message_ttl: Option<u64>,
+}
+
+impl FileSettings {
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+
+        toml::from_str(&text).map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))
+    }
+
+    fn apply(self, settings: &mut Settings) {
+        if let Some(messages_limit) = self.messages_limit {
+            settings.messages_limit = messages_limit;
+        }
+        if let Some(max_list_size) = self.max_list_size {
+            settings.max_list_size = Some(max_list_size);
+        }
+        if let Some(max_line_length) = self.max_line_length {
+            settings.max_line_length = max_line_length;
+        }
+        if let Some(max_header_size) = self.max_header_size {
+            settings.max_header_size = max_header_size;
+        }
+        if let Some(sender_allow) = self.sender_allow {
+            settings.sender_allowlist = sender_allow;
+        }
+        if let Some(sender_deny) = self.sender_deny {
+            settings.sender_denylist = sender_deny;
+        }
+        if let Some(spam_keyword) = self.spam_keyword {
+            settings.spam_keywords = spam_keyword;
+        }
+        if let Some(message_ttl) = self.message_ttl {
+            settings.message_ttl = Some(Duration::from_secs(message_ttl));
+        }
+    }
+}
+
+/// `base` with the settings of the file at `path`, if any, on top.
+fn load_settings(base: &Settings, path: Option<&Path>) -> Result<Settings> {
+    let mut settings = base.clone();
+
+    if let Some(path) = path {
+        FileSettings::read(path)?.apply(&mut settings);
+    }
+
+    Ok(settings)
+}
+
 impl Config {
     pub fn from_env_and_args() -> Self {
         Self::parse()
     }
 
-    pub fn build(&self) -> Mailtutan {
+    /// The hot-reloadable part of the configuration.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            messages_limit: self.messages_limit,
+            max_list_size: self.max_list_size,
+            max_line_length: self.max_line_length,
+            max_header_size: self.max_header_size,
+            sender_allowlist: self.sender_allow.clone(),
+            sender_denylist: self.sender_deny.clone(),
+            spam_keywords: self.spam_keywords.clone(),
+            message_ttl: self.message_ttl.map(Duration::from_secs),
+        }
+    }
+
+    pub fn build(&self) -> Result<Mailtutan> {
+        if let Some(charset) = &self.default_charset {
+            if !ParseOptions::is_known_charset(charset) {
+                return Err(Error::Config(format!("unknown charset {}", charset)));
+            }
+        }
+
+        let parse_options = ParseOptions {
+            line_endings: self
+                .normalize_line_endings
+                .as_ref()
+                .map(|ending| match ending {
+                    LineEndingType::Lf => LineEnding::Lf,
+                    LineEndingType::Crlf => LineEnding::Crlf,
+                }),
+            strict: self.strict_parse,
+            max_attachments: self.max_attachments,
+            max_attachments_size: self.max_attachments_size,
+            max_attachment_size: self.max_attachment_size,
+            attachment_overflow: match self.attachment_overflow {
+                AttachmentOverflowType::Reject => AttachmentOverflow::Reject,
+                AttachmentOverflowType::Truncate => AttachmentOverflow::Truncate,
+            },
+            default_charset: self.default_charset.clone(),
+            verify_cids: self.verify_cids,
+            normalize_addresses: self.normalize_addresses,
+            html_to_text: self.html_to_text,
+            pretty_json: self.pretty_json,
+            max_body_length: self.max_body_length,
+        };
+
+        // Workers reply to DATA before parsing, too early for these
+        // rejections to be sent.
+        if self.parse_workers > 0 && parse_options.rejects_after_parse() {
+            return Err(Error::Config(
+                "--parse-workers can't be combined with --strict-parse or attachment limits \
+                 that reject"
+                    .to_owned(),
+            ));
+        }
+
         let storage: Box<dyn Storage + 'static> = match self.storage {
-            StorageType::Memory => Box::new(Memory::new(self.messages_limit)),
-            StorageType::Maildir => Box::new(Memdir::new(self.messages_limit, &self.maildir_path)),
+            StorageType::Memory => {
+                Box::new(Memory::new(self.messages_limit).with_compression(self.compress_source))
+            }
+            StorageType::Maildir => Box::new(
+                Memdir::new(self.messages_limit, &self.maildir_path)?
+                    .with_parse_options(parse_options.clone()),
+            ),
         };
 
-        Mailtutan {
+        let base = self.settings();
+        let path = self.config.clone();
+        let settings = load_settings(&base, path.as_deref())?;
+
+        let mut app = Mailtutan {
             ip: self.ip,
+            http_ip: self.http_ip,
+            smtp_ip: self.smtp_ip,
             http_port: self.http_port,
-            smtp_port: self.smtp_port,
+            smtp_ports: self.smtp_port.clone(),
             http_username: self.http_username.clone(),
             http_password: self.http_password.clone(),
+            read_only: self.read_only,
+            server_timing: self.server_timing,
             http_auth: self.http_auth,
+            smtp_auth: self.smtp_auth,
+            smtp_username: self.smtp_username.clone(),
+            smtp_password: self.smtp_password.clone(),
             storage,
             ws_sender: broadcast::channel(100).0,
+            event_sender: broadcast::channel(100).0,
             messages_limit: self.messages_limit,
+            max_list_size: self.max_list_size,
+            inline_body_size: self.inline_body_size,
+            max_line_length: self.max_line_length,
+            max_header_size: self.max_header_size,
+            parse_options,
+            greylist: self
+                .greylist_delay
+                .map(|seconds| Greylist::new(Duration::from_secs(seconds))),
+            rate_limit: self
+                .rate_limit
+                .map(|rate| RateLimiter::new(rate, self.rate_limit_burst)),
+            sender_allowlist: self.sender_allow.clone(),
+            sender_denylist: self.sender_deny.clone(),
+            smtp_ready: false,
+            message_ttl: self.message_ttl.map(Duration::from_secs),
+            parse_workers: self.parse_workers,
+            parse_pool: None,
+            data_response: self.data_response.clone(),
+            slow_parse_ms: self.slow_parse_ms,
+            rcpt_delays: self
+                .rcpt_delays
+                .iter()
+                .map(|delay| delay.parse())
+                .collect::<Result<_>>()?,
+            daily_sequence: self.daily_sequence,
+            sequence_day: None,
+            proxy_protocol: self.proxy_protocol,
+            unknown_command_code: self.unknown_command_code,
+            log_unknown_commands: self.log_unknown_commands,
+            reverse_dns: self.reverse_dns,
+            syslog: self.syslog.map(|target| Syslog {
+                target,
+                transport: match self.syslog_transport {
+                    SyslogTransportType::Udp => Transport::Udp,
+                    SyslogTransportType::Tcp => Transport::Tcp,
+                },
+                include_source: self.syslog_include_source,
+            }),
+            headers_only: self.headers_only,
+            public_url: self.public_url.clone(),
+            block_tracking_pixels: self.block_tracking_pixels,
+            received_header: self.received_header,
+            raw_source: self.raw_source,
+            chaos_drop_after: self.chaos_drop_after,
+            spam_keywords: self.spam_keywords.clone(),
+            required_headers: self.required_headers.clone(),
+            mailbox_rules: self
+                .mailbox_rules
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<_>>()?,
+            max_ws_subscribers: self.max_ws_subscribers,
+            ws_subscribers: 0,
+            relay: self.relay.clone(),
+            xforward: self.xforward,
+            camel_case_json: self.camel_case_json,
+            tls: match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => Some(smtp::acceptor(cert, key)?),
+                _ => None,
+            },
+            max_parse_failures: self.max_parse_failures,
+            parse_failures: VecDeque::new(),
+            parse_failure_count: 0,
+            starttls_failure: self.starttls_failure,
+            // The command line and environment can't change while running,
+            // so only a config file gives reload something to re-read.
+            reloader: path
+                .is_some()
+                .then(|| -> Reloader { Box::new(move || load_settings(&base, path.as_deref())) }),
+        };
+        app.apply(settings)?;
+
+        Ok(app)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_host() {
+        let app = Config::try_parse_from([
+            "mailtutan",
+            "--ip",
+            "127.0.0.2",
+            "--http-port",
+            "8025",
+            "--smtp-port",
+            "2525",
+        ])
+        .unwrap()
+        .build()
+        .unwrap();
+
+        assert_eq!(app.get_api_uri(), "127.0.0.2:8025");
+        assert_eq!(app.get_smtp_uris(), vec!["127.0.0.2:2525"]);
+
+        let app =
+            Config::try_parse_from(["mailtutan", "--ip", "127.0.0.2", "--smtp-ip", "127.0.0.3"])
+                .unwrap()
+                .build()
+                .unwrap();
+
+        assert_eq!(app.get_api_uri(), "127.0.0.2:1080");
+        assert_eq!(app.get_smtp_uris(), vec!["127.0.0.3:1025"]);
+    }
+
+    #[test]
+    fn test_several_smtp_ports() {
+        let app = Config::try_parse_from(["mailtutan", "--smtp-port", "1025,2525"])
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(app.get_smtp_uris(), vec!["0.0.0.0:1025", "0.0.0.0:2525"]);
+    }
+
+    #[test]
+    fn test_parse_workers_refuse_parse_rejections() {
+        for flag in [&["--strict-parse"][..], &["--max-attachments", "3"]] {
+            let mut args = vec!["mailtutan", "--parse-workers", "2"];
+            args.extend(flag);
+
+            let config = Config::try_parse_from(args).unwrap();
+            assert!(matches!(config.build(), Err(Error::Config(_))));
         }
+
+        let config = Config::try_parse_from([
+            "mailtutan",
+            "--parse-workers",
+            "2",
+            "--max-attachments",
+            "3",
+            "--attachment-overflow",
+            "truncate",
+        ])
+        .unwrap();
+        assert!(config.build().is_ok());
+    }
+
+    #[test]
+    fn test_unknown_command_code() {
+        let config =
+            Config::try_parse_from(["mailtutan", "--unknown-command-code", "502"]).unwrap();
+        assert_eq!(config.unknown_command_code, 502);
+
+        assert!(Config::try_parse_from(["mailtutan", "--unknown-command-code", "250"]).is_err());
+    }
+
+    #[test]
+    fn test_reload_rereads_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("mailtutan-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "messages-limit = 50\n").unwrap();
+
+        let mut app = Config::try_parse_from([
+            "mailtutan",
+            "--messages-limit",
+            "10",
+            "--max-list-size",
+            "5",
+            "--config",
+            path.to_str().unwrap(),
+        ])
+        .unwrap()
+        .build()
+        .unwrap();
+        assert_eq!(app.messages_limit, 50);
+        assert_eq!(app.max_list_size, Some(5));
+
+        std::fs::write(
+            &path,
+            "messages-limit = 20\nspam-keyword = [\"lottery\"]\nmessage-ttl = 60\n",
+        )
+        .unwrap();
+        app.reload().unwrap();
+        assert_eq!(app.messages_limit, 20);
+        assert_eq!(app.spam_keywords, vec!["lottery"]);
+        assert_eq!(app.message_ttl, Some(Duration::from_secs(60)));
+        // Options left out of the file keep their command line value.
+        assert_eq!(app.max_list_size, Some(5));
+
+        std::fs::write(&path, "messages-limt = 20\n").unwrap();
+        assert!(matches!(app.reload(), Err(Error::Config(_))));
+        assert_eq!(app.messages_limit, 20);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_needs_config_file() {
+        let mut app = Config::try_parse_from(["mailtutan"])
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(matches!(app.reload(), Err(Error::Config(_))));
     }
 }
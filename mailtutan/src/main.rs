@@ -7,17 +7,48 @@ use tokio::{self, runtime::Builder, signal};
 
 #[tokio::main]
 async fn main() {
-    Config::from_env_and_args().build().init();
+    let app = match Config::from_env_and_args().build() {
+        Ok(app) => app.init(),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
 
+    runtime.spawn(expiry::serve(app.clone()));
+    runtime.spawn(syslog::serve(app.clone()));
+    #[cfg(unix)]
+    runtime.spawn(reload_on_hangup(app.clone()));
+
     tokio::select! {
-        _ = runtime.spawn(api::serve()) => {
+        Ok(Err(err)) = runtime.spawn(api::serve(app.clone())) => {
+            eprintln!("{}", err);
         }
-        _ = runtime.spawn(smtp::serve()) => {
+        Ok(Err(err)) = runtime.spawn(smtp::serve(app)) => {
+            eprintln!("{}", err);
         }
         _ = signal::ctrl_c() => {
         }
     }
     runtime.shutdown_background();
 }
+
+/// Applies the current configuration whenever SIGHUP is received.
+#[cfg(unix)]
+async fn reload_on_hangup(app: AppState) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            eprintln!("cannot listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if let Err(err) = app.lock().unwrap().reload() {
+            eprintln!("configuration reload failed: {}", err);
+        }
+    }
+}